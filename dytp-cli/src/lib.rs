@@ -4,6 +4,7 @@ use crate::error::{CliError, Result};
 use dytp_future::get_health_cloud::GetHealthCloud;
 use dytp_future::get_health_gateway::GetHealthGateway;
 use dytp_future::get_health_node::GetHealthNode;
+use futures::future;
 use futures::prelude::*;
 use serde_json::json;
 use std::net::SocketAddr;
@@ -24,12 +25,15 @@ where
     }
 }
 
-pub fn main_inner(addr: SocketAddr, component: &str, method: &str, pretty: bool) -> Result<()> {
-    log::debug!("addr={}, component={}, method={}", addr, component, method);
-
+fn request(
+    addr: SocketAddr,
+    component: &str,
+    method: &str,
+    pretty: bool,
+) -> Result<Box<Future<Item = (), Error = ()> + Send>> {
     let f: Box<Future<Item = (), Error = ()> + Send> = match (component, method) {
         ("cloud", "health") => Box::new(
-            GetHealthCloud::new(addr)?
+            GetHealthCloud::new(addr)
                 .map(move |h| {
                     print_json(h, pretty);
                     ()
@@ -39,7 +43,7 @@ pub fn main_inner(addr: SocketAddr, component: &str, method: &str, pretty: bool)
                 }),
         ),
         ("gateway", "health") => Box::new(
-            GetHealthGateway::new(addr)?
+            GetHealthGateway::new(addr)
                 .map(move |h| {
                     print_json(h, pretty);
                     ()
@@ -49,7 +53,7 @@ pub fn main_inner(addr: SocketAddr, component: &str, method: &str, pretty: bool)
                 }),
         ),
         ("node", "health") => Box::new(
-            GetHealthNode::new(addr)?
+            GetHealthNode::new(addr)
                 .map(move |h| {
                     print_json(h, pretty);
                     ()
@@ -67,7 +71,20 @@ pub fn main_inner(addr: SocketAddr, component: &str, method: &str, pretty: bool)
         }
     };
 
+    Ok(f)
+}
+
+pub fn main_inner(addr: SocketAddr, component: &str, method: &str, pretty: bool) -> Result<()> {
+    log::debug!("addr={}, component={}, method={}", addr, component, method);
+
     let mut runtime = Runtime::new()?;
+    let component = component.to_owned();
+    let method = method.to_owned();
+
+    // Connections are made inside the runtime so that they are registered to its reactor.
+    let f = runtime.block_on(future::lazy(move || {
+        request(addr, &component, &method, pretty)
+    }))?;
 
     runtime.spawn(f);
 
@@ -1,5 +1,6 @@
 use failure::Error;
 use failure::Fail;
+use std::net::SocketAddr;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -8,3 +9,9 @@ pub enum DatabaseError {
     #[fail(display = "invalid or unsupported database url={}", url)]
     InvalidDatabaseUrl { url: String },
 }
+
+#[derive(Debug, Fail)]
+pub enum JoinError {
+    #[fail(display = "node {} is already active with another identity", addr)]
+    IdentityMismatch { addr: SocketAddr },
+}
@@ -6,15 +6,18 @@ use crate::manager::Manager;
 use chrono::prelude::*;
 use clap::crate_version;
 use dytp_component::health_resp_cloud::HealthRespCloud;
+use dytp_component::node::{is_valid_family, Node, MAX_BANDWIDTH};
 use dytp_component::node_state::NodeState;
 use dytp_connection::prelude::*;
 use dytp_future::get_health_node::GetHealthNode;
 use dytp_future::get_pub_key::GetPubKey;
+use dytp_protocol::crypto;
 use dytp_protocol::method::plain;
 use failure::Error;
 use futures::future::Either;
+use openssl::memcmp;
+use openssl::rand::rand_bytes;
 use openssl::rsa::Padding;
-use semver::Version;
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
@@ -27,85 +30,76 @@ fn audit(
     mut origin: Origin,
     ts: i64,
 ) -> Box<Future<Item = (), Error = Error> + Send> {
-    let f = manager.sync(ts).map(move |audit| {
-        let buf = audit.iter().fold(Vec::<u8>::new(), |mut audit, a| {
-            if audit.len() > 0 {
-                audit.append(&mut b" ".to_vec());
-            }
-
-            audit.append(
-                &mut format!("{} {} {} {}", a.addr, a.state, a.version, a.ts)
-                    .as_bytes()
-                    .to_vec(),
-            );
-            audit
-        });
+    let f = manager.sync(ts).and_then(move |audits| -> Result<_> {
+        origin.write_all(&plain::FromCloud::AUDITS { audits }.encode()?)?;
 
-        origin.write(&buf).unwrap();
-        origin.flush().unwrap();
+        Ok(Flush::new(origin).map(|_| ()))
     });
 
-    Box::new(f)
+    Box::new(f.flatten())
 }
 
 fn list(
     manager: Box<Manager + Send>,
     mut origin: Origin,
 ) -> Box<Future<Item = (), Error = Error> + Send> {
-    let f = manager
-        .latest_ts()
-        .join(manager.list(true))
-        .map(move |(ts, nodes)| {
-            let buf = nodes
-                .iter()
-                .map(|node| format!("{} {}", node.addr, node.version))
-                .fold(format!("{}", ts).as_bytes().to_vec(), |mut nodes, node| {
-                    nodes.append(&mut b" ".to_vec());
-                    nodes.append(&mut node.as_bytes().to_vec());
-                    nodes
-                });
+    let f =
+        manager
+            .latest_ts()
+            .join(manager.list(true))
+            .and_then(move |(ts, nodes)| -> Result<_> {
+                origin.write_all(&plain::FromCloud::NODES { ts, nodes }.encode()?)?;
 
-            origin.write(&buf).unwrap();
-            origin.flush().unwrap();
-        });
+                Ok(Flush::new(origin).map(|_| ()))
+            });
 
-    Box::new(f)
+    Box::new(f.flatten())
 }
 
+// Length of the nonce a node decrypts to join.
+const NONCE_LEN: usize = 32;
+
 fn join(
     manager: Box<Manager + Send>,
     mut origin: Origin,
-    addr: SocketAddr,
-    version: Version,
+    node: Node,
 ) -> Box<Future<Item = (), Error = Error> + Send> {
-    let get_pub_key = GetPubKey::new(addr.clone());
+    let addr = node.addr;
+    let f = GetPubKey::new(addr.clone())
+        .map_err(|e| e.into())
+        .and_then(move |rsa| -> Result<_> {
+            let rsa = rsa.filter(|rsa| {
+                let der = rsa.public_key_to_der().unwrap();
 
-    if let Err(_) = get_pub_key {
-        return Box::new(future::ok(()));
-    }
+                if crypto::hex(&crypto::fingerprint(&der)) != node.fingerprint {
+                    log::warn!("node {} published a fingerprint of another key", addr);
+
+                    return false;
+                }
+
+                true
+            });
 
-    let f = get_pub_key
-        .unwrap()
-        .map_err(|e| e.into())
-        .and_then(move |rsa| {
             let f = if let Some(rsa) = rsa {
-                let data: &[u8] = &[0, 1, 2, 3];
+                // The node proves that it has the identity key by decrypting a nonce only for this JOIN.
+                let mut nonce = vec![0; NONCE_LEN];
                 let mut buf = vec![0; rsa.size() as usize];
 
-                rsa.public_encrypt(data, &mut buf, Padding::PKCS1).unwrap();
+                rand_bytes(&mut nonce)?;
+                rsa.public_encrypt(&nonce, &mut buf, Padding::PKCS1)?;
 
-                origin.write(&buf).unwrap();
-                origin.flush().unwrap();
+                origin.write_all(&buf)?;
+                origin.flush()?;
 
                 let f = origin
                     .into_future()
                     .map_err(|(e, _)| e)
                     .and_then(move |(buf, _)| {
                         let f = if let Some(buf) = buf {
-                            let f = if &buf[0..data.len()] == data {
+                            let f = if buf.len() == nonce.len() && memcmp::eq(&buf, &nonce) {
                                 log::info!("new node has joined! <- {}", addr);
 
-                                Either::A(manager.join(addr, version))
+                                Either::A(manager.join(node))
                             } else {
                                 Either::B(future::ok(()))
                             };
@@ -123,8 +117,9 @@ fn join(
                 Either::B(future::ok(()))
             };
 
-            f
-        });
+            Ok(f)
+        })
+        .flatten();
 
     Box::new(f)
 }
@@ -134,16 +129,16 @@ fn check(
     mut origin: Origin,
     addr: SocketAddr,
 ) -> Box<Future<Item = (), Error = Error> + Send> {
-    let f = manager.check(addr).map(move |state_opt| {
+    let f = manager.check(addr).and_then(move |state_opt| {
         if let Some(state) = state_opt {
             let buf = format!("{}", state).into_bytes();
 
             origin.write(&buf).unwrap();
-            origin.flush().unwrap();
         } else {
             origin.write(b"E").unwrap();
-            origin.flush().unwrap();
         }
+
+        Flush::new(origin).map(|_| ())
     });
 
     Box::new(f)
@@ -153,20 +148,22 @@ fn health(
     manager: Box<Manager + Send>,
     mut origin: Origin,
 ) -> Box<Future<Item = (), Error = Error> + Send> {
-    let f = manager.list(false).map_err(|e| e.into()).map(move |nodes| {
-        let res: Vec<u8> = HealthRespCloud::new(crate_version!(), &nodes).into();
+    let f = manager.list(false).and_then(move |nodes| -> Result<_> {
+        let res = plain::Health::CLOUD(HealthRespCloud::new(crate_version!(), &nodes));
 
-        origin.write(&res).unwrap();
-        origin.flush().unwrap();
+        origin.write_all(&res.encode()?)?;
 
-        ()
+        Ok(Flush::new(origin).map(|_| ()))
     });
 
-    Box::new(f)
+    Box::new(f.flatten())
 }
 
 fn process(socket: TcpStream, manager: Box<Manager + Send>, read_timeout: u64) {
-    let origin = Origin::new_with_timeout(socket, read_timeout);
+    let mut origin = Origin::new_with_timeout(socket, read_timeout);
+
+    origin.negotiate();
+
     let f = origin
         .into_future()
         .map_err(|(e, _)| e)
@@ -188,8 +185,40 @@ fn process(socket: TcpStream, manager: Box<Manager + Send>, read_timeout: u64) {
                     plain::ToCloud::FETCH => {
                         return list(manager, origin);
                     }
-                    plain::ToCloud::JOIN { addr, version } => {
-                        return join(manager, origin, addr, version);
+                    plain::ToCloud::JOIN {
+                        addr,
+                        version,
+                        fingerprint,
+                        bandwidth,
+                        family,
+                        exit_policy,
+                    } => {
+                        // The family is shared with gateways, which separate nodes by it.
+                        if let Some(family) = family.as_ref().filter(|f| !is_valid_family(f)) {
+                            log::warn!("node {} declared an invalid family={:?}", addr, family);
+
+                            return Box::new(future::ok::<(), Error>(()));
+                        }
+
+                        if bandwidth > MAX_BANDWIDTH {
+                            log::warn!(
+                                "node {} advertised {}KB/s, capped to {}KB/s",
+                                addr,
+                                bandwidth,
+                                MAX_BANDWIDTH
+                            );
+                        }
+
+                        let node = Node::new(
+                            &addr,
+                            &version,
+                            &fingerprint,
+                            bandwidth.min(MAX_BANDWIDTH),
+                            family,
+                            exit_policy,
+                        );
+
+                        return join(manager, origin, node);
                     }
                     plain::ToCloud::CHECK { addr } => {
                         return check(manager, origin, addr);
@@ -216,35 +245,23 @@ fn healthcheck(manager: Box<Manager + Send>, node_deletion_timeout: u64) {
 
                 match node.state {
                     NodeState::ACTIVE => {
-                        if let Ok(g) = GetHealthNode::new(node.addr.clone()) {
-                            let f = g
-                                .map_err(move |e| {
-                                    log::error!("health check error={:?}", e);
-
-                                    let f = manager.pending_delete(node.addr.clone(), node.version.clone()).map_err(|e| {
-                                        log::error!(
-                                            "couldn't change the state of the node due to errror={:?}",
-                                            e
-                                        );
-                                    });
-
-                                    tokio::spawn(f);
-                                })
-                                .map(|_| ());
-
-                            tokio::spawn(f);
-                        } else {
-                            log::warn!(
-                                "couldn't connect to {}. change state to PENDING_DELETE",
-                                node.addr
-                            );
-
-                            let f = manager.pending_delete(node.addr.clone(), node.version.clone()).map_err(|e| {
-                                log::error!("couldn't change the state of the node due to error={:?}", e);
-                            });
+                        let f = GetHealthNode::new(node.addr.clone())
+                            .map_err(move |e| {
+                                log::warn!(
+                                    "couldn't connect to {} due to error={:?}. change state to PENDING_DELETE",
+                                    node.addr,
+                                    e
+                                );
+
+                                let f = manager.pending_delete(node.clone()).map_err(|e| {
+                                    log::error!("couldn't change the state of the node due to error={:?}", e);
+                                });
+
+                                tokio::spawn(f);
+                            })
+                            .map(|_| ());
 
-                            tokio::spawn(f);
-                        }
+                        tokio::spawn(f);
                     }
                     NodeState::PENDING_DELETE => {
                         log::warn!("node {} is pending to be deleted", node.addr);
@@ -8,7 +8,6 @@ use dytp_component::node::Node;
 use dytp_component::node_state::NodeState;
 use failure::Error;
 use futures::prelude::*;
-use semver::Version;
 use std::env;
 use std::net::SocketAddr;
 
@@ -26,17 +25,9 @@ pub trait ManagerClone {
 }
 
 pub trait Manager: ManagerClone {
-    fn join(
-        &self,
-        addr: SocketAddr,
-        version: Version,
-    ) -> Box<Future<Item = (), Error = Error> + Send>;
+    fn join(&self, node: Node) -> Box<Future<Item = (), Error = Error> + Send>;
     fn delete(&self, addr: SocketAddr) -> Box<Future<Item = (), Error = Error> + Send>;
-    fn pending_delete(
-        &self,
-        addr: SocketAddr,
-        version: Version,
-    ) -> Box<Future<Item = (), Error = Error> + Send>;
+    fn pending_delete(&self, node: Node) -> Box<Future<Item = (), Error = Error> + Send>;
     fn list(&self, active_only: bool) -> Box<Future<Item = Vec<Node>, Error = Error> + Send>;
     fn check(
         &self,
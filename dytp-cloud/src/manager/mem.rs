@@ -11,16 +11,15 @@ use crate::manager::{Manager, ManagerClone};
 use dytp_component::audit::Audit;
 use dytp_component::node::Node;
 use dytp_component::node_state::NodeState;
+use dytp_future::lock::Lock;
 use failure::Error;
 use futures::prelude::*;
 use lazy_static::lazy_static;
-use semver::Version;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
 
 lazy_static! {
-    pub static ref ON_MEM_NODES: Arc<RwLock<Vec<Node>>> = Arc::new(RwLock::new(Vec::new()));
-    pub static ref ON_MEM_AUDIT: Arc<RwLock<Vec<Audit>>> = Arc::new(RwLock::new(Vec::new()));
+    pub static ref ON_MEM_NODES: Lock<Vec<Node>> = Lock::new(Vec::new());
+    pub static ref ON_MEM_AUDIT: Lock<Vec<Audit>> = Lock::new(Vec::new());
 }
 
 #[derive(Clone)]
@@ -33,24 +32,16 @@ impl Mem {
 }
 
 impl Manager for Mem {
-    fn join(
-        &self,
-        addr: SocketAddr,
-        version: Version,
-    ) -> Box<Future<Item = (), Error = Error> + Send> {
-        Box::new(join::Join::new(addr, version))
+    fn join(&self, node: Node) -> Box<Future<Item = (), Error = Error> + Send> {
+        Box::new(join::Join::new(node))
     }
 
     fn delete(&self, addr: SocketAddr) -> Box<Future<Item = (), Error = Error> + Send> {
         Box::new(delete::Delete::new(addr))
     }
 
-    fn pending_delete(
-        &self,
-        addr: SocketAddr,
-        version: Version,
-    ) -> Box<Future<Item = (), Error = Error> + Send> {
-        Box::new(pending_delete::PendingDelete::new(addr, version))
+    fn pending_delete(&self, node: Node) -> Box<Future<Item = (), Error = Error> + Send> {
+        Box::new(pending_delete::PendingDelete::new(node))
     }
 
     fn list(&self, active_only: bool) -> Box<Future<Item = Vec<Node>, Error = Error> + Send> {
@@ -1,17 +1,23 @@
 use crate::manager::mem::ON_MEM_AUDIT;
+use dytp_component::audit::Audit;
 use dytp_component::node_state::NodeState;
+use dytp_future::lock::Acquire;
 use failure::Error;
 use futures::prelude::*;
+use futures::try_ready;
 use std::net::SocketAddr;
-use tokio::prelude::*;
 
 pub struct Check {
     addr: SocketAddr,
+    audit: Acquire<Vec<Audit>>,
 }
 
 impl Check {
     pub fn new(addr: SocketAddr) -> Check {
-        Check { addr }
+        Check {
+            addr,
+            audit: ON_MEM_AUDIT.acquire(),
+        }
     }
 }
 
@@ -20,18 +26,14 @@ impl Future for Check {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(audit) = ON_MEM_AUDIT.try_read() {
-            for a in audit.iter().rev() {
-                if a.addr == self.addr {
-                    return Ok(Async::Ready(Some(a.state.clone())));
-                }
-            }
+        let audit = try_ready!(self.audit.poll_lock());
 
-            return Ok(Async::Ready(None));
+        for a in audit.iter().rev() {
+            if a.addr == self.addr {
+                return Ok(Async::Ready(Some(a.state.clone())));
+            }
         }
 
-        task::current().notify();
-
-        Ok(Async::NotReady)
+        Ok(Async::Ready(None))
     }
 }
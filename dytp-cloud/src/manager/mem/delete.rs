@@ -1,16 +1,22 @@
 use crate::manager::mem::ON_MEM_NODES;
+use dytp_component::node::Node;
+use dytp_future::lock::Acquire;
 use failure::Error;
 use futures::prelude::*;
+use futures::try_ready;
 use std::net::SocketAddr;
-use tokio::prelude::*;
 
 pub struct Delete {
     addr: SocketAddr,
+    nodes: Acquire<Vec<Node>>,
 }
 
 impl Delete {
     pub fn new(addr: SocketAddr) -> Delete {
-        Delete { addr }
+        Delete {
+            addr,
+            nodes: ON_MEM_NODES.acquire(),
+        }
     }
 }
 
@@ -19,21 +25,18 @@ impl Future for Delete {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(mut nodes) = ON_MEM_NODES.try_write() {
-            if let Some(idx) = nodes
-                .iter()
-                .enumerate()
-                .find(|(_, n)| n.addr == self.addr)
-                .map(|(idx, _)| idx)
-            {
-                nodes.remove(idx);
-            }
+        let addr = self.addr;
+        let mut nodes = try_ready!(self.nodes.poll_lock());
 
-            return Ok(Async::Ready(()));
+        if let Some(idx) = nodes
+            .iter()
+            .enumerate()
+            .find(|(_, n)| n.addr == addr)
+            .map(|(idx, _)| idx)
+        {
+            nodes.remove(idx);
         }
 
-        task::current().notify();
-
-        Ok(Async::NotReady)
+        Ok(Async::Ready(()))
     }
 }
@@ -1,18 +1,24 @@
 use crate::manager::mem::ON_MEM_AUDIT;
+use dytp_component::audit::Audit;
 use dytp_component::error::AuditError;
 use dytp_component::node_state::NodeState;
+use dytp_future::lock::Acquire;
 use failure::Error;
 use futures::prelude::*;
+use futures::try_ready;
 use std::net::SocketAddr;
-use tokio::prelude::*;
 
 pub struct DeletedTs {
     addr: SocketAddr,
+    audit: Acquire<Vec<Audit>>,
 }
 
 impl DeletedTs {
     pub fn new(addr: SocketAddr) -> DeletedTs {
-        DeletedTs { addr }
+        DeletedTs {
+            addr,
+            audit: ON_MEM_AUDIT.acquire(),
+        }
     }
 }
 
@@ -21,28 +27,24 @@ impl Future for DeletedTs {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(audit) = ON_MEM_AUDIT.try_read() {
-            for a in audit.iter().rev() {
-                if a.addr == self.addr {
-                    match a.state {
-                        NodeState::ACTIVE => {
-                            log::warn!("try to delete but found active audit");
-                            return Err(AuditError::InvalidAudit.into());
-                        }
-                        NodeState::PENDING_DELETE => {
-                            return Ok(Async::Ready(a.ts));
-                        }
+        let audit = try_ready!(self.audit.poll_lock());
+
+        for a in audit.iter().rev() {
+            if a.addr == self.addr {
+                match a.state {
+                    NodeState::ACTIVE => {
+                        log::warn!("try to delete but found active audit");
+                        return Err(AuditError::InvalidAudit.into());
+                    }
+                    NodeState::PENDING_DELETE => {
+                        return Ok(Async::Ready(a.ts));
                     }
                 }
             }
-
-            log::warn!("deletion audit not found");
-
-            return Err(AuditError::InvalidAudit.into());
         }
 
-        task::current().notify();
+        log::warn!("deletion audit not found");
 
-        Ok(Async::NotReady)
+        Err(AuditError::InvalidAudit.into())
     }
 }
@@ -1,23 +1,28 @@
+use crate::error::JoinError;
 use crate::manager::mem::ON_MEM_AUDIT;
 use crate::manager::mem::ON_MEM_NODES;
 use crate::manager::ts;
 use dytp_component::audit::Audit;
 use dytp_component::node::Node;
 use dytp_component::node_state::NodeState;
+use dytp_future::lock::Acquire;
 use failure::Error;
 use futures::prelude::*;
-use semver::Version;
-use std::net::SocketAddr;
-use tokio::prelude::*;
+use futures::try_ready;
 
 pub struct Join {
-    addr: SocketAddr,
-    version: Version,
+    node: Node,
+    nodes: Acquire<Vec<Node>>,
+    audit: Acquire<Vec<Audit>>,
 }
 
 impl Join {
-    pub fn new(addr: SocketAddr, version: Version) -> Join {
-        Join { addr, version }
+    pub fn new(node: Node) -> Join {
+        Join {
+            node,
+            nodes: ON_MEM_NODES.acquire(),
+            audit: ON_MEM_AUDIT.acquire(),
+        }
     }
 }
 
@@ -26,40 +31,39 @@ impl Future for Join {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(mut nodes) = ON_MEM_NODES.try_write() {
-            if let Ok(mut audit) = ON_MEM_AUDIT.try_write() {
-                if let Some((idx, node)) =
-                    nodes.iter().enumerate().find(|(_, a)| a.addr == self.addr)
-                {
-                    match node.state {
-                        NodeState::ACTIVE => {
-                            log::warn!("node {} is already active", self.addr);
-                            nodes[idx].version = self.version.clone();
-                            return Ok(Async::Ready(()));
-                        }
-                        NodeState::PENDING_DELETE => {
-                            log::info!("node {} has been recovered", self.addr);
-                            nodes[idx].version = self.version.clone();
-                            nodes[idx].state = NodeState::ACTIVE;
-                        }
-                    }
-                } else {
-                    nodes.push(Node::new(&self.addr, &self.version));
-                }
+        let addr = self.node.addr;
+
+        // always in this order to avoid a deadlock
+        let mut nodes = try_ready!(self.nodes.poll_lock());
+        let mut audit = try_ready!(self.audit.poll_lock());
 
-                audit.push(Audit::new(
-                    &self.addr,
-                    NodeState::ACTIVE,
-                    &self.version,
-                    ts(),
-                ));
+        if let Some((idx, node)) = nodes.iter().enumerate().find(|(_, a)| a.addr == addr) {
+            match node.state {
+                NodeState::ACTIVE if node.fingerprint != self.node.fingerprint => {
+                    log::warn!("node {} is already active with another identity", addr);
+                    return Err(JoinError::IdentityMismatch { addr }.into());
+                }
+                NodeState::ACTIVE => {
+                    log::warn!("node {} is already active", addr);
+                    nodes[idx] = self.node.clone();
+                    return Ok(Async::Ready(()));
+                }
+                NodeState::PENDING_DELETE => {
+                    // The key of the node may have been lost, but the address may have been taken over as well.
+                    if node.fingerprint != self.node.fingerprint {
+                        log::warn!("node {} has been recovered with another identity", addr);
+                    }
 
-                return Ok(Async::Ready(()));
+                    log::info!("node {} has been recovered", addr);
+                    nodes[idx] = self.node.clone();
+                }
             }
+        } else {
+            nodes.push(self.node.clone());
         }
 
-        task::current().notify();
+        audit.push(Audit::new(&self.node, NodeState::ACTIVE, ts()));
 
-        Ok(Async::NotReady)
+        Ok(Async::Ready(()))
     }
 }
@@ -1,13 +1,19 @@
 use crate::manager::mem::ON_MEM_AUDIT;
+use dytp_component::audit::Audit;
+use dytp_future::lock::Acquire;
 use failure::Error;
 use futures::prelude::*;
-use tokio::prelude::*;
+use futures::try_ready;
 
-pub struct LatestTs;
+pub struct LatestTs {
+    audit: Acquire<Vec<Audit>>,
+}
 
 impl LatestTs {
     pub fn new() -> LatestTs {
-        LatestTs {}
+        LatestTs {
+            audit: ON_MEM_AUDIT.acquire(),
+        }
     }
 }
 
@@ -16,18 +22,14 @@ impl Future for LatestTs {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(audit) = ON_MEM_AUDIT.try_read() {
-            let ts = if audit.len() > 0 {
-                audit[audit.len() - 1].ts
-            } else {
-                0
-            };
-
-            return Ok(Async::Ready(ts));
-        }
+        let audit = try_ready!(self.audit.poll_lock());
 
-        task::current().notify();
+        let ts = if audit.len() > 0 {
+            audit[audit.len() - 1].ts
+        } else {
+            0
+        };
 
-        Ok(Async::NotReady)
+        Ok(Async::Ready(ts))
     }
 }
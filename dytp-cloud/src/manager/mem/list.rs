@@ -1,17 +1,22 @@
 use crate::manager::mem::ON_MEM_NODES;
 use dytp_component::node::Node;
 use dytp_component::node_state::NodeState;
+use dytp_future::lock::Acquire;
 use failure::Error;
 use futures::prelude::*;
-use tokio::prelude::*;
+use futures::try_ready;
 
 pub struct List {
     active_only: bool,
+    nodes: Acquire<Vec<Node>>,
 }
 
 impl List {
     pub fn new(active_only: bool) -> List {
-        List { active_only }
+        List {
+            active_only,
+            nodes: ON_MEM_NODES.acquire(),
+        }
     }
 }
 
@@ -20,22 +25,18 @@ impl Future for List {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(nodes) = ON_MEM_NODES.try_read() {
-            let nodes = if self.active_only {
-                nodes
-                    .iter()
-                    .filter(|n| n.state == NodeState::ACTIVE)
-                    .map(|n| n.clone())
-                    .collect::<Vec<Node>>()
-            } else {
-                nodes.clone()
-            };
-
-            return Ok(Async::Ready(nodes));
-        }
+        let nodes = try_ready!(self.nodes.poll_lock());
 
-        task::current().notify();
+        let nodes = if self.active_only {
+            nodes
+                .iter()
+                .filter(|n| n.state == NodeState::ACTIVE)
+                .map(|n| n.clone())
+                .collect::<Vec<Node>>()
+        } else {
+            nodes.clone()
+        };
 
-        Ok(Async::NotReady)
+        Ok(Async::Ready(nodes))
     }
 }
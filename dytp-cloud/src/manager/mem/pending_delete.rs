@@ -2,21 +2,26 @@ use crate::manager::mem::ON_MEM_AUDIT;
 use crate::manager::mem::ON_MEM_NODES;
 use crate::manager::ts;
 use dytp_component::audit::Audit;
+use dytp_component::node::Node;
 use dytp_component::node_state::NodeState;
+use dytp_future::lock::Acquire;
 use failure::Error;
 use futures::prelude::*;
-use semver::Version;
-use std::net::SocketAddr;
-use tokio::prelude::*;
+use futures::try_ready;
 
 pub struct PendingDelete {
-    addr: SocketAddr,
-    version: Version,
+    node: Node,
+    nodes: Acquire<Vec<Node>>,
+    audit: Acquire<Vec<Audit>>,
 }
 
 impl PendingDelete {
-    pub fn new(addr: SocketAddr, version: Version) -> PendingDelete {
-        PendingDelete { addr, version }
+    pub fn new(node: Node) -> PendingDelete {
+        PendingDelete {
+            node,
+            nodes: ON_MEM_NODES.acquire(),
+            audit: ON_MEM_AUDIT.acquire(),
+        }
     }
 }
 
@@ -25,30 +30,23 @@ impl Future for PendingDelete {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(mut nodes) = ON_MEM_NODES.try_write() {
-            if let Ok(mut audit) = ON_MEM_AUDIT.try_write() {
-                if let Some(idx) = nodes
-                    .iter()
-                    .enumerate()
-                    .find(|(_, n)| n.addr == self.addr)
-                    .map(|(idx, _)| idx)
-                {
-                    nodes[idx].state = NodeState::PENDING_DELETE;
-                }
-
-                audit.push(Audit::new(
-                    &self.addr,
-                    NodeState::PENDING_DELETE,
-                    &self.version,
-                    ts(),
-                ));
-
-                return Ok(Async::Ready(()));
-            }
+        let addr = self.node.addr;
+
+        // always in this order to avoid a deadlock
+        let mut nodes = try_ready!(self.nodes.poll_lock());
+        let mut audit = try_ready!(self.audit.poll_lock());
+
+        if let Some(idx) = nodes
+            .iter()
+            .enumerate()
+            .find(|(_, n)| n.addr == addr)
+            .map(|(idx, _)| idx)
+        {
+            nodes[idx].state = NodeState::PENDING_DELETE;
         }
 
-        task::current().notify();
+        audit.push(Audit::new(&self.node, NodeState::PENDING_DELETE, ts()));
 
-        Ok(Async::NotReady)
+        Ok(Async::Ready(()))
     }
 }
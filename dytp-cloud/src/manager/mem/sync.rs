@@ -1,16 +1,21 @@
 use crate::manager::mem::ON_MEM_AUDIT;
 use dytp_component::audit::Audit;
+use dytp_future::lock::Acquire;
 use failure::Error;
 use futures::prelude::*;
-use tokio::prelude::*;
+use futures::try_ready;
 
 pub struct Sync {
     ts: i64,
+    audit: Acquire<Vec<Audit>>,
 }
 
 impl Sync {
     pub fn new(ts: i64) -> Sync {
-        Sync { ts }
+        Sync {
+            ts,
+            audit: ON_MEM_AUDIT.acquire(),
+        }
     }
 }
 
@@ -19,22 +24,18 @@ impl Future for Sync {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(audit) = ON_MEM_AUDIT.try_read() {
-            let mut audit_res = Vec::new();
+        let audit = try_ready!(self.audit.poll_lock());
 
-            for a in audit.iter().rev() {
-                if a.ts > self.ts {
-                    audit_res.push(a.clone())
-                } else {
-                    break;
-                }
-            }
+        let mut audit_res = Vec::new();
 
-            return Ok(Async::Ready(audit_res));
+        for a in audit.iter().rev() {
+            if a.ts > self.ts {
+                audit_res.push(a.clone())
+            } else {
+                break;
+            }
         }
 
-        task::current().notify();
-
-        Ok(Async::NotReady)
+        Ok(Async::Ready(audit_res))
     }
 }
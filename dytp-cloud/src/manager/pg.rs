@@ -1,4 +1,4 @@
-use crate::error::Result;
+use crate::error::{JoinError, Result};
 use crate::manager::ts;
 use crate::manager::{Manager, ManagerClone};
 use diesel::pg::PgConnection;
@@ -12,7 +12,6 @@ use dytp_component::schema::audits;
 use dytp_component::schema::nodes;
 use failure::Error;
 use futures::prelude::*;
-use semver::Version;
 use std::net::SocketAddr;
 
 #[derive(Clone)]
@@ -29,9 +28,18 @@ impl Pg {
     }
 }
 
-fn node_create(conn: &PgConnection, a: &SocketAddr, v: &Version) -> Result<Node> {
+fn node_create(conn: &PgConnection, n: &Node) -> Result<Node> {
+    let fa = n.family.as_ref().map(|fa| fa.as_str());
+
     diesel::insert_into(nodes::table)
-        .values(NodeInsert::new(a, v))
+        .values(NodeInsert::new(
+            &n.addr,
+            &n.version,
+            &n.fingerprint,
+            n.bandwidth,
+            fa,
+            &n.exit_policy,
+        ))
         .get_result::<Node>(conn)
         .map_err(|e| e.into())
 }
@@ -45,16 +53,18 @@ fn node_update(conn: &PgConnection, a: &SocketAddr, update: NodeUpdate) -> Resul
         .map_err(|e| e.into())
 }
 
-fn audit_create(conn: &PgConnection, a: &SocketAddr, s: &NodeState, v: &Version) -> Result<Audit> {
+fn audit_create(conn: &PgConnection, n: &Node, s: &NodeState) -> Result<Audit> {
     diesel::insert_into(audits::table)
-        .values(AuditInsert::new(a, s, v, ts()))
+        .values(AuditInsert::new(n, s, ts()))
         .get_result::<Audit>(conn)
         .map_err(|e| e.into())
 }
 
 impl Manager for Pg {
-    fn join(&self, a: SocketAddr, v: Version) -> Box<Future<Item = (), Error = Error> + Send> {
+    fn join(&self, n: Node) -> Box<Future<Item = (), Error = Error> + Send> {
         let conn = self.pool.clone().get().unwrap();
+        let a = n.addr;
+        let fa = n.family.as_ref().map(|fa| fa.as_str());
         let node = {
             use dytp_component::schema::nodes::dsl::*;
 
@@ -66,25 +76,57 @@ impl Manager for Pg {
         };
 
         if node.len() == 0 {
-            node_create(&conn, &a, &v).unwrap();
+            node_create(&conn, &n).unwrap();
         } else {
             if node[0].state == NodeState::ACTIVE {
+                if node[0].fingerprint != n.fingerprint {
+                    log::warn!("node {} is already active with another identity", a);
+
+                    return Box::new(futures::future::err(
+                        JoinError::IdentityMismatch { addr: a }.into(),
+                    ));
+                }
+
                 log::warn!("node {} is already active", node[0].addr);
 
-                node_update(&conn, &a, NodeUpdate::new(None, Some(&v))).unwrap();
+                node_update(
+                    &conn,
+                    &a,
+                    NodeUpdate::new(
+                        None,
+                        Some(&n.version),
+                        None,
+                        Some(n.bandwidth),
+                        Some(fa),
+                        Some(&n.exit_policy),
+                    ),
+                )
+                .unwrap();
             } else {
+                // The key of the node may have been lost, but the address may have been taken over as well.
+                if node[0].fingerprint != n.fingerprint {
+                    log::warn!("node {} has been recovered with another identity", a);
+                }
+
                 log::info!("node {} has been recovered", node[0].addr);
 
                 node_update(
                     &conn,
                     &a,
-                    NodeUpdate::new(Some(&NodeState::ACTIVE), Some(&v)),
+                    NodeUpdate::new(
+                        Some(&NodeState::ACTIVE),
+                        Some(&n.version),
+                        Some(&n.fingerprint),
+                        Some(n.bandwidth),
+                        Some(fa),
+                        Some(&n.exit_policy),
+                    ),
                 )
                 .unwrap();
             }
         }
 
-        audit_create(&conn, &a, &NodeState::ACTIVE, &v).unwrap();
+        audit_create(&conn, &n, &NodeState::ACTIVE).unwrap();
 
         Box::new(futures::future::ok(()))
     }
@@ -102,21 +144,24 @@ impl Manager for Pg {
         Box::new(futures::future::ok(()))
     }
 
-    fn pending_delete(
-        &self,
-        addr: SocketAddr,
-        version: Version,
-    ) -> Box<Future<Item = (), Error = Error> + Send> {
+    fn pending_delete(&self, node: Node) -> Box<Future<Item = (), Error = Error> + Send> {
         let conn = self.pool.clone().get().unwrap();
 
         node_update(
             &conn,
-            &addr,
-            NodeUpdate::new(Some(&NodeState::PENDING_DELETE), None),
+            &node.addr,
+            NodeUpdate::new(
+                Some(&NodeState::PENDING_DELETE),
+                None,
+                None,
+                None,
+                None,
+                None,
+            ),
         )
         .unwrap();
 
-        audit_create(&conn, &addr, &NodeState::PENDING_DELETE, &version).unwrap();
+        audit_create(&conn, &node, &NodeState::PENDING_DELETE).unwrap();
 
         Box::new(futures::future::ok(()))
     }
@@ -145,7 +190,16 @@ impl Manager for Pg {
             use dytp_component::schema::audits::dsl::*;
 
             audits
-                .select((addr, state, version, ts))
+                .select((
+                    addr,
+                    state,
+                    version,
+                    ts,
+                    fingerprint,
+                    bandwidth,
+                    family,
+                    exit_policy,
+                ))
                 .filter(ts.gt(t))
                 .order_by(ts.desc())
                 .load::<Audit>(&conn)
@@ -215,7 +269,16 @@ impl Manager for Pg {
             use dytp_component::schema::audits::dsl::*;
 
             audits
-                .select((addr, state, version, ts))
+                .select((
+                    addr,
+                    state,
+                    version,
+                    ts,
+                    fingerprint,
+                    bandwidth,
+                    family,
+                    exit_policy,
+                ))
                 .order_by(ts.desc())
                 .limit(1)
                 .load::<Audit>(&conn)
@@ -1,3 +1,4 @@
+use crate::exit_policy::ExitPolicy;
 use crate::node::Node;
 use crate::node_state::NodeState;
 use crate::schema::audits;
@@ -10,15 +11,24 @@ pub struct Audit {
     pub addr: SocketAddr,
     pub state: NodeState,
     pub version: Version,
+    pub fingerprint: String,
+    pub bandwidth: u64,
+    pub family: Option<String>,
+    pub exit_policy: ExitPolicy,
     pub ts: i64,
 }
 
 impl Audit {
-    pub fn new(addr: &SocketAddr, state: NodeState, version: &Version, ts: i64) -> Audit {
+    // Records the node as it was described when its state changed.
+    pub fn new(node: &Node, state: NodeState, ts: i64) -> Audit {
         Audit {
-            addr: addr.clone(),
+            addr: node.addr,
             state,
-            version: version.clone(),
+            version: node.version.clone(),
+            fingerprint: node.fingerprint.clone(),
+            bandwidth: node.bandwidth,
+            family: node.family.clone(),
+            exit_policy: node.exit_policy.clone(),
             ts,
         }
     }
@@ -26,12 +36,28 @@ impl Audit {
 
 impl Into<Node> for Audit {
     fn into(self) -> Node {
-        Node::new(&self.addr, &self.version)
+        Node::new(
+            &self.addr,
+            &self.version,
+            &self.fingerprint,
+            self.bandwidth,
+            self.family,
+            self.exit_policy,
+        )
     }
 }
 
 impl Queryable<audits::SqlType, diesel::pg::Pg> for Audit {
-    type Row = (String, String, String, i64);
+    type Row = (
+        String,
+        String,
+        String,
+        i64,
+        String,
+        i64,
+        Option<String>,
+        String,
+    );
 
     fn build(row: Self::Row) -> Self {
         Audit {
@@ -39,6 +65,10 @@ impl Queryable<audits::SqlType, diesel::pg::Pg> for Audit {
             state: row.1.parse().unwrap(),
             version: row.2.parse().unwrap(),
             ts: row.3,
+            fingerprint: row.4,
+            bandwidth: row.5 as u64,
+            family: row.6,
+            exit_policy: row.7.parse().unwrap(),
         }
     }
 }
@@ -50,19 +80,31 @@ pub struct AuditInsert {
     pub state: String,
     pub version: String,
     pub ts: i64,
+    pub fingerprint: String,
+    pub bandwidth: i64,
+    pub family: Option<String>,
+    pub exit_policy: String,
 }
 
 impl AuditInsert {
-    pub fn new(addr: &SocketAddr, state: &NodeState, version: &Version, ts: i64) -> AuditInsert {
-        let addr = format!("{}", addr);
+    pub fn new(node: &Node, state: &NodeState, ts: i64) -> AuditInsert {
+        let addr = format!("{}", node.addr);
         let state = format!("{}", state);
-        let version = format!("{}", version);
+        let version = format!("{}", node.version);
+        let fingerprint = node.fingerprint.clone();
+        let bandwidth = node.bandwidth as i64;
+        let family = node.family.clone();
+        let exit_policy = format!("{}", node.exit_policy);
 
         AuditInsert {
             addr,
             state,
             version,
             ts,
+            fingerprint,
+            bandwidth,
+            family,
+            exit_policy,
         }
     }
 }
@@ -14,3 +14,9 @@ pub enum AuditError {
     #[fail(display = "invalid audit")]
     InvalidAudit,
 }
+
+#[derive(Debug, Fail)]
+pub enum ExitPolicyError {
+    #[fail(display = "invalid exit policy rule={}", rule)]
+    InvalidRule { rule: String },
+}
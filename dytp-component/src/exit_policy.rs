@@ -0,0 +1,299 @@
+use crate::error::{ExitPolicyError, Result};
+use failure::Error;
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Accept,
+    Reject,
+}
+
+//
+// A rule is written as `action:address:port` where
+// - action is `accept` or `reject`
+// - address is an IP address with an optional prefix length (`10.0.0.0/8`, `fc00::/7`) or `*`
+// - port is a port, a range of ports (`6660-6669`) or `*`
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub action: Action,
+    pub net: Option<(IpAddr, u8)>, // None matches any address
+    pub ports: Option<(u16, u16)>, // None matches any port
+}
+
+impl Rule {
+    fn matches_addr(&self, ip: IpAddr) -> bool {
+        match self.net {
+            Some((net, prefix)) => in_net(ip, net, prefix),
+            None => true,
+        }
+    }
+
+    fn matches_port(&self, port: u16) -> bool {
+        match self.ports {
+            Some((lo, hi)) => lo <= port && port <= hi,
+            None => true,
+        }
+    }
+}
+
+fn in_net(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+fn max_prefix(ip: IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.action {
+            Action::Accept => write!(f, "accept:")?,
+            Action::Reject => write!(f, "reject:")?,
+        }
+
+        match self.net {
+            Some((net, prefix)) if prefix == max_prefix(net) => write!(f, "{}:", net)?,
+            Some((net, prefix)) => write!(f, "{}/{}:", net, prefix)?,
+            None => write!(f, "*:")?,
+        }
+
+        match self.ports {
+            Some((lo, hi)) if lo == hi => write!(f, "{}", lo),
+            Some((lo, hi)) => write!(f, "{}-{}", lo, hi),
+            None => write!(f, "*"),
+        }
+    }
+}
+
+impl std::str::FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || ExitPolicyError::InvalidRule { rule: s.to_owned() };
+
+        // IPv6 addresses contain colons, so the port is taken from the end.
+        let (action, rest) = s.split_at(s.find(':').ok_or_else(invalid)?);
+        let (net, ports) = rest[1..].split_at(rest[1..].rfind(':').ok_or_else(invalid)?);
+        let ports = &ports[1..];
+
+        let action = match action {
+            "accept" => Action::Accept,
+            "reject" => Action::Reject,
+            _ => return Err(invalid().into()),
+        };
+
+        let net = match net {
+            "*" => None,
+            _ => {
+                let mut split = net.splitn(2, '/');
+                let ip: IpAddr = split.next().unwrap().parse().map_err(|_| invalid())?;
+                let prefix = match split.next() {
+                    Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+                    None => max_prefix(ip),
+                };
+
+                if prefix > max_prefix(ip) {
+                    return Err(invalid().into());
+                }
+
+                Some((ip, prefix))
+            }
+        };
+
+        let ports = match ports {
+            "*" => None,
+            _ => {
+                let mut split = ports.splitn(2, '-');
+                let lo: u16 = split.next().unwrap().parse().map_err(|_| invalid())?;
+                let hi: u16 = match split.next() {
+                    Some(hi) => hi.parse().map_err(|_| invalid())?,
+                    None => lo,
+                };
+
+                if lo > hi {
+                    return Err(invalid().into());
+                }
+
+                Some((lo, hi))
+            }
+        };
+
+        Ok(Rule { action, net, ports })
+    }
+}
+
+//
+// Which destinations an exit node connects to.
+// Rules are separated by commas and the first one matching the destination decides,
+// a destination matching no rule is rejected.
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExitPolicy {
+    pub rules: Vec<Rule>,
+}
+
+impl ExitPolicy {
+    pub fn allows(&self, ip: IpAddr, port: u16) -> bool {
+        self.rules
+            .iter()
+            .find(|r| r.matches_addr(ip) && r.matches_port(port))
+            .map(|r| r.action == Action::Accept)
+            .unwrap_or(false)
+    }
+
+    // For a hostname which is resolved only at the exit node.
+    // True unless every address is rejected on the port.
+    pub fn may_allow(&self, port: u16) -> bool {
+        for rule in self.rules.iter().filter(|r| r.matches_port(port)) {
+            match (rule.action, rule.net) {
+                (Action::Accept, _) => return true,
+                (Action::Reject, None) => return false,
+                (Action::Reject, Some(_)) => {}
+            }
+        }
+
+        false
+    }
+}
+
+impl std::fmt::Display for ExitPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let rules: Vec<String> = self.rules.iter().map(|r| format!("{}", r)).collect();
+
+        write!(f, "{}", rules.join(","))
+    }
+}
+
+impl std::str::FromStr for ExitPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let rules = s
+            .split(',')
+            .map(|r| r.trim().parse())
+            .collect::<Result<Vec<Rule>>>()?;
+
+        Ok(ExitPolicy { rules })
+    }
+}
+
+impl serde::Serialize for ExitPolicy {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(s: &str) -> ExitPolicy {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_rules() {
+        let p = policy("reject:10.0.0.0/8:*, accept:*:80,accept:fc00::/7:6660-6669");
+
+        assert_eq!(
+            p.rules,
+            vec![
+                Rule {
+                    action: Action::Reject,
+                    net: Some((ip("10.0.0.0"), 8)),
+                    ports: None,
+                },
+                Rule {
+                    action: Action::Accept,
+                    net: None,
+                    ports: Some((80, 80)),
+                },
+                Rule {
+                    action: Action::Accept,
+                    net: Some((ip("fc00::"), 7)),
+                    ports: Some((6660, 6669)),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn is_written_back_as_parsed() {
+        let s = "reject:10.0.0.0/8:*,accept:192.0.2.1:80,accept:::1:6660-6669,reject:*:*";
+
+        assert_eq!(format!("{}", policy(s)), s);
+    }
+
+    #[test]
+    fn refuses_invalid_rules() {
+        for s in &[
+            "",
+            "accept",
+            "accept:*",
+            "allow:*:*",
+            "accept:10.0.0.0/33:*",
+            "accept:::/129:*",
+            "accept:example.com:*",
+            "accept:*:80-79",
+            "accept:*:65536",
+            "accept:*:*,",
+        ] {
+            assert!(s.parse::<ExitPolicy>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let p = policy("reject:10.0.0.1:*,accept:10.0.0.0/8:*,accept:*:443");
+
+        assert!(!p.allows(ip("10.0.0.1"), 80));
+        assert!(p.allows(ip("10.0.0.2"), 80));
+        assert!(p.allows(ip("192.0.2.1"), 443));
+        assert!(!p.allows(ip("192.0.2.1"), 80));
+    }
+
+    #[test]
+    fn families_never_match_each_other() {
+        let p = policy("accept:0.0.0.0/0:*");
+
+        assert!(p.allows(ip("192.0.2.1"), 80));
+        assert!(!p.allows(ip("2001:db8::1"), 80));
+    }
+
+    #[test]
+    fn may_allow_unless_every_address_is_rejected() {
+        let p = policy("reject:10.0.0.0/8:*,reject:*:25,accept:*:*");
+
+        assert!(p.may_allow(80));
+        assert!(!p.may_allow(25));
+
+        // Some address may still be accepted after a rejected network.
+        let p = policy("reject:10.0.0.0/8:80,accept:192.0.2.0/24:80");
+
+        assert!(p.may_allow(80));
+        assert!(!p.may_allow(443));
+    }
+}
@@ -1,35 +1,28 @@
 use semver::Version;
 use serde_derive::Serialize;
 
+// Frames on the links of the circuits of a node since it started, dummies are counted in the totals too.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Traffic {
+    pub cells: u64,
+    pub bytes: u64,
+    pub padding_cells: u64,
+    pub padding_bytes: u64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct HealthRespNode {
-    version: Version, // Response including version
+    pub version: Version,  // Response including version
+    pub sent: Traffic,     // Toward the gateways
+    pub received: Traffic, // From the gateways
 }
 
 impl HealthRespNode {
-    pub fn new(version: &str) -> HealthRespNode {
+    pub fn new(version: &str, sent: Traffic, received: Traffic) -> HealthRespNode {
         HealthRespNode {
             version: Version::parse(version).unwrap(),
+            sent,
+            received,
         }
     }
 }
-
-impl Into<Vec<u8>> for HealthRespNode {
-    fn into(self) -> Vec<u8> {
-        format!("OK {}", self.version).into_bytes()
-    }
-}
-
-impl From<&[u8]> for HealthRespNode {
-    fn from(m: &[u8]) -> HealthRespNode {
-        let re = regex::Regex::new(r"^OK\s(.+?)$").unwrap();
-
-        for cap in re.captures_iter(std::str::from_utf8(m).unwrap()) {
-            if let Ok(v) = cap[1].parse() {
-                return HealthRespNode { version: v };
-            }
-        }
-
-        unreachable!();
-    }
-}
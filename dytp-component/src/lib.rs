@@ -3,6 +3,7 @@ extern crate diesel;
 
 pub mod audit;
 pub mod error;
+pub mod exit_policy;
 pub mod health_resp_cloud;
 pub mod health_resp_gateway;
 pub mod health_resp_node;
@@ -12,6 +13,7 @@ pub mod schema;
 
 pub mod prelude {
     pub use crate::audit::Audit;
+    pub use crate::exit_policy::ExitPolicy;
     pub use crate::node::Node;
     pub use crate::node_state::NodeState;
 }
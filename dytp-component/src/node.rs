@@ -1,3 +1,4 @@
+use crate::exit_policy::ExitPolicy;
 use crate::node_state::NodeState;
 use crate::schema::nodes;
 use diesel::deserialize::Queryable;
@@ -5,63 +6,86 @@ use semver::Version;
 use serde_derive::Serialize;
 use std::net::SocketAddr;
 
+// Families longer than this are refused on JOIN.
+pub const MAX_FAMILY_LEN: usize = 64;
+
+// Bandwidths advertised over 10GB/s are taken as 10GB/s.
+// It only bounds the value, the gateways weigh nodes by what the others advertise.
+pub const MAX_BANDWIDTH: u64 = 10 * 1024 * 1024;
+
 #[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Node {
     pub addr: SocketAddr,
     pub state: NodeState,
     pub version: Version,
+    pub fingerprint: String,     // Fingerprint of the identity key
+    pub bandwidth: u64,          // Bandwidth advertised by the node as KB/s
+    pub family: Option<String>,  // Nodes run by the same operator declare the same family
+    pub exit_policy: ExitPolicy, // Destinations the node connects to as the exit
 }
 
 impl Node {
-    pub fn new(addr: &SocketAddr, version: &Version) -> Node {
+    pub fn new(
+        addr: &SocketAddr,
+        version: &Version,
+        fingerprint: &str,
+        bandwidth: u64,
+        family: Option<String>,
+        exit_policy: ExitPolicy,
+    ) -> Node {
         Node {
             addr: addr.clone(),
             state: NodeState::ACTIVE,
             version: version.clone(),
+            fingerprint: fingerprint.to_owned(),
+            bandwidth,
+            family,
+            exit_policy,
         }
     }
 }
 
-impl std::fmt::Display for Node {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{} {} {}", self.addr, self.state, self.version)
-    }
+// A node without a family is written as "-" where fields are separated by spaces.
+pub fn format_family(family: &Option<String>) -> &str {
+    family.as_ref().map(|f| f.as_str()).unwrap_or("-")
 }
 
-impl Into<Vec<u8>> for Node {
-    fn into(self) -> Vec<u8> {
-        format!("{} {} {}", self.addr, self.state, self.version).into_bytes()
-    }
+// Families are compared as they are, so one is a single word of a bounded length.
+pub fn is_valid_family(family: &str) -> bool {
+    !family.is_empty()
+        && family != "-"
+        && family.len() <= MAX_FAMILY_LEN
+        && !family.contains(char::is_whitespace)
 }
 
-impl From<&[u8]> for Node {
-    fn from(n: &[u8]) -> Node {
-        let re = regex::Regex::new(r"^(.+?)\s(.+?)\s(.+?)$").unwrap();
-
-        for cap in re.captures_iter(std::str::from_utf8(n).unwrap()) {
-            let addr = cap[1].parse().unwrap();
-            let state = cap[2].parse().unwrap();
-            let version = cap[3].parse().unwrap();
-
-            return Node {
-                addr,
-                state,
-                version,
-            };
-        }
-
-        unreachable!();
+impl std::fmt::Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {} {}",
+            self.addr,
+            self.state,
+            self.version,
+            self.fingerprint,
+            self.bandwidth,
+            format_family(&self.family),
+            self.exit_policy
+        )
     }
 }
 
 impl Queryable<nodes::SqlType, diesel::pg::Pg> for Node {
-    type Row = (String, String, String);
+    type Row = (String, String, String, String, i64, Option<String>, String);
 
     fn build(row: Self::Row) -> Self {
         Node {
             addr: row.0.parse().unwrap(),
             state: row.1.parse().unwrap(),
             version: row.2.parse().unwrap(),
+            fingerprint: row.3,
+            bandwidth: row.4 as u64,
+            family: row.5,
+            exit_policy: row.6.parse().unwrap(),
         }
     }
 }
@@ -72,18 +96,37 @@ pub struct NodeInsert {
     pub addr: String,
     pub state: String,
     pub version: String,
+    pub fingerprint: String,
+    pub bandwidth: i64,
+    pub family: Option<String>,
+    pub exit_policy: String,
 }
 
 impl NodeInsert {
-    pub fn new(addr: &SocketAddr, version: &Version) -> NodeInsert {
+    pub fn new(
+        addr: &SocketAddr,
+        version: &Version,
+        fingerprint: &str,
+        bandwidth: u64,
+        family: Option<&str>,
+        exit_policy: &ExitPolicy,
+    ) -> NodeInsert {
         let addr = format!("{}", addr);
         let state = format!("{}", NodeState::ACTIVE);
         let version = format!("{}", version);
+        let fingerprint = fingerprint.to_owned();
+        let bandwidth = bandwidth as i64;
+        let family = family.map(|f| f.to_owned());
+        let exit_policy = format!("{}", exit_policy);
 
         NodeInsert {
             addr,
             state,
             version,
+            fingerprint,
+            bandwidth,
+            family,
+            exit_policy,
         }
     }
 }
@@ -93,13 +136,35 @@ impl NodeInsert {
 pub struct NodeUpdate {
     pub state: Option<String>,
     pub version: Option<String>,
+    pub fingerprint: Option<String>,
+    pub bandwidth: Option<i64>,
+    pub family: Option<Option<String>>,
+    pub exit_policy: Option<String>,
 }
 
 impl NodeUpdate {
-    pub fn new(state: Option<&NodeState>, version: Option<&Version>) -> NodeUpdate {
+    pub fn new(
+        state: Option<&NodeState>,
+        version: Option<&Version>,
+        fingerprint: Option<&str>,
+        bandwidth: Option<u64>,
+        family: Option<Option<&str>>,
+        exit_policy: Option<&ExitPolicy>,
+    ) -> NodeUpdate {
         let state = state.map(|s| format!("{}", s));
         let version = version.map(|v| format!("{}", v));
+        let fingerprint = fingerprint.map(|f| f.to_owned());
+        let bandwidth = bandwidth.map(|b| b as i64);
+        let family = family.map(|f| f.map(|f| f.to_owned()));
+        let exit_policy = exit_policy.map(|e| format!("{}", e));
 
-        NodeUpdate { state, version }
+        NodeUpdate {
+            state,
+            version,
+            fingerprint,
+            bandwidth,
+            family,
+            exit_policy,
+        }
     }
 }
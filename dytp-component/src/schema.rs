@@ -4,6 +4,10 @@ table! {
         state -> Varchar,
         version -> Varchar,
         ts -> Int8,
+        fingerprint -> Varchar,
+        bandwidth -> Int8,
+        family -> Nullable<Varchar>,
+        exit_policy -> Varchar,
     }
 }
 
@@ -12,6 +16,10 @@ table! {
         addr -> Varchar,
         state -> Varchar,
         version -> Varchar,
+        fingerprint -> Varchar,
+        bandwidth -> Int8,
+        family -> Nullable<Varchar>,
+        exit_policy -> Varchar,
     }
 }
 
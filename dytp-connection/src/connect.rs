@@ -0,0 +1,67 @@
+use crate::upstream::Upstream;
+use failure::Error;
+use futures::prelude::*;
+use std::net::SocketAddr;
+use tokio::net::tcp::ConnectFuture;
+use tokio::net::TcpStream;
+
+//
+// Resolves to the connection to the first address which accepts it.
+// The task isn't blocked while the handshake is in flight.
+//
+#[derive(Debug)]
+pub struct Connect {
+    addrs: std::vec::IntoIter<SocketAddr>,
+    addr: Option<SocketAddr>,
+    connecting: Option<ConnectFuture>,
+    read_timeout: u64,
+}
+
+impl Connect {
+    pub fn new(addrs: Vec<SocketAddr>, read_timeout: u64) -> Connect {
+        Connect {
+            addrs: addrs.into_iter(),
+            addr: None,
+            connecting: None,
+            read_timeout,
+        }
+    }
+}
+
+impl Future for Connect {
+    type Item = Upstream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut error: std::io::Error = std::io::ErrorKind::AddrNotAvailable.into();
+
+        loop {
+            if let Some(connecting) = self.connecting.as_mut() {
+                match connecting.poll() {
+                    Ok(Async::Ready(stream)) => {
+                        let _ = stream.set_nodelay(true);
+
+                        return Ok(Async::Ready(Upstream::from_stream(
+                            stream,
+                            self.read_timeout,
+                        )));
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => {
+                        log::warn!("failed to connect to {:?} due to error={:?}", self.addr, e);
+
+                        error = e;
+                    }
+                }
+            }
+
+            match self.addrs.next() {
+                Some(addr) => {
+                    self.addr = Some(addr);
+                    self.connecting = Some(TcpStream::connect(&addr));
+                }
+                None => return Err(error.into()),
+            }
+        }
+    }
+}
@@ -5,12 +5,33 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Fail)]
 pub enum RequestError {
-    #[fail(display = "failed to lookup ip address of {}", host)]
-    LookupFailure { host: String },
-
     #[fail(display = "invalid request (request host not found)")]
     HostNotFound,
 
     #[fail(display = "invalid request (request path not found)")]
     PathNotFound,
+
+    #[fail(display = "invalid socks request")]
+    InvalidSocksRequest,
+
+    #[fail(display = "invalid socks credentials (specified by `username:password`)")]
+    InvalidSocksAuth,
+
+    #[fail(display = "invalid proxy protocol={}", s)]
+    InvalidProxy { s: String },
+
+    #[fail(display = "invalid http message (too large head)")]
+    HeadTooLarge,
+
+    #[fail(display = "invalid http message (invalid content-length)")]
+    InvalidContentLength,
+
+    #[fail(display = "invalid http message (transfer-encoding other than a final chunked)")]
+    InvalidTransferEncoding,
+
+    #[fail(display = "invalid http message (invalid chunk)")]
+    InvalidChunk,
+
+    #[fail(display = "invalid header value={:?} (must be a single line)", s)]
+    InvalidHeaderValue { s: String },
 }
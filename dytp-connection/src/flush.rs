@@ -0,0 +1,29 @@
+use crate::Connection;
+use failure::Error;
+use futures::prelude::*;
+use futures::try_ready;
+
+//
+// Resolves to the connection once everything written to it has been sent.
+//
+#[derive(Debug)]
+pub struct Flush<C> {
+    conn: Option<C>,
+}
+
+impl<C: Connection> Flush<C> {
+    pub fn new(conn: C) -> Flush<C> {
+        Flush { conn: Some(conn) }
+    }
+}
+
+impl<C: Connection> Future for Flush<C> {
+    type Item = C;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        try_ready!(self.conn.as_mut().unwrap().poll_flush());
+
+        Ok(Async::Ready(self.conn.take().unwrap()))
+    }
+}
@@ -0,0 +1,621 @@
+use crate::error::{RequestError, Result};
+
+// Headers are parsed into an array of this size first, then into larger ones up to MAX_HEADERS.
+const HEADERS: usize = 32;
+const MAX_HEADERS: usize = 1024;
+
+// A head (or a line of a chunked body) longer than this is refused.
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+fn headers(parsed: &[httparse::Header]) -> Vec<Header> {
+    parsed
+        .iter()
+        .map(|h| Header {
+            name: h.name.to_owned(),
+            value: h.value.to_vec(),
+        })
+        .collect()
+}
+
+fn find<'a>(headers: &'a [Header], name: &str) -> impl Iterator<Item = &'a [u8]> + 'a {
+    let name = name.to_owned();
+
+    headers
+        .iter()
+        .filter(move |h| h.name.eq_ignore_ascii_case(&name))
+        .map(|h| h.value.as_slice())
+}
+
+// True if any comma separated value of the headers is the token.
+fn has_token(headers: &[Header], name: &str, token: &str) -> bool {
+    find(headers, name).any(|v| {
+        String::from_utf8_lossy(v)
+            .split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+// Persistent connections are the default since HTTP/1.1.
+fn keep_alive(version: u8, headers: &[Header], connection: &str) -> bool {
+    if has_token(headers, connection, "close") {
+        return false;
+    }
+
+    version >= 1 || has_token(headers, connection, "keep-alive")
+}
+
+// The codings in the order they were applied.
+fn transfer_codings(headers: &[Header]) -> Vec<String> {
+    find(headers, "Transfer-Encoding")
+        .flat_map(|v| {
+            String::from_utf8_lossy(v)
+                .split(',')
+                .map(|t| t.trim().to_ascii_lowercase())
+                .filter(|t| !t.is_empty())
+                .collect::<Vec<String>>()
+        })
+        .collect()
+}
+
+//
+// Transfer-Encoding overrides Content-Length. (RFC7230 3.3.3)
+// Only chunked applied last tells where the body ends. Otherwise a request is refused,
+// and a response is read until the close.
+// More than one Content-Length is refused even if they agree,
+// since the destination may take another one than this gateway.
+//
+fn body(headers: &[Header], request: bool) -> Result<Body> {
+    let codings = transfer_codings(headers);
+
+    if find(headers, "Transfer-Encoding").next().is_some() {
+        let chunked = codings.iter().position(|t| t == "chunked");
+
+        if chunked.is_some() && chunked == Some(codings.len() - 1) {
+            return Ok(Body::Chunked);
+        }
+
+        if request {
+            return Err(RequestError::InvalidTransferEncoding.into());
+        }
+
+        return Ok(Body::Close);
+    }
+
+    let mut lengths = find(headers, "Content-Length");
+
+    match (lengths.next(), lengths.next()) {
+        (Some(len), None) => {
+            let len: u64 = std::str::from_utf8(len)
+                .map_err(|_| RequestError::InvalidContentLength)?
+                .trim()
+                .parse()
+                .map_err(|_| RequestError::InvalidContentLength)?;
+
+            Ok(Body::Length(len))
+        }
+        (Some(_), Some(_)) => Err(RequestError::InvalidContentLength.into()),
+        _ => Ok(Body::Close),
+    }
+}
+
+//
+// How the end of a message body is found.
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Body {
+    Empty,
+    Length(u64), // Content-Length
+    Chunked,     // Transfer-Encoding: chunked
+    Close,       // Read until the connection is closed
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub version: u8, // 0 for HTTP/1.0, 1 for HTTP/1.1
+    pub headers: Vec<Header>,
+    pub len: usize, // Bytes of the head including the empty line
+}
+
+impl RequestHead {
+    // Returns None until the whole head has been received.
+    pub fn parse(buf: &[u8]) -> Result<Option<RequestHead>> {
+        let mut size = HEADERS;
+
+        loop {
+            let mut parsed = vec![httparse::EMPTY_HEADER; size];
+            let mut req = httparse::Request::new(&mut parsed);
+
+            let len = match req.parse(buf) {
+                Ok(httparse::Status::Complete(len)) => len,
+                Ok(httparse::Status::Partial) => return Ok(None),
+                Err(httparse::Error::TooManyHeaders) if size < MAX_HEADERS => {
+                    size *= 2;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            return Ok(Some(RequestHead {
+                method: req.method.unwrap_or_default().to_owned(),
+                path: req.path.unwrap_or_default().to_owned(),
+                version: req.version.unwrap_or(1),
+                headers: headers(req.headers),
+                len,
+            }));
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        find(&self.headers, name).next()
+    }
+
+    // Requests have a body only if they say so.
+    pub fn body(&self) -> Result<Body> {
+        match body(&self.headers, true)? {
+            Body::Close => Ok(Body::Empty),
+            body => Ok(body),
+        }
+    }
+
+    // Clients talking to a proxy may ask for the persistent connection by Proxy-Connection.
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(self.version, &self.headers, "Connection")
+            && !has_token(&self.headers, "Proxy-Connection", "close")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseHead {
+    pub code: u16,
+    pub version: u8,
+    pub headers: Vec<Header>,
+    pub len: usize,
+}
+
+impl ResponseHead {
+    pub fn parse(buf: &[u8]) -> Result<Option<ResponseHead>> {
+        let mut size = HEADERS;
+
+        loop {
+            let mut parsed = vec![httparse::EMPTY_HEADER; size];
+            let mut res = httparse::Response::new(&mut parsed);
+
+            let len = match res.parse(buf) {
+                Ok(httparse::Status::Complete(len)) => len,
+                Ok(httparse::Status::Partial) => return Ok(None),
+                Err(httparse::Error::TooManyHeaders) if size < MAX_HEADERS => {
+                    size *= 2;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            return Ok(Some(ResponseHead {
+                code: res.code.unwrap_or_default(),
+                version: res.version.unwrap_or(1),
+                headers: headers(res.headers),
+                len,
+            }));
+        }
+    }
+
+    // Informational responses are followed by the final one, except for switching protocols.
+    pub fn is_informational(&self) -> bool {
+        self.code >= 100 && self.code < 200 && self.code != 101
+    }
+
+    pub fn body(&self, method: &str) -> Result<Body> {
+        if method.eq_ignore_ascii_case("HEAD")
+            || self.is_informational()
+            || self.code == 204
+            || self.code == 304
+        {
+            return Ok(Body::Empty);
+        }
+
+        // Whatever follows belongs to the new protocol.
+        if self.code == 101 {
+            return Ok(Body::Close);
+        }
+
+        body(&self.headers, false)
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(self.version, &self.headers, "Connection")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Chunk {
+    Size(Vec<u8>),    // Reading the line of the chunk size
+    Data(u64),        // Bytes left in the chunk
+    DataEnd(usize),   // Bytes left of CRLF after the chunk
+    Trailer(Vec<u8>), // Reading a line of the trailer
+}
+
+//
+// Finds the end of a message body in the bytes relayed as they are.
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyReader {
+    body: Body,
+    remaining: u64,
+    chunk: Chunk,
+    done: bool,
+}
+
+impl BodyReader {
+    pub fn new(body: Body) -> BodyReader {
+        let remaining = match body {
+            Body::Length(len) => len,
+            _ => 0,
+        };
+
+        BodyReader {
+            body,
+            remaining,
+            chunk: Chunk::Size(Vec::new()),
+            done: body == Body::Empty || body == Body::Length(0),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn is_close_delimited(&self) -> bool {
+        self.body == Body::Close
+    }
+
+    // Returns how many bytes of the buffer belong to the body,
+    // the rest belongs to the next message.
+    pub fn consume(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+
+        match self.body {
+            Body::Empty => Ok(0),
+            Body::Close => Ok(buf.len()),
+            Body::Length(_) => {
+                let n = std::cmp::min(self.remaining, buf.len() as u64);
+
+                self.remaining -= n;
+                self.done = self.remaining == 0;
+
+                Ok(n as usize)
+            }
+            Body::Chunked => {
+                let mut n = 0;
+
+                while n < buf.len() && !self.done {
+                    n += self.consume_chunk(&buf[n..])?;
+                }
+
+                Ok(n)
+            }
+        }
+    }
+
+    fn consume_chunk(&mut self, buf: &[u8]) -> Result<usize> {
+        match &mut self.chunk {
+            Chunk::Size(line) | Chunk::Trailer(line) => {
+                let (n, complete) = match buf.iter().position(|b| *b == b'\n') {
+                    Some(idx) => (idx + 1, true),
+                    None => (buf.len(), false),
+                };
+
+                line.extend_from_slice(&buf[..n]);
+
+                if line.len() > MAX_HEAD_SIZE {
+                    return Err(RequestError::InvalidChunk.into());
+                }
+
+                if complete {
+                    self.line_done()?;
+                }
+
+                Ok(n)
+            }
+            Chunk::Data(remaining) => {
+                let n = std::cmp::min(*remaining, buf.len() as u64);
+
+                *remaining -= n;
+
+                if *remaining == 0 {
+                    self.chunk = Chunk::DataEnd(2);
+                }
+
+                Ok(n as usize)
+            }
+            // Anything else after the data would be taken differently by the destination.
+            Chunk::DataEnd(remaining) => {
+                let n = std::cmp::min(*remaining, buf.len());
+                let crlf = &b"\r\n"[2 - *remaining..2 - *remaining + n];
+
+                if &buf[..n] != crlf {
+                    return Err(RequestError::InvalidChunk.into());
+                }
+
+                *remaining -= n;
+
+                if *remaining == 0 {
+                    self.chunk = Chunk::Size(Vec::new());
+                }
+
+                Ok(n)
+            }
+        }
+    }
+
+    fn line_done(&mut self) -> Result<()> {
+        match &self.chunk {
+            Chunk::Size(line) => {
+                // An empty line is taken as the last chunk by httparse.
+                if !line.first().map(|b| b.is_ascii_hexdigit()).unwrap_or(false) {
+                    return Err(RequestError::InvalidChunk.into());
+                }
+
+                let size = match httparse::parse_chunk_size(line) {
+                    Ok(httparse::Status::Complete((_, size))) => size,
+                    _ => return Err(RequestError::InvalidChunk.into()),
+                };
+
+                // The last chunk is followed by the trailer.
+                self.chunk = if size == 0 {
+                    Chunk::Trailer(Vec::new())
+                } else {
+                    Chunk::Data(size)
+                };
+            }
+            Chunk::Trailer(line) => {
+                // The trailer ends with an empty line.
+                if line.as_slice() == b"\r\n" || line.as_slice() == b"\n" {
+                    self.done = true;
+                } else {
+                    self.chunk = Chunk::Trailer(Vec::new());
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+//
+// Follows the responses to a request in the bytes relayed to the client.
+// Informational responses (e.g. 100 Continue) are passed through until the final one.
+//
+#[derive(Debug)]
+pub struct ResponseReader {
+    method: String,
+    head: Vec<u8>,
+    response: Option<(ResponseHead, BodyReader)>,
+    closed: bool,
+}
+
+impl ResponseReader {
+    pub fn new(method: &str) -> ResponseReader {
+        ResponseReader {
+            method: method.to_owned(),
+            head: Vec::new(),
+            response: None,
+            closed: false,
+        }
+    }
+
+    // Returns how many bytes of the buffer belong to the response.
+    pub fn feed(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut n = 0;
+
+        while n < buf.len() && !self.is_done() {
+            if let Some((_, body)) = self.response.as_mut() {
+                n += body.consume(&buf[n..])?;
+                continue;
+            }
+
+            let before = self.head.len();
+
+            self.head.extend_from_slice(&buf[n..]);
+
+            match ResponseHead::parse(&self.head)? {
+                Some(head) => {
+                    n += head.len - before;
+
+                    self.head.clear();
+
+                    if !head.is_informational() {
+                        let body = BodyReader::new(head.body(&self.method)?);
+
+                        self.response = Some((head, body));
+                    }
+                }
+                None => {
+                    if self.head.len() > MAX_HEAD_SIZE {
+                        return Err(RequestError::HeadTooLarge.into());
+                    }
+
+                    n = buf.len();
+                }
+            }
+        }
+
+        Ok(n)
+    }
+
+    // The destination has closed the stream.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    pub fn is_done(&self) -> bool {
+        match &self.response {
+            Some((_, body)) => body.is_done() || (self.closed && body.is_close_delimited()),
+            None => false,
+        }
+    }
+
+    // The connection to the client may be kept for the next request.
+    pub fn keep_alive(&self) -> bool {
+        match &self.response {
+            Some((head, body)) => body.is_done() && !body.is_close_delimited() && head.keep_alive(),
+            None => false,
+        }
+    }
+
+    pub fn is_upgraded(&self) -> bool {
+        match &self.response {
+            Some((head, _)) => head.code == 101,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNKED: &[u8] = b"5\r\nhello\r\na;ext=1\r\n, chunked!\r\n0\r\nTrailer: x\r\n\r\n";
+
+    fn chunked() -> BodyReader {
+        BodyReader::new(Body::Chunked)
+    }
+
+    fn request(head: &str) -> RequestHead {
+        RequestHead::parse(head.as_bytes()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn reads_chunked_body() {
+        let mut reader = chunked();
+        let buf = [CHUNKED, b"GET / HTTP/1.1\r\n"].concat();
+
+        assert_eq!(reader.consume(&buf).unwrap(), CHUNKED.len());
+        assert!(reader.is_done());
+    }
+
+    #[test]
+    fn reads_chunked_body_byte_by_byte() {
+        let mut reader = chunked();
+
+        for (i, b) in CHUNKED.iter().enumerate() {
+            assert!(!reader.is_done());
+            assert_eq!(reader.consume(&[*b]).unwrap(), 1, "at {}", i);
+        }
+
+        assert!(reader.is_done());
+        assert_eq!(reader.consume(b"next").unwrap(), 0);
+    }
+
+    #[test]
+    fn refuses_invalid_chunk_size() {
+        let sizes: Vec<&[u8]> = vec![b"zz\r\n", b"-1\r\n", b"\r\n", b"fffffffffffffffffff\r\n"];
+
+        for size in sizes {
+            assert!(chunked().consume(size).is_err(), "{:?}", size);
+        }
+    }
+
+    #[test]
+    fn refuses_chunk_size_line_too_long() {
+        let line = vec![b'0'; MAX_HEAD_SIZE + 1];
+
+        assert!(chunked().consume(&line).is_err());
+    }
+
+    #[test]
+    fn refuses_chunk_without_crlf() {
+        assert!(chunked().consume(b"5\r\nhelloXX0\r\n\r\n").is_err());
+        assert!(chunked().consume(b"5\r\nhello\n0\r\n\r\n").is_err());
+
+        // Split between the reads.
+        let mut reader = chunked();
+
+        assert!(reader.consume(b"5\r\nhello\r").is_ok());
+        assert!(reader.consume(b"X0\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn reads_content_length() {
+        let mut reader = BodyReader::new(Body::Length(5));
+
+        assert_eq!(reader.consume(b"hel").unwrap(), 3);
+        assert_eq!(reader.consume(b"loGET").unwrap(), 2);
+        assert!(reader.is_done());
+    }
+
+    #[test]
+    fn takes_chunked_applied_last() {
+        let head = request(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n",
+        );
+
+        assert_eq!(head.body().unwrap(), Body::Chunked);
+    }
+
+    #[test]
+    fn refuses_request_not_chunked_last() {
+        let heads = vec![
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: identity\r\n\r\n",
+        ];
+
+        for head in heads {
+            assert!(request(head).body().is_err(), "{}", head);
+        }
+    }
+
+    #[test]
+    fn reads_response_not_chunked_last_until_close() {
+        let head =
+            ResponseHead::parse(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked, gzip\r\n\r\n")
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(head.body("GET").unwrap(), Body::Close);
+    }
+
+    #[test]
+    fn refuses_invalid_content_length() {
+        let heads = vec![
+            "POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: -3\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 3, 3\r\n\r\n",
+        ];
+
+        for head in heads {
+            assert!(request(head).body().is_err(), "{}", head);
+        }
+
+        assert_eq!(
+            request("POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\n")
+                .body()
+                .unwrap(),
+            Body::Length(3)
+        );
+        assert_eq!(
+            request("GET / HTTP/1.1\r\n\r\n").body().unwrap(),
+            Body::Empty
+        );
+    }
+
+    #[test]
+    fn waits_for_the_whole_head() {
+        assert_eq!(
+            RequestHead::parse(b"GET / HTTP/1.1\r\nHost: a").unwrap(),
+            None
+        );
+        assert!(RequestHead::parse(b"GET / HTTP/1.1\r\nHo st: a\r\n\r\n").is_err());
+    }
+}
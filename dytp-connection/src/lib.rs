@@ -1,24 +1,81 @@
 #![recursion_limit = "128"]
 
+pub mod connect;
 pub mod error;
+pub mod flush;
+pub mod http;
 pub mod origin;
 pub mod request;
+pub mod socks;
 pub mod upstream;
 pub mod prelude {
+    pub use super::connect::Connect;
+    pub use super::flush::Flush;
     pub use super::origin::Origin;
-    pub use super::request::{Request, RequestContext};
+    pub use super::request::{HeaderPolicy, Proxy, Request, RequestContext};
+    pub use super::socks::SocksAuth;
     pub use super::upstream::Upstream;
     pub use super::Connection;
     pub use std::io::Write;
 }
 
-use bytes::{BufMut, BytesMut};
+use bytes::BytesMut;
 use dytp_protocol as protocol;
+use dytp_protocol::codec::Codec;
 use dytp_protocol::delim::Delim;
 use failure::Error;
 use futures::prelude::*;
+use futures::try_ready;
 use std::time::{Duration, Instant};
-use tokio::prelude::*;
+use tokio::codec::Decoder;
+use tokio::io::AsyncRead;
+use tokio::timer::Delay;
+
+// Writes are refused once this many bytes are waiting for the socket.
+// Relays stop reading the other side until the buffer is drained.
+pub const WB_LIMIT: usize = 256 * 1024;
+
+pub use dytp_protocol::codec::CHUNK_SIZE;
+
+// Bytes read from a socket at once.
+const READ_SIZE: usize = 16 * 1024;
+
+//
+// Reads until the buffer holds as much as the codec may need for a frame.
+// The rest is left in the socket until the frames buffered are taken,
+// so that the task isn't woken up for it meanwhile.
+//
+pub fn fill<R: AsyncRead>(stream: &mut R, rb: &mut BytesMut, codec: &Codec) -> Poll<(), Error> {
+    loop {
+        let len = rb.len();
+        let limit = codec.read_limit(rb);
+
+        if len >= limit {
+            return Ok(Async::NotReady);
+        }
+
+        rb.resize(len + std::cmp::min(limit - len, READ_SIZE), 0);
+
+        match stream.poll_read(&mut rb[len..]) {
+            Ok(Async::Ready(0)) => {
+                rb.truncate(len);
+
+                return Ok(Async::Ready(()));
+            }
+            Ok(Async::Ready(n)) => rb.truncate(len + n),
+            Ok(Async::NotReady) => {
+                rb.truncate(len);
+
+                return Ok(Async::NotReady);
+            }
+            Err(e) => {
+                rb.truncate(len);
+
+                return Err(e.into());
+            }
+        }
+    }
+}
 
 pub trait Connection {
     fn wb(&self) -> &BytesMut;
@@ -29,11 +86,18 @@ pub trait Connection {
     fn read_delim_mut(&mut self) -> &mut Delim;
     fn read_timeout(&self) -> &Duration;
     fn read_timeout_mut(&mut self) -> &mut Duration;
-    fn read_since(&self) -> &Option<Instant>;
-    fn read_since_mut(&mut self) -> &mut Option<Instant>;
+    fn read_timer(&self) -> &Option<Delay>;
+    fn read_timer_mut(&mut self) -> &mut Option<Delay>;
     fn write_delim(&self) -> &Delim;
     fn write_delim_mut(&mut self) -> &mut Delim;
     fn fill(&mut self) -> Poll<(), Error>;
+    fn poll_write_wb(&mut self) -> Poll<usize, std::io::Error>;
+    fn shutdown_write(&mut self) -> Result<(), std::io::Error>;
+
+    // Frames delimited by Dytp or Http larger than this close the connection.
+    fn max_frame_size(&self) -> usize {
+        protocol::max_frame_size()
+    }
 
     fn set_read_delim(&mut self, delim: Delim) {
         *self.read_delim_mut() = delim;
@@ -43,6 +107,11 @@ pub trait Connection {
         *self.write_delim_mut() = delim;
     }
 
+    fn set_read_timeout(&mut self, read_timeout: Duration) {
+        *self.read_timeout_mut() = read_timeout;
+        self.reset_read_timer();
+    }
+
     fn wb_remaining(&self) -> bool {
         !self.wb().is_empty()
     }
@@ -51,39 +120,115 @@ pub trait Connection {
         !self.rb().is_empty()
     }
 
+    fn wb_full(&self) -> bool {
+        self.wb().len() >= WB_LIMIT
+    }
+
     fn try_write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
-        match self.write_delim() {
-            Delim::Dytp => {
-                let protocol = protocol::Protocol::from(buf);
-                self.wb_mut().reserve(protocol.size());
+        if self.wb_full() {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
 
-                let protocol_buf: BytesMut = protocol.into();
-                self.wb_mut().put(&protocol_buf);
-            }
-            Delim::Http => {
-                self.wb_mut().reserve(buf.len() + 2);
-                self.wb_mut().put(buf);
-                self.wb_mut().put(b"\r\n" as &[u8]);
-            }
-            Delim::None => {
-                self.wb_mut().reserve(buf.len());
-                self.wb_mut().put(buf);
+        // A connection being written to is not idle.
+        self.reset_read_timer();
+        self.put_frame(buf);
+
+        Ok(buf.len())
+    }
+
+    // Writes the payloads at once, so that a message split into many frames
+    // is never cut in the middle by the limit of the write buffer.
+    fn try_write_all(&mut self, bufs: &[Vec<u8>]) -> Result<usize, std::io::Error> {
+        if self.wb_full() {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+
+        self.reset_read_timer();
+
+        for buf in bufs {
+            self.put_frame(buf);
+        }
+
+        Ok(bufs.iter().map(|buf| buf.len()).sum())
+    }
+
+    fn put_frame(&mut self, buf: &[u8]) {
+        let codec = Codec::new(self.write_delim(), self.max_frame_size());
+
+        codec.put(buf, self.wb_mut());
+    }
+
+    // Whatever the socket doesn't take now is kept in the write buffer,
+    // and the task is parked until the socket gets writable.
+    fn poll_flush(&mut self) -> Poll<(), std::io::Error> {
+        while self.wb_remaining() {
+            let n = try_ready!(self.poll_write_wb());
+
+            if n == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
             }
+
+            let _ = self.wb_mut().split_to(n);
         }
 
-        Ok(buf.len())
+        Ok(Async::Ready(()))
     }
 
-    fn try_read(&mut self) -> Poll<Option<BytesMut>, Error> {
-        if self.read_since().is_none() {
-            *self.read_since_mut() = Some(Instant::now());
+    // Sends FIN to the peer once everything written has been sent.
+    fn poll_shutdown(&mut self) -> Poll<(), std::io::Error> {
+        try_ready!(self.poll_flush());
+
+        self.shutdown_write()?;
+
+        Ok(Async::Ready(()))
+    }
+
+    // Polling the timer registers the current task to be woken up at the deadline,
+    // so a reader waiting for data doesn't have to be polled again by itself.
+    fn read_timed_out(&mut self) -> bool {
+        // A zero timeout never expires.
+        if *self.read_timeout() == Duration::from_secs(0) {
+            return false;
         }
 
+        // A connection still sending what has been written to it is not idle either.
+        if self.wb_remaining() {
+            self.reset_read_timer();
+
+            return false;
+        }
+
+        if self.read_timer().is_none() {
+            let deadline = Instant::now() + *self.read_timeout();
+
+            *self.read_timer_mut() = Some(Delay::new(deadline));
+        }
+
+        match self.read_timer_mut().as_mut().unwrap().poll() {
+            Ok(Async::NotReady) => false,
+            Ok(Async::Ready(())) => true,
+            Err(e) => {
+                log::warn!("failed to poll the read timer error={:?}", e);
+                true
+            }
+        }
+    }
+
+    fn reset_read_timer(&mut self) {
+        *self.read_timer_mut() = None;
+    }
+
+    fn try_read(&mut self) -> Poll<Option<BytesMut>, Error> {
         let disconnected = self.fill()?.is_ready();
 
         if !self.rb().is_empty() {
-            if let Some(payload) = self.try_read_delim() {
-                *self.read_since_mut() = None;
+            let payload = self.try_read_delim().map_err(|e| {
+                log::warn!("close the connection error={}", e);
+                e
+            })?;
+
+            if let Some(payload) = payload {
+                self.reset_read_timer();
 
                 return Ok(Async::Ready(Some(payload)));
             }
@@ -92,44 +237,19 @@ pub trait Connection {
         if disconnected {
             Ok(Async::Ready(None))
         } else {
-            if Instant::now().duration_since(*self.read_since().as_ref().unwrap())
-                > *self.read_timeout()
-            {
+            if self.read_timed_out() {
                 log::debug!("read timeout");
 
                 return Ok(Async::Ready(None));
             }
 
-            task::current().notify();
-
             Ok(Async::NotReady)
         }
     }
 
-    fn try_read_delim(&mut self) -> Option<BytesMut> {
-        match self.read_delim() {
-            Delim::Dytp => {
-                if let Some(p) = protocol::parse(self.rb_mut()) {
-                    Some((p.1).0)
-                } else {
-                    None
-                }
-            }
-            Delim::Http => self
-                .rb()
-                .windows(2)
-                .enumerate()
-                .find(|&(_, bytes)| bytes == b"\r\n")
-                .map(|(i, _)| i)
-                .map(|i| {
-                    let mut p = self.rb_mut().split_to(i + 2);
-                    p.split_off(i);
-                    p
-                }),
-            Delim::None => {
-                let len = self.rb().len();
-                Some(self.rb_mut().split_to(len))
-            }
-        }
+    fn try_read_delim(&mut self) -> Result<Option<BytesMut>, Error> {
+        let mut codec = Codec::new(self.read_delim(), self.max_frame_size());
+
+        codec.decode(self.rb_mut())
     }
 }
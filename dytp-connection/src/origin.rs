@@ -1,13 +1,18 @@
 use crate::Connection;
 use bytes::BytesMut;
+use dytp_protocol::codec::Codec;
 use dytp_protocol::delim::Delim;
+use dytp_protocol::error::WireError;
+use dytp_protocol::method::plain;
 use failure::Error;
 use futures::prelude::*;
 use futures::try_ready;
 use std::io::Write;
-use std::time::{Duration, Instant};
+use std::net::Shutdown;
+use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio::prelude::*;
+use tokio::timer::Delay;
 
 #[derive(Debug)]
 pub struct Origin {
@@ -17,7 +22,8 @@ pub struct Origin {
     read_delim: Delim,
     write_delim: Delim,
     read_timeout: Duration,
-    read_since: Option<Instant>,
+    read_timer: Option<Delay>,
+    negotiating: bool, // Waiting for HELLO
 }
 
 impl Connection for Origin {
@@ -53,12 +59,12 @@ impl Connection for Origin {
         &mut self.read_timeout
     }
 
-    fn read_since(&self) -> &Option<Instant> {
-        &self.read_since
+    fn read_timer(&self) -> &Option<Delay> {
+        &self.read_timer
     }
 
-    fn read_since_mut(&mut self) -> &mut Option<Instant> {
-        &mut self.read_since
+    fn read_timer_mut(&mut self) -> &mut Option<Delay> {
+        &mut self.read_timer
     }
 
     fn write_delim(&self) -> &Delim {
@@ -70,15 +76,17 @@ impl Connection for Origin {
     }
 
     fn fill(&mut self) -> Poll<(), Error> {
-        loop {
-            self.rb.reserve(1024);
+        let codec = Codec::new(&self.read_delim, self.max_frame_size());
 
-            let n = try_ready!(self.stream.read_buf(&mut self.rb));
+        crate::fill(&mut self.stream, &mut self.rb, &codec)
+    }
 
-            if n == 0 {
-                return Ok(Async::Ready(()));
-            }
-        }
+    fn poll_write_wb(&mut self) -> Poll<usize, std::io::Error> {
+        self.stream.poll_write(&self.wb)
+    }
+
+    fn shutdown_write(&mut self) -> std::result::Result<(), std::io::Error> {
+        self.stream.shutdown(Shutdown::Write)
     }
 }
 
@@ -91,7 +99,8 @@ impl Origin {
             read_delim: Delim::Dytp,
             write_delim: Delim::Dytp,
             read_timeout: Duration::from_secs(1),
-            read_since: None,
+            read_timer: None,
+            negotiating: false,
         }
     }
 
@@ -103,8 +112,59 @@ impl Origin {
             read_delim: Delim::Dytp,
             write_delim: Delim::Dytp,
             read_timeout: Duration::from_secs(read_timeout),
-            read_since: None,
+            read_timer: None,
+            negotiating: false,
+        }
+    }
+
+    // Takes over a connection with the bytes already read from it.
+    pub fn from_parts(stream: TcpStream, rb: BytesMut, read_timeout: Duration) -> Self {
+        Origin {
+            stream,
+            rb,
+            wb: BytesMut::new(),
+            read_delim: Delim::Dytp,
+            write_delim: Delim::Dytp,
+            read_timeout,
+            read_timer: None,
+            negotiating: false,
+        }
+    }
+
+    // Connections from other components start with HELLO, which is answered before the first payload.
+    pub fn negotiate(&mut self) {
+        self.negotiating = true;
+    }
+
+    // Peers speaking none of the versions are rejected with the versions spoken here.
+    fn answer(&mut self, hello: &[u8]) -> Result<(), Error> {
+        let hello = plain::Negotiation::from(hello);
+        let answer = hello.answer();
+        let rejected = answer.accepted().is_err();
+        let answer: Vec<u8> = answer.into();
+
+        self.try_write(&answer)?;
+        self.poll_flush()?;
+
+        if !rejected {
+            return Ok(());
         }
+
+        match hello {
+            plain::Negotiation::HELLO { min, max } => Err(WireError::IncompatibleVersion {
+                min,
+                max,
+                own_min: dytp_protocol::MIN_VERSION,
+                own_max: dytp_protocol::VERSION,
+            }
+            .into()),
+            _ => Err(WireError::NotNegotiated.into()),
+        }
+    }
+
+    // Anything not written yet is dropped, flush it beforehand.
+    pub fn into_parts(self) -> (TcpStream, BytesMut, Duration) {
+        (self.stream, self.rb, self.read_timeout)
     }
 }
 
@@ -114,17 +174,7 @@ impl Write for Origin {
     }
 
     fn flush(&mut self) -> Result<(), std::io::Error> {
-        while !self.wb.is_empty() {
-            match self.stream.poll_write(&self.wb) {
-                Ok(Async::Ready(n)) => {
-                    let _ = self.wb.split_to(n);
-                }
-                Ok(Async::NotReady) => {
-                    return Err(std::io::ErrorKind::BrokenPipe.into());
-                }
-                Err(e) => return Err(e),
-            }
-        }
+        self.poll_flush()?;
 
         Ok(())
     }
@@ -135,6 +185,18 @@ impl Stream for Origin {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.poll_flush()?;
+
+        if self.negotiating {
+            match try_ready!(self.try_read()) {
+                Some(hello) => {
+                    self.negotiating = false;
+                    self.answer(&hello)?;
+                }
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+
         self.try_read()
     }
 }
@@ -0,0 +1,578 @@
+use crate::error::{RequestError, Result};
+use dytp_protocol::addr::{is_valid_host, Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const VERSION: u8 = 0x05;
+
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SocksAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::str::FromStr for SocksAuth {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut split = s.splitn(2, ':');
+
+        match (split.next(), split.next()) {
+            (Some(username), Some(password)) if !username.is_empty() => Ok(SocksAuth {
+                username: username.to_owned(),
+                password: password.to_owned(),
+            }),
+            _ => Err(RequestError::InvalidSocksAuth.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Succeeded,
+    GeneralFailure,
+    ConnectionNotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+}
+
+impl Into<Vec<u8>> for Reply {
+    fn into(self) -> Vec<u8> {
+        let rep = match self {
+            Reply::Succeeded => 0x00,
+            Reply::GeneralFailure => 0x01,
+            Reply::ConnectionNotAllowed => 0x02,
+            Reply::NetworkUnreachable => 0x03,
+            Reply::HostUnreachable => 0x04,
+            Reply::ConnectionRefused => 0x05,
+            Reply::TtlExpired => 0x06,
+            Reply::CommandNotSupported => 0x07,
+            Reply::AddressTypeNotSupported => 0x08,
+        };
+
+        // The bound address is not meaningful for the client
+        // since the connection is established at the exit node.
+        vec![VERSION, rep, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Greeting,
+    Auth,
+    Request,
+    Done,
+}
+
+//
+// Server side state machine of SOCKS5 (RFC1928, RFC1929).
+// `feed` consumes bytes received from the client and
+// returns bytes to be written back to the client (may be empty).
+//
+#[derive(Debug)]
+pub struct Socks {
+    phase: Phase,
+    buf: Vec<u8>,
+    auth: Option<SocksAuth>,
+    addr: Option<Addr>,
+}
+
+impl Socks {
+    pub fn new(auth: Option<SocksAuth>) -> Socks {
+        Socks {
+            phase: Phase::Greeting,
+            buf: Vec::new(),
+            auth,
+            addr: None,
+        }
+    }
+
+    pub fn addr(&self) -> Option<Addr> {
+        self.addr.clone()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.phase == Phase::Done
+    }
+
+    pub fn feed(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        self.buf.extend_from_slice(payload);
+
+        let mut replies = Vec::new();
+
+        loop {
+            let phase = self.phase;
+            let reply = match phase {
+                Phase::Greeting => self.greeting()?,
+                Phase::Auth => self.authenticate()?,
+                Phase::Request => self.request()?,
+                Phase::Done => None,
+            };
+
+            if let Some(reply) = reply {
+                replies.extend_from_slice(&reply);
+            }
+
+            if self.phase == phase || self.phase == Phase::Done {
+                break;
+            }
+        }
+
+        Ok(replies)
+    }
+
+    fn greeting(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+
+        if self.buf[0] != VERSION {
+            return Err(RequestError::InvalidSocksRequest.into());
+        }
+
+        let nmethods = self.buf[1] as usize;
+
+        if self.buf.len() < 2 + nmethods {
+            return Ok(None);
+        }
+
+        let methods: Vec<u8> = self.buf.drain(0..2 + nmethods).skip(2).collect();
+
+        let method = if self.auth.is_some() {
+            METHOD_USERNAME_PASSWORD
+        } else {
+            METHOD_NO_AUTH
+        };
+
+        if !methods.contains(&method) {
+            log::warn!("socks client doesn't support the method={}", method);
+
+            self.phase = Phase::Done;
+
+            return Ok(Some(vec![VERSION, METHOD_NO_ACCEPTABLE]));
+        }
+
+        if method == METHOD_USERNAME_PASSWORD {
+            self.phase = Phase::Auth;
+        } else {
+            self.phase = Phase::Request;
+        }
+
+        Ok(Some(vec![VERSION, method]))
+    }
+
+    fn authenticate(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+
+        if self.buf[0] != AUTH_VERSION {
+            return Err(RequestError::InvalidSocksRequest.into());
+        }
+
+        let ulen = self.buf[1] as usize;
+
+        if self.buf.len() < 2 + ulen + 1 {
+            return Ok(None);
+        }
+
+        let plen = self.buf[2 + ulen] as usize;
+
+        if self.buf.len() < 2 + ulen + 1 + plen {
+            return Ok(None);
+        }
+
+        let auth: Vec<u8> = self.buf.drain(0..2 + ulen + 1 + plen).collect();
+        let username = &auth[2..2 + ulen];
+        let password = &auth[2 + ulen + 1..];
+
+        let accepted = self
+            .auth
+            .as_ref()
+            .map(|a| {
+                constant_time_eq(a.username.as_bytes(), username)
+                    & constant_time_eq(a.password.as_bytes(), password)
+            })
+            .unwrap_or(false);
+
+        if !accepted {
+            log::warn!("socks authentication failed");
+
+            self.phase = Phase::Done;
+
+            return Ok(Some(vec![AUTH_VERSION, 0x01]));
+        }
+
+        self.phase = Phase::Request;
+
+        Ok(Some(vec![AUTH_VERSION, 0x00]))
+    }
+
+    fn request(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.buf.len() < 5 {
+            return Ok(None);
+        }
+
+        if self.buf[0] != VERSION {
+            return Err(RequestError::InvalidSocksRequest.into());
+        }
+
+        if self.buf[1] != CMD_CONNECT {
+            log::warn!("unsupported socks command={}", self.buf[1]);
+
+            self.phase = Phase::Done;
+
+            return Ok(Some(Reply::CommandNotSupported.into()));
+        }
+
+        let (len, host) = match self.buf[3] {
+            ATYP_IPV4 => (4 + 4, None),
+            ATYP_IPV6 => (4 + 16, None),
+            ATYP_DOMAIN => {
+                let dlen = self.buf[4] as usize;
+
+                (4 + 1 + dlen, Some(5..5 + dlen))
+            }
+            atyp => {
+                log::warn!("unsupported socks address type={}", atyp);
+
+                self.phase = Phase::Done;
+
+                return Ok(Some(Reply::AddressTypeNotSupported.into()));
+            }
+        };
+
+        if self.buf.len() < len + 2 {
+            return Ok(None);
+        }
+
+        let req: Vec<u8> = self.buf.drain(0..len + 2).collect();
+        let port = ((req[len] as u16) << 8) | req[len + 1] as u16;
+
+        // Domain names are resolved at the exit node.
+        let addr = match host {
+            Some(range) => match std::str::from_utf8(&req[range]) {
+                Ok(host) if is_valid_host(host) => Addr::Host {
+                    host: host.to_owned(),
+                    port,
+                },
+                _ => {
+                    log::warn!("invalid socks domain name");
+
+                    self.phase = Phase::Done;
+
+                    return Ok(Some(Reply::GeneralFailure.into()));
+                }
+            },
+            None if req[3] == ATYP_IPV4 => Addr::Socket(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(req[4], req[5], req[6], req[7])),
+                port,
+            )),
+            None => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&req[4..20]);
+
+                Addr::Socket(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+            }
+        };
+
+        self.addr = Some(addr);
+        self.phase = Phase::Done;
+
+        Ok(None)
+    }
+}
+
+// Takes the same time wherever the bytes differ, so the password can't be guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONNECT_HOST: &[u8] = &[
+        VERSION,
+        CMD_CONNECT,
+        0x00,
+        ATYP_DOMAIN,
+        11,
+        b'e',
+        b'x',
+        b'a',
+        b'm',
+        b'p',
+        b'l',
+        b'e',
+        b'.',
+        b'c',
+        b'o',
+        b'm',
+        0x01,
+        0xbb,
+    ];
+
+    fn auth() -> Option<SocksAuth> {
+        Some("user:pass".parse().unwrap())
+    }
+
+    fn reply(reply: Reply) -> Vec<u8> {
+        reply.into()
+    }
+
+    #[test]
+    fn connects_to_host() {
+        let mut socks = Socks::new(None);
+
+        assert_eq!(
+            socks.feed(&[VERSION, 1, METHOD_NO_AUTH]).unwrap(),
+            vec![VERSION, METHOD_NO_AUTH]
+        );
+        assert_eq!(socks.feed(CONNECT_HOST).unwrap(), Vec::<u8>::new());
+        assert!(socks.is_done());
+        assert_eq!(
+            socks.addr(),
+            Some(Addr::Host {
+                host: "example.com".to_owned(),
+                port: 443
+            })
+        );
+    }
+
+    #[test]
+    fn connects_to_ip() {
+        let mut socks = Socks::new(None);
+
+        socks.feed(&[VERSION, 1, METHOD_NO_AUTH]).unwrap();
+        socks
+            .feed(&[VERSION, CMD_CONNECT, 0x00, ATYP_IPV4, 10, 0, 0, 1, 0, 80])
+            .unwrap();
+
+        assert_eq!(
+            socks.addr(),
+            Some(Addr::Socket("10.0.0.1:80".parse().unwrap()))
+        );
+
+        let mut socks = Socks::new(None);
+        let mut req = vec![
+            VERSION,
+            1,
+            METHOD_NO_AUTH,
+            VERSION,
+            CMD_CONNECT,
+            0x00,
+            ATYP_IPV6,
+        ];
+
+        req.extend_from_slice(&"::1".parse::<Ipv6Addr>().unwrap().octets());
+        req.extend_from_slice(&[0, 80]);
+        socks.feed(&req).unwrap();
+
+        assert_eq!(
+            socks.addr(),
+            Some(Addr::Socket("[::1]:80".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_the_request() {
+        let mut socks = Socks::new(auth());
+        let req = [
+            &[VERSION, 2, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD][..],
+            &[
+                AUTH_VERSION,
+                4,
+                b'u',
+                b's',
+                b'e',
+                b'r',
+                4,
+                b'p',
+                b'a',
+                b's',
+                b's',
+            ],
+            CONNECT_HOST,
+        ]
+        .concat();
+        let mut replies = Vec::new();
+
+        for b in req.iter() {
+            assert!(!socks.is_done());
+
+            replies.extend(socks.feed(&[*b]).unwrap());
+        }
+
+        assert!(socks.is_done());
+        assert_eq!(
+            replies,
+            vec![VERSION, METHOD_USERNAME_PASSWORD, AUTH_VERSION, 0x00]
+        );
+        assert!(socks.addr().is_some());
+    }
+
+    #[test]
+    fn refuses_other_version() {
+        assert!(Socks::new(None).feed(&[0x04, 1, METHOD_NO_AUTH]).is_err());
+
+        let mut socks = Socks::new(None);
+
+        socks.feed(&[VERSION, 1, METHOD_NO_AUTH]).unwrap();
+
+        assert!(socks
+            .feed(&[0x04, CMD_CONNECT, 0x00, ATYP_IPV4, 10, 0, 0, 1, 0, 80])
+            .is_err());
+    }
+
+    #[test]
+    fn refuses_client_without_the_method() {
+        let mut socks = Socks::new(auth());
+
+        assert_eq!(
+            socks.feed(&[VERSION, 1, METHOD_NO_AUTH]).unwrap(),
+            vec![VERSION, METHOD_NO_ACCEPTABLE]
+        );
+        assert!(socks.is_done());
+        assert!(socks.addr().is_none());
+
+        let mut socks = Socks::new(None);
+
+        assert_eq!(
+            socks.feed(&[VERSION, 0]).unwrap(),
+            vec![VERSION, METHOD_NO_ACCEPTABLE]
+        );
+    }
+
+    #[test]
+    fn refuses_wrong_password() {
+        let mut socks = Socks::new(auth());
+
+        socks.feed(&[VERSION, 1, METHOD_USERNAME_PASSWORD]).unwrap();
+
+        let replies = socks
+            .feed(&[
+                AUTH_VERSION,
+                4,
+                b'u',
+                b's',
+                b'e',
+                b'r',
+                4,
+                b'p',
+                b'a',
+                b's',
+                b'x',
+            ])
+            .unwrap();
+
+        assert_eq!(replies, vec![AUTH_VERSION, 0x01]);
+        assert!(socks.is_done());
+
+        // Nothing after the failure is taken as the request.
+        assert!(socks.feed(CONNECT_HOST).unwrap().is_empty());
+        assert!(socks.addr().is_none());
+    }
+
+    #[test]
+    fn refuses_other_auth_version() {
+        let mut socks = Socks::new(auth());
+
+        socks.feed(&[VERSION, 1, METHOD_USERNAME_PASSWORD]).unwrap();
+
+        assert!(socks.feed(&[0x02, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn refuses_unsupported_command() {
+        let mut socks = Socks::new(None);
+
+        socks.feed(&[VERSION, 1, METHOD_NO_AUTH]).unwrap();
+
+        // BIND
+        let replies = socks
+            .feed(&[VERSION, 0x02, 0x00, ATYP_IPV4, 10, 0, 0, 1, 0, 80])
+            .unwrap();
+
+        assert_eq!(replies, reply(Reply::CommandNotSupported));
+        assert!(socks.is_done());
+        assert!(socks.addr().is_none());
+    }
+
+    #[test]
+    fn refuses_unknown_address_type() {
+        let mut socks = Socks::new(None);
+
+        socks.feed(&[VERSION, 1, METHOD_NO_AUTH]).unwrap();
+
+        let replies = socks.feed(&[VERSION, CMD_CONNECT, 0x00, 0x05, 0]).unwrap();
+
+        assert_eq!(replies, reply(Reply::AddressTypeNotSupported));
+        assert!(socks.addr().is_none());
+    }
+
+    #[test]
+    fn refuses_invalid_domain_names() {
+        let names: Vec<&[u8]> = vec![b"", b"exa mple.com", b"example.com\r\n", &[0xff, 0xfe]];
+
+        for name in names {
+            let mut socks = Socks::new(None);
+            let req = [
+                &[
+                    VERSION,
+                    1,
+                    METHOD_NO_AUTH,
+                    VERSION,
+                    CMD_CONNECT,
+                    0x00,
+                    ATYP_DOMAIN,
+                ][..],
+                &[name.len() as u8],
+                name,
+                &[0, 80],
+            ]
+            .concat();
+
+            let replies = socks.feed(&req).unwrap();
+
+            assert_eq!(
+                replies,
+                [
+                    &[VERSION, METHOD_NO_AUTH][..],
+                    &reply(Reply::GeneralFailure)
+                ]
+                .concat()
+            );
+            assert!(socks.is_done());
+            assert!(socks.addr().is_none());
+        }
+    }
+
+    #[test]
+    fn reads_credentials() {
+        assert_eq!(
+            "user:pa:ss".parse::<SocksAuth>().unwrap(),
+            SocksAuth {
+                username: "user".to_owned(),
+                password: "pa:ss".to_owned()
+            }
+        );
+        assert!("user".parse::<SocksAuth>().is_err());
+        assert!(":pass".parse::<SocksAuth>().is_err());
+    }
+}
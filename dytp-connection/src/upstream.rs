@@ -1,12 +1,17 @@
+use crate::connect::Connect;
 use crate::Connection;
 use bytes::BytesMut;
+use dytp_protocol::codec::Codec;
 use dytp_protocol::delim::Delim;
+use dytp_protocol::method::plain;
 use failure::Error;
 use futures::try_ready;
 use std::io::Write;
-use std::net::{TcpStream, ToSocketAddrs};
-use std::time::{Duration, Instant};
+use std::net::{Shutdown, SocketAddr};
+use std::time::Duration;
+use tokio::net::TcpStream;
 use tokio::prelude::*;
+use tokio::timer::Delay;
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -18,44 +23,8 @@ pub struct Upstream {
     read_delim: Delim,
     write_delim: Delim,
     read_timeout: Duration,
-    read_since: Option<Instant>,
-    pub parse_http: bool,
-}
-
-impl Upstream {
-    fn parse_http(&mut self) -> Poll<(), Error> {
-        let mut headers = [httparse::EMPTY_HEADER; 32];
-        let mut req = httparse::Response::new(&mut headers);
-
-        match req.parse(&self.rb)? {
-            httparse::Status::Complete(body_start) => {
-                for header in headers.iter() {
-                    if header.name == "Content-Length" {
-                        let len: usize = std::str::from_utf8(header.value)?.parse()?;
-
-                        if self.rb.len() - body_start == len {
-                            if !self.rb.ends_with(b"\r\n") {
-                                // It doesn't end with http delimiter.
-                                // Change the mode from Delim::Http to Delim::None
-                                self.read_delim = Delim::None;
-                            }
-
-                            return Ok(Async::Ready(()));
-                        } else {
-                            return Ok(Async::NotReady);
-                        }
-                    }
-                }
-            }
-            httparse::Status::Partial => {
-                return Ok(Async::NotReady);
-            }
-        }
-
-        log::debug!("content-length header not found");
-
-        Ok(Async::Ready(()))
-    }
+    read_timer: Option<Delay>,
+    negotiating: bool, // Waiting for the answer to HELLO
 }
 
 impl Connection for Upstream {
@@ -91,12 +60,12 @@ impl Connection for Upstream {
         &mut self.read_timeout
     }
 
-    fn read_since(&self) -> &Option<Instant> {
-        &self.read_since
+    fn read_timer(&self) -> &Option<Delay> {
+        &self.read_timer
     }
 
-    fn read_since_mut(&mut self) -> &mut Option<Instant> {
-        &mut self.read_since
+    fn read_timer_mut(&mut self) -> &mut Option<Delay> {
+        &mut self.read_timer
     }
 
     fn write_delim(&self) -> &Delim {
@@ -108,25 +77,17 @@ impl Connection for Upstream {
     }
 
     fn fill(&mut self) -> Poll<(), Error> {
-        loop {
-            let mut b = [0; 1024];
-
-            match self.stream.read(&mut b) {
-                Ok(n) => {
-                    if n > 0 {
-                        self.rb.extend_from_slice(&b[0..n]);
-                    } else {
-                        return Ok(Async::Ready(()));
-                    }
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    break;
-                }
-                Err(e) => return Err(e.into()),
-            }
-        }
+        let codec = Codec::new(&self.read_delim, self.max_frame_size());
 
-        Ok(Async::NotReady)
+        crate::fill(&mut self.stream, &mut self.rb, &codec)
+    }
+
+    fn poll_write_wb(&mut self) -> Poll<usize, std::io::Error> {
+        self.stream.poll_write(&self.wb)
+    }
+
+    fn shutdown_write(&mut self) -> std::result::Result<(), std::io::Error> {
+        self.stream.shutdown(Shutdown::Write)
     }
 }
 
@@ -136,53 +97,44 @@ impl Write for Upstream {
     }
 
     fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
-        while !self.wb.is_empty() {
-            let n = self.stream.write(&self.wb)?;
-
-            self.wb.split_to(n);
-        }
+        self.poll_flush()?;
 
         Ok(())
     }
 }
 
 impl Upstream {
-    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Upstream> {
-        let stream = TcpStream::connect(addr)?;
-        let _ = stream.set_nodelay(true);
-        let _ = stream.set_nonblocking(true);
-        let _ = stream.set_write_timeout(Some(Duration::from_secs(30)));
-        let _ = stream.set_read_timeout(Some(Duration::from_secs(30)));
-
-        Ok(Upstream {
-            stream,
-            rb: BytesMut::new(),
-            wb: BytesMut::new(),
-            read_delim: Delim::Dytp,
-            write_delim: Delim::Dytp,
-            read_timeout: Duration::from_secs(1),
-            read_since: None,
-            parse_http: false,
-        })
+    // Resolved once connected, with the read timeout of 1 sec.
+    pub fn new(addr: SocketAddr) -> Connect {
+        Upstream::new_with_timeout(addr, 1)
     }
 
-    pub fn new_with_timeout<A: ToSocketAddrs>(addr: A, read_timeout: u64) -> Result<Upstream> {
-        let stream = TcpStream::connect(addr)?;
-        let _ = stream.set_nodelay(true);
-        let _ = stream.set_nonblocking(true);
-        let _ = stream.set_write_timeout(Some(Duration::from_secs(30)));
-        let _ = stream.set_read_timeout(Some(Duration::from_secs(read_timeout)));
+    pub fn new_with_timeout(addr: SocketAddr, read_timeout: u64) -> Connect {
+        Connect::new(vec![addr], read_timeout)
+    }
 
-        Ok(Upstream {
+    pub fn from_stream(stream: TcpStream, read_timeout: u64) -> Upstream {
+        Upstream {
             stream,
             rb: BytesMut::new(),
             wb: BytesMut::new(),
             read_delim: Delim::Dytp,
             write_delim: Delim::Dytp,
             read_timeout: Duration::from_secs(read_timeout),
-            read_since: None,
-            parse_http: false,
-        })
+            read_timer: None,
+            negotiating: false,
+        }
+    }
+
+    // Offers the protocol versions before anything else is written to another component.
+    // The answer is taken by `poll` before the first payload, no round trip is waited for.
+    pub fn negotiate(&mut self) -> Result<()> {
+        let hello: Vec<u8> = plain::Negotiation::hello().into();
+
+        self.try_write(&hello)?;
+        self.negotiating = true;
+
+        Ok(())
     }
 }
 
@@ -191,40 +143,19 @@ impl Future for Upstream {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if self.read_since().is_none() {
-            *self.read_since_mut() = Some(Instant::now());
-        }
+        self.poll_flush()?;
 
-        let disconnected = self.fill()?.is_ready();
+        if self.negotiating {
+            match try_ready!(self.try_read()) {
+                Some(answer) => {
+                    plain::Negotiation::decode(&answer)?.accepted()?;
 
-        if !self.rb.is_empty() && self.parse_http {
-            try_ready!(self.parse_http());
-
-            self.parse_http = false;
-        }
-
-        if !self.rb.is_empty() {
-            if let Some(payload) = self.try_read_delim() {
-                *self.read_since_mut() = None;
-
-                return Ok(Async::Ready(Some(payload)));
+                    self.negotiating = false;
+                }
+                None => return Ok(Async::Ready(None)),
             }
         }
 
-        if disconnected {
-            Ok(Async::Ready(None))
-        } else {
-            if Instant::now().duration_since(*self.read_since().as_ref().unwrap())
-                > *self.read_timeout()
-            {
-                log::debug!("read timeout");
-
-                return Ok(Async::Ready(None));
-            }
-
-            task::current().notify();
-
-            Ok(Async::NotReady)
-        }
+        self.try_read()
     }
 }
@@ -1,26 +1,20 @@
-use crate::error::Result;
+use crate::query::Query;
 use dytp_component::node::Node;
-use dytp_connection::prelude::*;
 use dytp_protocol::method::plain;
 use failure::Error;
 use futures::prelude::*;
 use std::net::SocketAddr;
-use tokio::prelude::*;
 
 #[derive(Debug)]
 pub struct FetchNodes {
-    upstream: Upstream,
+    query: Query,
 }
 
 impl FetchNodes {
-    pub fn new(cloud_addr: SocketAddr) -> Result<FetchNodes> {
-        let mut upstream = Upstream::new(cloud_addr)?;
-        let buf: Vec<u8> = plain::ToCloud::FETCH.into();
-
-        upstream.write(&buf)?;
-        upstream.flush()?;
-
-        Ok(FetchNodes { upstream })
+    pub fn new(cloud_addr: SocketAddr) -> FetchNodes {
+        FetchNodes {
+            query: Query::new(cloud_addr, plain::ToCloud::FETCH.encode()),
+        }
     }
 }
 
@@ -29,37 +23,17 @@ impl Future for FetchNodes {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match self.upstream.poll() {
-            Ok(Async::Ready(Some(payload))) => {
-                let ts_nodes: Vec<String> = std::str::from_utf8(&payload)
-                    .unwrap()
-                    .split(" ")
-                    .map(|s| s.to_owned())
-                    .collect();
-
-                let ts: i64 = ts_nodes[0].parse().unwrap();
-
-                if ts_nodes.len() == 1 || ts_nodes.len() % 2 != 1 {
-                    return Ok(Async::Ready(Some((ts, Vec::new()))));
+        match self.query.poll() {
+            Ok(Async::Ready(Some(payload))) => match plain::FromCloud::decode(&payload) {
+                Ok(plain::FromCloud::NODES { ts, nodes }) => {
+                    return Ok(Async::Ready(Some((ts, nodes))));
                 }
+                answer => {
+                    log::warn!("invalid node list={:?}", answer);
 
-                let addr_versions: Vec<String> =
-                    ts_nodes[1..].iter().map(|n| n.to_owned()).collect();
-                let mut nodes = Vec::new();
-
-                for idx in 0..addr_versions.len() / 2 {
-                    let addr = addr_versions[idx * 2].parse();
-                    let version = addr_versions[idx * 2 + 1].parse();
-
-                    if addr.is_err() || version.is_err() {
-                        return Ok(Async::Ready(Some((ts, nodes))));
-                    }
-
-                    nodes.push(Node::new(&addr.unwrap(), &version.unwrap()));
+                    return Ok(Async::Ready(None));
                 }
-
-                return Ok(Async::Ready(Some((ts, nodes))));
-            }
+            },
             Ok(Async::Ready(None)) => {
                 log::warn!("failed to get node list.");
                 log::warn!("this may require you to change cloud endpoint if this happens again.");
@@ -67,8 +41,6 @@ impl Future for FetchNodes {
                 return Ok(Async::Ready(None));
             }
             Ok(Async::NotReady) => {
-                task::current().notify();
-
                 return Ok(Async::NotReady);
             }
             Err(e) => {
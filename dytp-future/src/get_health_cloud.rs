@@ -1,30 +1,22 @@
-use crate::error::Result;
+use crate::query::Query;
 use dytp_component::health_resp_cloud::HealthRespCloud;
-use dytp_connection::prelude::*;
 use dytp_protocol::method::plain;
 use failure::Error;
 use futures::prelude::*;
 use std::net::SocketAddr;
-use tokio::prelude::*;
 
 #[derive(Debug)]
 pub struct GetHealthCloud {
     pub addr: SocketAddr,
-    upstream: Upstream,
+    query: Query,
 }
 
 impl GetHealthCloud {
-    pub fn new(cloud_addr: SocketAddr) -> Result<GetHealthCloud> {
-        let mut upstream = Upstream::new(cloud_addr.clone())?;
-        let buf: Vec<u8> = plain::Common::HEALTH.into();
-
-        upstream.write(&buf)?;
-        upstream.flush()?;
-
-        Ok(GetHealthCloud {
+    pub fn new(cloud_addr: SocketAddr) -> GetHealthCloud {
+        GetHealthCloud {
             addr: cloud_addr,
-            upstream,
-        })
+            query: Query::new(cloud_addr, Ok(plain::Common::HEALTH.into())),
+        }
     }
 }
 
@@ -33,20 +25,23 @@ impl Future for GetHealthCloud {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match self.upstream.poll() {
-            Ok(Async::Ready(Some(payload))) => {
-                let health = HealthRespCloud::from(&payload as &[u8]);
-
-                return Ok(Async::Ready(Some(health)));
-            }
+        match self.query.poll() {
+            Ok(Async::Ready(Some(payload))) => match plain::Health::decode(&payload) {
+                Ok(plain::Health::CLOUD(health)) => {
+                    return Ok(Async::Ready(Some(health)));
+                }
+                answer => {
+                    log::warn!("invalid cloud health on {}={:?}", self.addr, answer);
+
+                    return Ok(Async::Ready(None));
+                }
+            },
             Ok(Async::Ready(None)) => {
                 log::warn!("failed to get cloud health on {}", self.addr);
 
                 return Ok(Async::Ready(None));
             }
             Ok(Async::NotReady) => {
-                task::current().notify();
-
                 return Ok(Async::NotReady);
             }
             Err(e) => {
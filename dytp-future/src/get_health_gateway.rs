@@ -1,33 +1,22 @@
-use crate::error::Result;
+use crate::query::Query;
 use dytp_component::health_resp_gateway::HealthRespGateway;
-use dytp_connection::prelude::*;
-use dytp_protocol::delim::Delim;
 use dytp_protocol::method::plain;
 use failure::Error;
 use futures::prelude::*;
 use std::net::SocketAddr;
-use tokio::prelude::*;
 
 #[derive(Debug)]
 pub struct GetHealthGateway {
     pub addr: SocketAddr,
-    upstream: Upstream,
+    query: Query,
 }
 
 impl GetHealthGateway {
-    pub fn new(gateway_addr: SocketAddr) -> Result<GetHealthGateway> {
-        let mut upstream = Upstream::new(gateway_addr.clone())?;
-        let buf: Vec<u8> = plain::Common::HEALTH.into();
-
-        upstream.set_write_delim(Delim::Http);
-        upstream.set_read_delim(Delim::Http);
-        upstream.write(&buf)?;
-        upstream.flush()?;
-
-        Ok(GetHealthGateway {
+    pub fn new(gateway_addr: SocketAddr) -> GetHealthGateway {
+        GetHealthGateway {
             addr: gateway_addr,
-            upstream,
-        })
+            query: Query::http(gateway_addr, Ok(plain::Common::HEALTH.into())),
+        }
     }
 }
 
@@ -36,20 +25,23 @@ impl Future for GetHealthGateway {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match self.upstream.poll() {
-            Ok(Async::Ready(Some(payload))) => {
-                let health = HealthRespGateway::from(&payload as &[u8]);
-
-                return Ok(Async::Ready(Some(health)));
-            }
+        match self.query.poll() {
+            Ok(Async::Ready(Some(payload))) => match plain::Health::decode(&payload) {
+                Ok(plain::Health::GATEWAY(health)) => {
+                    return Ok(Async::Ready(Some(health)));
+                }
+                answer => {
+                    log::warn!("invalid gateway health on {}={:?}", self.addr, answer);
+
+                    return Ok(Async::Ready(None));
+                }
+            },
             Ok(Async::Ready(None)) => {
                 log::warn!("failed to get gateway health on {}", self.addr);
 
                 return Ok(Async::Ready(None));
             }
             Ok(Async::NotReady) => {
-                task::current().notify();
-
                 return Ok(Async::NotReady);
             }
             Err(e) => {
@@ -1,30 +1,23 @@
-use crate::error::Result;
+use crate::query::Query;
 use dytp_component::health_resp_node::HealthRespNode;
-use dytp_connection::prelude::*;
 use dytp_protocol::method::plain;
 use failure::Error;
 use futures::prelude::*;
+use futures::try_ready;
 use std::net::SocketAddr;
-use tokio::prelude::*;
 
 #[derive(Debug)]
 pub struct GetHealthNode {
     pub addr: SocketAddr,
-    upstream: Upstream,
+    query: Query,
 }
 
 impl GetHealthNode {
-    pub fn new(node_addr: SocketAddr) -> Result<GetHealthNode> {
-        let mut upstream = Upstream::new(node_addr.clone())?;
-        let buf: Vec<u8> = plain::Common::HEALTH.into();
-
-        upstream.write(&buf)?;
-        upstream.flush()?;
-
-        Ok(GetHealthNode {
+    pub fn new(node_addr: SocketAddr) -> GetHealthNode {
+        GetHealthNode {
             addr: node_addr,
-            upstream,
-        })
+            query: Query::new(node_addr, Ok(plain::Common::HEALTH.into())),
+        }
     }
 }
 
@@ -33,20 +26,26 @@ impl Future for GetHealthNode {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match self.upstream.poll() {
-            Ok(Async::Ready(Some(payload))) => {
-                let health = HealthRespNode::from(&payload as &[u8]);
-
-                return Ok(Async::Ready(Some(health)));
-            }
+        // The cloud takes a node it can't connect to as down.
+        try_ready!(self.query.poll_connect());
+
+        match self.query.poll() {
+            Ok(Async::Ready(Some(payload))) => match plain::Health::decode(&payload) {
+                Ok(plain::Health::NODE(health)) => {
+                    return Ok(Async::Ready(Some(health)));
+                }
+                answer => {
+                    log::warn!("invalid node health on {}={:?}", self.addr, answer);
+
+                    return Ok(Async::Ready(None));
+                }
+            },
             Ok(Async::Ready(None)) => {
                 log::warn!("failed to get node health on {}", self.addr);
 
                 return Ok(Async::Ready(None));
             }
             Ok(Async::NotReady) => {
-                task::current().notify();
-
                 return Ok(Async::NotReady);
             }
             Err(e) => {
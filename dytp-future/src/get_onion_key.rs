@@ -0,0 +1,43 @@
+use crate::query::Query;
+use dytp_protocol::crypto::SignedOnionKey;
+use dytp_protocol::method::plain;
+use failure::Error;
+use futures::prelude::*;
+use std::net::SocketAddr;
+
+#[derive(Debug)]
+pub struct GetOnionKey {
+    query: Query,
+}
+
+impl GetOnionKey {
+    pub fn new(addr: SocketAddr) -> GetOnionKey {
+        GetOnionKey {
+            query: Query::new(addr, plain::ToNode::ONION_KEY.encode()),
+        }
+    }
+}
+
+impl Future for GetOnionKey {
+    type Item = Option<SignedOnionKey>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.query.poll() {
+            Ok(Async::Ready(Some(payload))) => {
+                return Ok(Async::Ready(SignedOnionKey::decode(&payload).ok()));
+            }
+            Ok(Async::Ready(None)) => {
+                return Ok(Async::Ready(None));
+            }
+            Ok(Async::NotReady) => {
+                return Ok(Async::NotReady);
+            }
+            Err(e) => {
+                log::warn!("failed to get onion key due to {:?}", e);
+
+                return Ok(Async::Ready(None));
+            }
+        }
+    }
+}
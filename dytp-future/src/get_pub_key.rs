@@ -1,26 +1,21 @@
-use crate::error::Result;
-use dytp_connection::prelude::*;
+use crate::query::Query;
 use dytp_protocol::method::plain;
 use failure::Error;
 use futures::prelude::*;
 use openssl::pkey::Public;
 use openssl::rsa::Rsa;
 use std::net::SocketAddr;
-use tokio::prelude::*;
 
 #[derive(Debug)]
 pub struct GetPubKey {
-    upstream: Upstream,
+    query: Query,
 }
 
 impl GetPubKey {
-    pub fn new(addr: SocketAddr) -> Result<GetPubKey> {
-        let mut upstream = Upstream::new(addr)?;
-        let buf: Vec<u8> = plain::ToNode::PUB_KEY.into();
-        upstream.write(&buf)?;
-        upstream.flush()?;
-
-        Ok(GetPubKey { upstream })
+    pub fn new(addr: SocketAddr) -> GetPubKey {
+        GetPubKey {
+            query: Query::new(addr, plain::ToNode::PUB_KEY.encode()),
+        }
     }
 }
 
@@ -29,7 +24,7 @@ impl Future for GetPubKey {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match self.upstream.poll() {
+        match self.query.poll() {
             Ok(Async::Ready(Some(payload))) => {
                 return Ok(Async::Ready(
                     Rsa::<Public>::public_key_from_der(&payload).ok(),
@@ -39,8 +34,6 @@ impl Future for GetPubKey {
                 return Ok(Async::Ready(None));
             }
             Ok(Async::NotReady) => {
-                task::current().notify();
-
                 return Ok(Async::NotReady);
             }
             Err(e) => {
@@ -3,5 +3,8 @@ pub mod fetch_nodes;
 pub mod get_health_cloud;
 pub mod get_health_gateway;
 pub mod get_health_node;
+pub mod get_onion_key;
 pub mod get_pub_key;
+pub mod lock;
+pub mod query;
 pub mod sync_audit;
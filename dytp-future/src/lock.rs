@@ -0,0 +1,75 @@
+use failure::Error;
+use futures::prelude::*;
+use futures::try_ready;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio_sync::semaphore::{Permit, Semaphore};
+
+//
+// A lock for the states shared between futures.
+// A task waiting for the lock is parked and woken up when the holder releases it.
+//
+pub struct Lock<T> {
+    semaphore: Arc<Semaphore>,
+    data: Arc<Mutex<T>>,
+}
+
+impl<T> Lock<T> {
+    pub fn new(data: T) -> Lock<T> {
+        Lock {
+            semaphore: Arc::new(Semaphore::new(1)),
+            data: Arc::new(Mutex::new(data)),
+        }
+    }
+
+    pub fn acquire(&self) -> Acquire<T> {
+        Acquire {
+            lock: self.clone(),
+            permit: Permit::new(),
+        }
+    }
+}
+
+impl<T> Clone for Lock<T> {
+    fn clone(&self) -> Lock<T> {
+        Lock {
+            semaphore: self.semaphore.clone(),
+            data: self.data.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Lock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lock")
+            .field("semaphore", &self.semaphore)
+            .finish()
+    }
+}
+
+//
+// The lock is held from the first successful `poll_lock` until `Acquire` is dropped.
+//
+#[derive(Debug)]
+pub struct Acquire<T> {
+    lock: Lock<T>,
+    permit: Permit,
+}
+
+impl<T> Acquire<T> {
+    pub fn poll_lock(&mut self) -> Poll<MutexGuard<'_, T>, Error> {
+        try_ready!(self.permit.poll_acquire(&self.lock.semaphore));
+
+        // never blocks since only the permit holder touches the data
+        match self.lock.data.lock() {
+            Ok(data) => Ok(Async::Ready(data)),
+            Err(poisoned) => Ok(Async::Ready(poisoned.into_inner())),
+        }
+    }
+}
+
+impl<T> Drop for Acquire<T> {
+    fn drop(&mut self) {
+        self.permit.release(&self.lock.semaphore);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::error::Result;
+use bytes::BytesMut;
+use dytp_connection::prelude::*;
+use dytp_protocol::delim::Delim;
+use failure::Error;
+use futures::prelude::*;
+use futures::try_ready;
+use std::net::SocketAddr;
+
+//
+// Connects to another component and sends the request once connected.
+// Resolves to the answers as `Upstream` does after that.
+//
+#[derive(Debug)]
+pub struct Query {
+    connect: Connect,
+    upstream: Option<Upstream>,
+    request: Vec<u8>,
+    error: Option<Error>, // The request couldn't be encoded
+    http: bool,           // Gateways take the request over HTTP without the negotiation
+}
+
+impl Query {
+    // A request which couldn't be encoded fails the same as the connection.
+    pub fn new(addr: SocketAddr, request: Result<Vec<u8>>) -> Query {
+        let (request, error) = match request {
+            Ok(request) => (request, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+
+        Query {
+            connect: Upstream::new(addr),
+            upstream: None,
+            request,
+            error,
+            http: false,
+        }
+    }
+
+    pub fn http(addr: SocketAddr, request: Result<Vec<u8>>) -> Query {
+        Query {
+            http: true,
+            ..Query::new(addr, request)
+        }
+    }
+
+    // Errors here are only of the connection, not of the answers.
+    pub fn poll_connect(&mut self) -> Poll<(), Error> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        if self.upstream.is_none() {
+            let mut upstream = try_ready!(self.connect.poll());
+
+            // The answer is framed as the other methods since it's binary.
+            if self.http {
+                upstream.set_write_delim(Delim::Http);
+                upstream.set_read_delim(Delim::Dytp);
+            } else {
+                upstream.negotiate()?;
+            }
+
+            upstream.write_all(&self.request)?;
+            self.upstream = Some(upstream);
+        }
+
+        Ok(Async::Ready(()))
+    }
+
+    // Only after the first answer, when the connection is surely established.
+    pub fn write(&mut self, buf: &[u8]) -> Result<()> {
+        if let Some(upstream) = self.upstream.as_mut() {
+            upstream.write_all(buf)?;
+            upstream.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Future for Query {
+    type Item = Option<BytesMut>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        try_ready!(self.poll_connect());
+
+        self.upstream.as_mut().unwrap().poll()
+    }
+}
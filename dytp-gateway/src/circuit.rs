@@ -0,0 +1,687 @@
+use crate::error::Result;
+use crate::failures::RecordFailure;
+use crate::guard::GuardConfig;
+use crate::route_node::RouteNode;
+use crate::selection::{exits_to, Diversity};
+use dytp_component::exit_policy::ExitPolicy;
+use dytp_connection::prelude::*;
+use dytp_future::lock::{Acquire, Lock};
+use dytp_protocol::addr::Addr;
+use dytp_protocol::cover::Cover;
+use dytp_protocol::delim::Delim;
+use dytp_protocol::link::{Frame, Link};
+use dytp_protocol::method::encrypted::{self, Cell, Padding, Schedule};
+use failure::Error;
+use futures::prelude::*;
+use futures::sync::{mpsc, oneshot};
+use futures::try_ready;
+use lazy_static::lazy_static;
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+// Capacity of the channel from the streams to the circuit.
+const MESSAGES: usize = 64;
+
+// Capacity of the channel from the circuit to each stream.
+pub const CELLS: usize = 16;
+
+lazy_static! {
+    // Circuits which take new streams, including the ones still in the handshake.
+    pub static ref CIRCUITS: Lock<Vec<CircuitHandle>> = Lock::new(Vec::new());
+}
+
+#[derive(Clone, Debug)]
+pub struct CircuitConfig {
+    pub hops: usize,
+    pub circuits: usize,   // Number of circuits kept ready in the pool
+    pub max_age: Duration, // Circuits older than this don't take new streams
+    pub diversity: Diversity,
+    pub guards: GuardConfig,
+    pub attempts: usize, // Number of routes tried for a request before giving up
+    pub deadline: Duration, // Time to build a circuit for a request including the retries
+    pub hop_timeout: Duration, // Time for each hop to connect and answer during the build
+    pub padding: Padding,
+    pub cover: Schedule, // Dummy frames sent by both ends of each link
+}
+
+#[derive(Debug)]
+pub enum Message {
+    // Cells of the stream are delivered to `cells` once the exit node replies.
+    Begin {
+        stream: u16,
+        addr: Addr,
+        tls: bool,
+        cells: mpsc::Sender<Cell>,
+    },
+    Cell(Cell),
+}
+
+//
+// Shared by the streams on the circuit.
+// The circuit is closed once all the handles are dropped or the connection to the first node is lost.
+//
+#[derive(Clone, Debug)]
+pub struct CircuitHandle {
+    messages: mpsc::Sender<Message>,
+    next_stream: Arc<AtomicUsize>,
+    ready: Arc<AtomicBool>, // The handshakes with all the hops are done
+    nodes: Vec<SocketAddr>,
+    exit_policy: ExitPolicy, // Published by the last hop
+    created: Instant,
+}
+
+impl CircuitHandle {
+    // Stream ids are never reused on a circuit, a new circuit is built once they run out.
+    pub fn is_usable(&self) -> bool {
+        !self.messages.is_closed()
+            && self.next_stream.load(Ordering::SeqCst) <= u16::max_value() as usize
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.is_usable() && self.ready.load(Ordering::SeqCst)
+    }
+
+    pub fn is_expired(&self, max_age: Duration) -> bool {
+        self.created.elapsed() > max_age
+    }
+
+    pub fn is_via(&self, addr: SocketAddr) -> bool {
+        self.nodes.contains(&addr)
+    }
+
+    pub fn is_via_ip(&self, ip: IpAddr) -> bool {
+        self.nodes.iter().any(|n| n.ip() == ip)
+    }
+
+    pub fn exits_to(&self, addr: &Addr) -> bool {
+        exits_to(&self.exit_policy, addr)
+    }
+
+    pub fn open(&self) -> Option<(u16, mpsc::Sender<Message>)> {
+        let stream = self.next_stream.fetch_add(1, Ordering::SeqCst);
+
+        if stream > u16::max_value() as usize {
+            return None;
+        }
+
+        Some((stream as u16, self.messages.clone()))
+    }
+}
+
+// Resolved once the handshakes are done, or with the node which has failed in them.
+pub type Handshake = oneshot::Receiver<std::result::Result<(), SocketAddr>>;
+
+#[derive(Debug)]
+pub struct Circuit {
+    upstream: Upstream,
+    link: Link, // To the first node
+    nodes: Vec<RouteNode>,
+    established: usize, // Number of hops which have completed the handshake
+    hop_timeout: Duration,
+    hop_deadline: Delay, // Dummies from the established hops don't put it off
+    messages: mpsc::Receiver<Message>,
+    streams: HashMap<u16, mpsc::Sender<Cell>>,
+    pending: Option<(u16, Cell)>, // A cell waiting for its stream to take it
+    padding: Padding,
+    cover: Cover,
+    schedule: Schedule,
+    ready: Arc<AtomicBool>,
+    handshake: Option<oneshot::Sender<std::result::Result<(), SocketAddr>>>,
+}
+
+impl Circuit {
+    pub fn new(
+        mut upstream: Upstream,
+        nodes: Vec<RouteNode>,
+        exit_policy: ExitPolicy,
+        padding: Padding,
+        schedule: Schedule,
+        hop_timeout: Duration,
+    ) -> Result<(Circuit, CircuitHandle, Handshake)> {
+        upstream.set_read_delim(Delim::Dytp);
+        upstream.set_write_delim(Delim::Dytp);
+
+        let (tx, rx) = mpsc::channel(MESSAGES);
+        let ready = Arc::new(AtomicBool::new(false));
+        let (handshake_tx, handshake_rx) = oneshot::channel();
+
+        let handle = CircuitHandle {
+            messages: tx,
+            next_stream: Arc::new(AtomicUsize::new(1)),
+            ready: ready.clone(),
+            nodes: nodes.iter().map(|n| n.addr).collect(),
+            exit_policy,
+            created: Instant::now(),
+        };
+
+        let link = Link::near(&nodes[0].link)?;
+
+        let mut circuit = Circuit {
+            upstream,
+            link,
+            nodes,
+            established: 0,
+            hop_timeout,
+            hop_deadline: Delay::new(Instant::now() + hop_timeout),
+            messages: rx,
+            streams: HashMap::new(),
+            pending: None,
+            padding,
+            cover: Cover::new(schedule),
+            schedule,
+            ready,
+            handshake: Some(handshake_tx),
+        };
+
+        let create = circuit.nodes[0].create()?;
+        let create = circuit.link.seal(&create);
+
+        circuit.upstream.write(&create)?;
+        circuit.upstream.flush()?;
+
+        Ok((circuit, handle, handshake_rx))
+    }
+
+    // The node being extended to is taken as the one which has failed.
+    fn fail_handshake(&mut self) {
+        let idx = self.established.min(self.nodes.len() - 1);
+        let addr = self.nodes[idx].addr;
+
+        if let Some(handshake) = self.handshake.take() {
+            let _ = handshake.send(Err(addr));
+        }
+    }
+
+    fn exit(&self) -> SocketAddr {
+        self.nodes[self.nodes.len() - 1].addr
+    }
+
+    //
+    // The circuit is extended hop by hop.
+    // Once the handshake with a node is completed, the node is told where to rely
+    // and the CREATE for the next node is sent through the established hops.
+    // Every hop is told the padding since any of them may send DESTROY,
+    // and the schedule and the keys of its links since it sends dummies to both of its neighbours.
+    //
+    fn extend(&mut self, created: &[u8]) -> Result<()> {
+        let idx = self.established;
+
+        self.nodes[idx].created(created)?;
+        self.established += 1;
+
+        log::debug!("handshake done with {}", self.nodes[idx].addr);
+
+        let padding = self.padding;
+        let cover = self.schedule;
+        let link = self.nodes[idx].link.clone();
+        let method = match self.nodes[idx].next.clone() {
+            Some(addr) => encrypted::Method::RELY {
+                addr,
+                padding,
+                cover,
+                link,
+                next_link: self.nodes[idx + 1].link.clone(),
+            },
+            None => encrypted::Method::EXIT {
+                padding,
+                cover,
+                link,
+            },
+        }
+        .encode()?;
+
+        self.rely(&method)?;
+
+        if self.established < self.nodes.len() {
+            let create = self.nodes[self.established].create()?;
+
+            self.rely(&create)?;
+            self.hop_deadline = Delay::new(Instant::now() + self.hop_timeout);
+        }
+
+        Ok(())
+    }
+
+    // Fails if any layer doesn't authenticate, then the circuit must be torn down.
+    // None for a dummy from the first node.
+    fn decrypt(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut payload = match self.link.open(payload)? {
+            Frame::Cell(frame) => frame,
+            Frame::Dummy => return Ok(None),
+        };
+
+        for node in self.nodes.iter_mut().take(self.established) {
+            payload = node.open(&payload)?;
+        }
+
+        Ok(Some(payload))
+    }
+
+    fn seal(&mut self, buf: &[u8]) -> Vec<u8> {
+        let mut buf = buf.to_vec();
+
+        for node in self.nodes.iter_mut().take(self.established).rev() {
+            buf = node.seal(&buf);
+        }
+
+        buf
+    }
+
+    fn rely(&mut self, buf: &[u8]) -> Result<()> {
+        let buf = self.seal(buf);
+        let buf = self.link.seal(&buf);
+
+        self.upstream.write(&buf)?;
+
+        Ok(())
+    }
+
+    fn send(&mut self, cell: Cell) -> Result<()> {
+        let cells: Vec<Vec<u8>> = cell
+            .encode(self.padding)?
+            .iter()
+            .map(|cell| {
+                let sealed = self.seal(cell);
+
+                self.link.seal(&sealed)
+            })
+            .collect();
+
+        self.upstream.try_write_all(&cells)?;
+
+        Ok(())
+    }
+
+    //
+    // Hands a cell to its stream.
+    // Returns false if the stream can't take it for now, then the circuit stops reading
+    // until the stream catches up.
+    //
+    fn deliver(&mut self, stream: u16, cell: Cell) -> Result<bool> {
+        let end = match cell {
+            Cell::END { .. } => true,
+            _ => false,
+        };
+
+        let delivered = match self.streams.get_mut(&stream) {
+            Some(cells) => cells.start_send(cell),
+            None => return Ok(true),
+        };
+
+        match delivered {
+            Ok(AsyncSink::Ready) => {
+                if end {
+                    self.streams.remove(&stream);
+                }
+            }
+            Ok(AsyncSink::NotReady(cell)) => {
+                self.pending = Some((stream, cell));
+
+                return Ok(false);
+            }
+            Err(_) => {
+                // The client has gone away, cells for the stream are dropped from now on.
+                self.streams.remove(&stream);
+                self.send(Cell::END { stream })?;
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Future for Circuit {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // The handshakes are driven by the replies from the circuit.
+        while self.established < self.nodes.len() {
+            match self.upstream.poll() {
+                Ok(Async::Ready(Some(payload))) => {
+                    // The hops don't send dummies before the circuit is extended, but they're dropped anyway.
+                    let decrypted = match self.decrypt(&payload) {
+                        Ok(Some(decrypted)) => decrypted,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            log::warn!("tear down the circuit via {}: {}", self.exit(), e);
+
+                            self.fail_handshake();
+
+                            return Ok(Async::Ready(()));
+                        }
+                    };
+
+                    // The last established hop couldn't reach the next one.
+                    let destroy = match Cell::decode_padded(&decrypted, self.padding) {
+                        Ok(Cell::DESTROY { status }) if self.established > 0 => Some(status),
+                        _ => None,
+                    };
+
+                    if let Some(status) = destroy {
+                        log::warn!(
+                            "{} couldn't extend the circuit to {} (status={:?})",
+                            self.nodes[self.established - 1].addr,
+                            self.nodes[self.established].addr,
+                            status
+                        );
+
+                        self.fail_handshake();
+
+                        return Ok(Async::Ready(()));
+                    }
+
+                    if let Err(e) = self.extend(&decrypted) {
+                        log::warn!("failed to extend the circuit to {}: {}", self.exit(), e);
+
+                        self.fail_handshake();
+
+                        return Ok(Async::Ready(()));
+                    }
+
+                    if self.established == self.nodes.len() {
+                        log::debug!("circuit established via {}", self.exit());
+
+                        // Idle circuits are kept until they are retired from the pool.
+                        self.upstream.set_read_timeout(Duration::from_secs(0));
+                        self.ready.store(true, Ordering::SeqCst);
+
+                        if let Some(handshake) = self.handshake.take() {
+                            let _ = handshake.send(Ok(()));
+                        }
+                    }
+                }
+                Ok(Async::Ready(None)) | Err(_) => {
+                    log::warn!("circuit via {} closed before established", self.exit());
+
+                    self.fail_handshake();
+
+                    return Ok(Async::Ready(()));
+                }
+                Ok(Async::NotReady) => {
+                    // The build has been given up by its deadline, the stalled node is blamed all the same.
+                    let canceled = match self.handshake.as_mut().map(|h| h.poll_cancel()) {
+                        Some(Ok(Async::Ready(()))) => true,
+                        _ => false,
+                    };
+
+                    let expired = match self.hop_deadline.poll() {
+                        Ok(Async::NotReady) => false,
+                        _ => true,
+                    };
+
+                    if !canceled && !expired {
+                        return Ok(Async::NotReady);
+                    }
+
+                    let addr = self.nodes[self.established].addr;
+
+                    log::warn!("{} hasn't answered in time", addr);
+
+                    if canceled {
+                        tokio::spawn(
+                            RecordFailure::new(addr)
+                                .map_err(|e| log::error!("record failure error={:?}", e)),
+                        );
+                    } else {
+                        self.fail_handshake();
+                    }
+
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+
+        // The circuit and the streams are polled until neither of them makes progress.
+        loop {
+            let mut progress: bool = false;
+
+            if let Some((stream, cell)) = self.pending.take() {
+                progress = self.deliver(stream, cell)?;
+            }
+
+            if self.pending.is_none() {
+                match self.upstream.poll() {
+                    Ok(Async::Ready(Some(payload))) => {
+                        progress = true;
+
+                        let cell = match self.decrypt(&payload) {
+                            Ok(Some(decrypted)) => {
+                                Cell::decode_padded(&decrypted, self.padding).unwrap_or(Cell::E)
+                            }
+                            Ok(None) => continue,
+                            Err(e) => {
+                                log::warn!("tear down the circuit via {}: {}", self.exit(), e);
+
+                                return Ok(Async::Ready(()));
+                            }
+                        };
+
+                        match cell {
+                            Cell::STATUS { stream, .. }
+                            | Cell::DATA { stream, .. }
+                            | Cell::END { stream } => {
+                                self.deliver(stream, cell)?;
+                            }
+                            Cell::DESTROY { status } => {
+                                log::warn!(
+                                    "circuit via {} destroyed (status={:?})",
+                                    self.exit(),
+                                    status
+                                );
+
+                                return Ok(Async::Ready(()));
+                            }
+                            Cell::BEGIN { .. } | Cell::E => {
+                                log::warn!(
+                                    "tear down the circuit via {}: invalid cell",
+                                    self.exit()
+                                );
+
+                                return Ok(Async::Ready(()));
+                            }
+                        }
+                    }
+                    Ok(Async::Ready(None)) | Err(_) => {
+                        log::debug!("circuit via {} closed", self.exit());
+
+                        return Ok(Async::Ready(()));
+                    }
+                    Ok(Async::NotReady) => {}
+                }
+            }
+
+            // Cells from the streams wait in the channel while the first node is slow.
+            if self.upstream.wb_full() {
+                self.upstream.reset_read_timer();
+            } else {
+                match self.messages.poll() {
+                    Ok(Async::Ready(Some(message))) => {
+                        progress = true;
+
+                        match message {
+                            Message::Begin {
+                                stream,
+                                addr,
+                                tls,
+                                cells,
+                            } => {
+                                self.streams.insert(stream, cells);
+                                self.send(Cell::BEGIN { stream, addr, tls })?;
+                            }
+                            Message::Cell(cell) => {
+                                self.send(cell)?;
+                            }
+                        }
+                    }
+                    Ok(Async::Ready(None)) | Err(_) => {
+                        return Ok(Async::Ready(()));
+                    }
+                    Ok(Async::NotReady) => {}
+                }
+            }
+
+            // Idle circuits in the pool are covered too so that the first stream doesn't stand out.
+            if !self.upstream.wb_full() && self.cover.poll_due() {
+                progress = true;
+
+                let dummy = self.link.dummy();
+
+                self.upstream.write(&dummy)?;
+            }
+
+            if self.upstream.poll_flush().is_err() {
+                log::debug!("circuit via {} closed", self.exit());
+
+                return Ok(Async::Ready(()));
+            }
+
+            if !progress {
+                break;
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+//
+// Picks one of the circuits which are ready to open a stream.
+// Circuits going through the destination of the stream are left for other streams.
+// Circuits which have been closed are forgotten here.
+//
+#[derive(Debug)]
+pub struct GetCircuit {
+    addr: Addr,
+    circuits: Acquire<Vec<CircuitHandle>>,
+}
+
+impl GetCircuit {
+    pub fn new(addr: Addr) -> GetCircuit {
+        GetCircuit {
+            addr,
+            circuits: CIRCUITS.acquire(),
+        }
+    }
+}
+
+impl Future for GetCircuit {
+    type Item = Option<CircuitHandle>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut circuits = try_ready!(self.circuits.poll_lock());
+
+        circuits.retain(|c| c.is_usable());
+
+        let addr = &self.addr;
+        let avoid = addr.ip();
+        let ready: Vec<&CircuitHandle> = circuits
+            .iter()
+            .filter(|c| c.is_ready())
+            .filter(|c| avoid.map(|ip| !c.is_via_ip(ip)).unwrap_or(true))
+            .filter(|c| c.exits_to(addr))
+            .collect();
+
+        let mut rng = &mut rand::thread_rng();
+        let circuit = ready
+            .choose_multiple(&mut rng, 1)
+            .next()
+            .map(|c| (*c).clone());
+
+        Ok(Async::Ready(circuit))
+    }
+}
+
+#[derive(Debug)]
+pub struct RegisterCircuit {
+    circuit: Option<CircuitHandle>,
+    circuits: Acquire<Vec<CircuitHandle>>,
+}
+
+impl RegisterCircuit {
+    pub fn new(circuit: CircuitHandle) -> RegisterCircuit {
+        RegisterCircuit {
+            circuit: Some(circuit),
+            circuits: CIRCUITS.acquire(),
+        }
+    }
+}
+
+impl Future for RegisterCircuit {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut circuits = try_ready!(self.circuits.poll_lock());
+
+        circuits.push(self.circuit.take().unwrap());
+
+        Ok(Async::Ready(()))
+    }
+}
+
+//
+// Removes circuits from the pool so that they don't take new streams.
+// Streams already on them are carried until they end, then the circuits are closed.
+// Resolves to the number of circuits left in the pool.
+//
+#[derive(Debug)]
+pub struct RetireCircuits {
+    max_age: Option<Duration>,
+    node: Option<SocketAddr>,
+    circuits: Acquire<Vec<CircuitHandle>>,
+}
+
+impl RetireCircuits {
+    pub fn expired(max_age: Duration) -> RetireCircuits {
+        RetireCircuits {
+            max_age: Some(max_age),
+            node: None,
+            circuits: CIRCUITS.acquire(),
+        }
+    }
+
+    pub fn via(addr: SocketAddr) -> RetireCircuits {
+        RetireCircuits {
+            max_age: None,
+            node: Some(addr),
+            circuits: CIRCUITS.acquire(),
+        }
+    }
+}
+
+impl Future for RetireCircuits {
+    type Item = usize;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let max_age = self.max_age;
+        let node = self.node;
+        let mut circuits = try_ready!(self.circuits.poll_lock());
+
+        circuits.retain(|c| {
+            if !c.is_usable() {
+                return false;
+            }
+
+            let expired = max_age
+                .map(|max_age| c.is_expired(max_age))
+                .unwrap_or(false);
+            let via = node.map(|addr| c.is_via(addr)).unwrap_or(false);
+
+            if expired || via {
+                log::debug!("retire the circuit via {:?}", c.nodes);
+            }
+
+            !expired && !via
+        });
+
+        Ok(Async::Ready(circuits.len()))
+    }
+}
@@ -1,3 +1,21 @@
 use failure::Error;
+use failure::Fail;
+use std::net::SocketAddr;
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Fail)]
+pub enum GatewayError {
+    #[fail(display = "no more streams can be opened on the circuit")]
+    StreamsExhausted,
+    #[fail(display = "not enough diverse nodes for hops={}", hops)]
+    NotEnoughDiverseNodes { hops: usize },
+    #[fail(display = "none of the entry guards can be the first hop")]
+    NoUsableGuard,
+    #[fail(display = "no exit node allows {}", addr)]
+    NoExitAllowed { addr: String },
+    #[fail(display = "{} failed while building a circuit", addr)]
+    HopFailed { addr: SocketAddr },
+    #[fail(display = "gave up building a circuit within the deadline")]
+    BuildTimedOut,
+}
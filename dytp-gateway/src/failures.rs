@@ -0,0 +1,96 @@
+use dytp_future::lock::{Acquire, Lock};
+use failure::Error;
+use futures::prelude::*;
+use futures::try_ready;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Secs a node is left out of new routes after it has failed to build a circuit.
+const SUSPENSION: u64 = 60;
+
+lazy_static! {
+    // Nodes which have failed to build circuits, only known by this gateway.
+    pub static ref FAILURES: Lock<HashMap<SocketAddr, Failures>> = Lock::new(HashMap::new());
+}
+
+#[derive(Clone, Debug)]
+pub struct Failures {
+    pub count: u64,
+    pub last: Instant,
+}
+
+impl Failures {
+    pub fn is_suspended(&self) -> bool {
+        self.last.elapsed() < Duration::from_secs(SUSPENSION)
+    }
+}
+
+#[derive(Debug)]
+pub struct RecordFailure {
+    addr: SocketAddr,
+    failures: Acquire<HashMap<SocketAddr, Failures>>,
+}
+
+impl RecordFailure {
+    pub fn new(addr: SocketAddr) -> RecordFailure {
+        RecordFailure {
+            addr,
+            failures: FAILURES.acquire(),
+        }
+    }
+}
+
+impl Future for RecordFailure {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut failures = try_ready!(self.failures.poll_lock());
+
+        let failure = failures.entry(self.addr).or_insert(Failures {
+            count: 0,
+            last: Instant::now(),
+        });
+
+        failure.count += 1;
+        failure.last = Instant::now();
+
+        log::warn!(
+            "suspend {} for {} secs (failures={})",
+            self.addr,
+            SUSPENSION,
+            failure.count
+        );
+
+        Ok(Async::Ready(()))
+    }
+}
+
+// Number of failures of each node so far.
+#[derive(Debug)]
+pub struct GetFailures {
+    failures: Acquire<HashMap<SocketAddr, Failures>>,
+}
+
+impl GetFailures {
+    pub fn new() -> GetFailures {
+        GetFailures {
+            failures: FAILURES.acquire(),
+        }
+    }
+}
+
+impl Future for GetFailures {
+    type Item = HashMap<SocketAddr, u64>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let failures = try_ready!(self.failures.poll_lock());
+
+        Ok(Async::Ready(
+            failures.iter().map(|(addr, f)| (*addr, f.count)).collect(),
+        ))
+    }
+}
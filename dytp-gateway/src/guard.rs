@@ -0,0 +1,213 @@
+use crate::error::Result;
+use crate::route::NODES;
+use crate::selection::{PathSelection, Uniform};
+use dytp_component::node::Node;
+use dytp_future::lock::{Acquire, Lock};
+use failure::Error;
+use futures::prelude::*;
+use futures::try_ready;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    // Nodes taken as the first hop of every circuit.
+    pub static ref GUARDS: Lock<Vec<Guard>> = Lock::new(Vec::new());
+}
+
+#[derive(Clone, Debug)]
+pub struct GuardConfig {
+    pub guards: usize,      // Number of entry guards
+    pub lifetime: Duration, // Guards older than this are rotated
+    pub file: PathBuf,      // Where the guards are kept across restarts
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Guard {
+    pub addr: SocketAddr,
+    pub fingerprint: String,
+    pub added: u64, // Unix time when the node became a guard
+}
+
+impl Guard {
+    fn new(node: &Node) -> Guard {
+        Guard {
+            addr: node.addr,
+            fingerprint: node.fingerprint.clone(),
+            added: now(),
+        }
+    }
+
+    fn is_expired(&self, lifetime: Duration) -> bool {
+        self.added + lifetime.as_secs() < now()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+//
+// The state file has a guard per line as `addr fingerprint added`.
+//
+pub fn load(file: &Path) -> Result<Vec<Guard>> {
+    if !file.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut guards = Vec::new();
+
+    for line in fs::read_to_string(file)?.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+
+        if fields.len() != 3 {
+            log::warn!("ignore an invalid entry guard={:?}", line);
+            continue;
+        }
+
+        match (fields[0].parse(), fields[2].parse()) {
+            (Ok(addr), Ok(added)) => guards.push(Guard {
+                addr,
+                fingerprint: fields[1].to_owned(),
+                added,
+            }),
+            _ => log::warn!("ignore an invalid entry guard={:?}", line),
+        }
+    }
+
+    Ok(guards)
+}
+
+// Written to a temporary file first so that a crash never leaves a truncated state.
+pub fn save(file: &Path, guards: &[Guard]) -> Result<()> {
+    let buf: String = guards
+        .iter()
+        .map(|g| format!("{} {} {}\n", g.addr, g.fingerprint, g.added))
+        .collect();
+    let tmp = file.with_extension("tmp");
+
+    fs::write(&tmp, buf)?;
+    fs::rename(&tmp, file)?;
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct RegisterGuards {
+    guards: Vec<Guard>,
+    gs: Acquire<Vec<Guard>>,
+}
+
+impl RegisterGuards {
+    pub fn new(guards: Vec<Guard>) -> RegisterGuards {
+        RegisterGuards {
+            guards,
+            gs: GUARDS.acquire(),
+        }
+    }
+}
+
+impl Future for RegisterGuards {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut gs = try_ready!(self.gs.poll_lock());
+
+        *gs = self.guards.clone();
+
+        Ok(Async::Ready(()))
+    }
+}
+
+//
+// Drops the guards which have expired, left the node list or changed their identity,
+// then fills up the guards with nodes weighted by their bandwidth.
+// Resolves to the new guards if they have been changed.
+//
+#[derive(Debug)]
+pub struct KeepGuards {
+    guards: usize,
+    lifetime: Duration,
+    nodes: Acquire<Vec<Node>>,
+    gs: Acquire<Vec<Guard>>,
+}
+
+impl KeepGuards {
+    pub fn new(config: &GuardConfig) -> KeepGuards {
+        KeepGuards {
+            guards: config.guards,
+            lifetime: config.lifetime,
+            nodes: NODES.acquire(),
+            gs: GUARDS.acquire(),
+        }
+    }
+}
+
+impl Future for KeepGuards {
+    type Item = Option<Vec<Guard>>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let guards = self.guards;
+        let lifetime = self.lifetime;
+
+        // always in this order to avoid a deadlock
+        let nodes = try_ready!(self.nodes.poll_lock());
+        let mut gs = try_ready!(self.gs.poll_lock());
+
+        // The node list hasn't been fetched yet.
+        if nodes.is_empty() {
+            return Ok(Async::Ready(None));
+        }
+
+        let before = gs.clone();
+
+        gs.retain(|g| {
+            if g.is_expired(lifetime) {
+                log::info!("rotate the entry guard {}", g.addr);
+                return false;
+            }
+
+            if !nodes
+                .iter()
+                .any(|n| n.addr == g.addr && n.fingerprint == g.fingerprint)
+            {
+                log::info!("drop the entry guard {} which is no longer active", g.addr);
+                return false;
+            }
+
+            true
+        });
+
+        while gs.len() < guards {
+            let candidates: Vec<&Node> = nodes
+                .iter()
+                .filter(|n| !gs.iter().any(|g| g.addr == n.addr))
+                .collect();
+
+            if candidates.is_empty() {
+                break;
+            }
+
+            // Guards are kept for long, so a node lying about its bandwidth must not be preferred for them.
+            let guard = Guard::new(candidates[Uniform.choose(&candidates, &HashMap::new())]);
+
+            log::info!("new entry guard {}", guard.addr);
+
+            gs.push(guard);
+        }
+
+        if *gs == before {
+            return Ok(Async::Ready(None));
+        }
+
+        Ok(Async::Ready(Some(gs.clone())))
+    }
+}
@@ -0,0 +1,51 @@
+use dytp_future::lock::{Acquire, Lock};
+use failure::Error;
+use futures::prelude::*;
+use futures::try_ready;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+lazy_static! {
+    // Round trips to the nodes measured while fetching their onion keys, only known by this gateway.
+    pub static ref LATENCIES: Lock<HashMap<SocketAddr, Duration>> = Lock::new(HashMap::new());
+}
+
+//
+// Keeps a moving average of the round trips so that a single slow answer
+// doesn't drop the node out of routes.
+//
+#[derive(Debug)]
+pub struct RecordLatency {
+    addr: SocketAddr,
+    sample: Duration,
+    latencies: Acquire<HashMap<SocketAddr, Duration>>,
+}
+
+impl RecordLatency {
+    pub fn new(addr: SocketAddr, sample: Duration) -> RecordLatency {
+        RecordLatency {
+            addr,
+            sample,
+            latencies: LATENCIES.acquire(),
+        }
+    }
+}
+
+impl Future for RecordLatency {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut latencies = try_ready!(self.latencies.poll_lock());
+        let sample = self.sample;
+
+        latencies
+            .entry(self.addr)
+            .and_modify(|latency| *latency = (*latency * 7 + sample) / 8)
+            .or_insert(sample);
+
+        Ok(Async::Ready(()))
+    }
+}
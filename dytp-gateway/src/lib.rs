@@ -1,11 +1,24 @@
+pub mod circuit;
 pub mod error;
+pub mod failures;
+pub mod guard;
+pub mod latency;
 pub mod rely;
+pub mod reply;
 pub mod route;
 pub mod route_node;
+pub mod selection;
 pub mod ts;
 
-use crate::error::Result;
-use crate::rely::Rely;
+use crate::circuit::{
+    Circuit, CircuitConfig, CircuitHandle, GetCircuit, RegisterCircuit, RetireCircuits,
+};
+use crate::error::{GatewayError, Result};
+use crate::failures::{GetFailures, RecordFailure};
+use crate::guard::{KeepGuards, RegisterGuards};
+use crate::latency::RecordLatency;
+use crate::rely::{Front, Rely};
+use crate::reply::StreamError;
 use crate::route::{GetAllNodes, GetRoute, RegisterNode, RegisterNodes, RemoveNode};
 use crate::route_node::RouteNode;
 use crate::ts::{LatestTs, RecordTs};
@@ -13,19 +26,22 @@ use clap::crate_version;
 use dytp_component::health_resp_gateway::HealthRespGateway;
 use dytp_component::node_state::NodeState;
 use dytp_connection::prelude::*;
+use dytp_connection::request::BAD_REQUEST;
 use dytp_future::fetch_nodes::FetchNodes;
-use dytp_future::get_pub_key::GetPubKey;
+use dytp_future::get_onion_key::GetOnionKey;
 use dytp_future::sync_audit::SyncAudit;
+use dytp_protocol::addr::Addr;
+use dytp_protocol::crypto;
 use dytp_protocol::delim::Delim;
 use dytp_protocol::method::plain;
 use failure::Error;
-use futures::future::{join_all, Either};
+use futures::future::{join_all, Either, Loop};
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
-use tokio::timer::Interval;
+use tokio::timer::{Interval, Timeout};
 
 type ProcessFuture = Box<Future<Item = (), Error = Error> + Send>;
 
@@ -34,27 +50,351 @@ fn ignore() -> ProcessFuture {
 }
 
 fn health(request: Request) -> ProcessFuture {
-    let f = GetAllNodes::new().map_err(|e| e.into()).map(|nodes| {
-        let res: Vec<u8> = HealthRespGateway::new(crate_version!(), &nodes).into();
+    let f = GetAllNodes::new()
+        .join(GetFailures::new())
+        .and_then(|(nodes, failures)| {
+            let res =
+                plain::Health::GATEWAY(HealthRespGateway::new(crate_version!(), &nodes, &failures));
+
+            let mut origin = Origin::new(request.stream());
+
+            // The answer is binary, so it's framed as the other methods instead of a line.
+            origin.set_write_delim(Delim::Dytp);
+            origin.set_read_delim(Delim::Http);
+            origin.write(&res.encode()?)?;
+
+            Ok(Flush::new(origin).map(|_| ()))
+        });
+
+    Box::new(f.flatten())
+}
+
+type BuildFuture = Box<Future<Item = Option<CircuitHandle>, Error = Error> + Send>;
+
+// The node is left out of new routes for a while and the build is failed with it.
+fn hop_failed(addr: SocketAddr) -> BuildFuture {
+    let f =
+        RecordFailure::new(addr).and_then(move |_| Err(GatewayError::HopFailed { addr }.into()));
+
+    Box::new(f)
+}
+
+// Resolved once the handshakes with all the hops are done.
+fn build(config: &CircuitConfig, dest: Option<Addr>) -> BuildFuture {
+    let route = GetRoute::new(config.hops, config.diversity, dest);
+    let padding = config.padding;
+    let cover = config.cover;
+    let hop_timeout = config.hop_timeout;
+
+    log::debug!("decided the route.");
+
+    let f = route.and_then(move |nodes| {
+        if let Some(nodes) = nodes {
+            let mut get_onion_keys = Vec::with_capacity(nodes.len());
+
+            for node in nodes.iter() {
+                let start = Instant::now();
+
+                // A node which doesn't answer in time is taken as failed the same as one without a key.
+                let get_onion_key = Timeout::new(GetOnionKey::new(node.addr), hop_timeout)
+                    .then(move |res| Ok::<_, Error>((res.unwrap_or(None), start.elapsed())));
+
+                get_onion_keys.push(get_onion_key);
+            }
 
-        let mut origin = Origin::new(request.stream());
+            let f = join_all(get_onion_keys).and_then(move |onion_keys| {
+                if let Some(idx) = onion_keys.iter().position(|(k, _)| k.is_none()) {
+                    return hop_failed(nodes[idx].addr);
+                }
+
+                // Measured on the way so that faster nodes are preferred for the next circuits.
+                for (node, (_, latency)) in nodes.iter().zip(onion_keys.iter()) {
+                    tokio::spawn(
+                        RecordLatency::new(node.addr, *latency)
+                            .map_err(|e| log::error!("record latency error={:?}", e)),
+                    );
+                }
+
+                log::debug!("received onion keys.");
+
+                // Onion keys must be signed by the identities known by the cloud.
+                let now = crypto::now();
+                let mut verified = Vec::with_capacity(nodes.len());
 
-        origin.set_write_delim(Delim::Http);
-        origin.set_read_delim(Delim::Http);
-        origin.write(&res).unwrap();
-        origin.flush().unwrap();
+                for (node, (onion_key, _)) in nodes.iter().zip(onion_keys.iter()) {
+                    match onion_key.as_ref().unwrap().verify(&node.fingerprint, now) {
+                        Ok(ntor_key) => verified.push(ntor_key),
+                        Err(e) => {
+                            log::warn!("node {} has an invalid onion key: {}", node.addr, e);
 
-        ()
+                            return hop_failed(node.addr);
+                        }
+                    }
+                }
+
+                let route_nodes: Result<Vec<RouteNode>> = verified
+                    .into_iter()
+                    .enumerate()
+                    .map(|(idx, ntor_key)| {
+                        if idx < nodes.len() - 1 {
+                            RouteNode::new(
+                                nodes[idx].addr,
+                                Some(nodes[idx + 1].addr.into()),
+                                ntor_key,
+                            )
+                        } else {
+                            RouteNode::new(nodes[idx].addr, None, ntor_key)
+                        }
+                    })
+                    .collect();
+
+                let route_nodes = match route_nodes {
+                    Ok(route_nodes) => route_nodes,
+                    Err(e) => return Box::new(future::err(e)) as BuildFuture,
+                };
+
+                let guard = nodes[0].addr;
+                let exit_policy = nodes[nodes.len() - 1].exit_policy.clone();
+
+                // Each hop is given the same time to answer CREATE as to connect.
+                let connect = Upstream::new_with_timeout(guard, hop_timeout.as_secs());
+
+                let f = Timeout::new(connect, hop_timeout).then(move |res| {
+                    let mut upstream = match res {
+                        Ok(upstream) => upstream,
+                        Err(_) => return hop_failed(guard),
+                    };
+
+                    if upstream.negotiate().is_err() {
+                        return hop_failed(guard);
+                    }
+
+                    match Circuit::new(
+                        upstream,
+                        route_nodes,
+                        exit_policy,
+                        padding,
+                        cover,
+                        hop_timeout,
+                    ) {
+                        Ok((circuit, handle, handshake)) => {
+                            tokio::spawn(circuit.map_err(|e| log::error!("circuit error={:?}", e)));
+
+                            // Registered before the handshakes so that the pool isn't overfilled meanwhile.
+                            let f = RegisterCircuit::new(handle.clone()).and_then(move |_| {
+                                handshake.then(move |res| match res {
+                                    Ok(Ok(())) => Box::new(future::ok(Some(handle))) as BuildFuture,
+                                    Ok(Err(addr)) => hop_failed(addr),
+                                    Err(_) => Box::new(future::ok(None)) as BuildFuture,
+                                })
+                            });
+
+                            Box::new(f) as BuildFuture
+                        }
+                        Err(_) => hop_failed(guard),
+                    }
+                });
+
+                Box::new(f) as BuildFuture
+            });
+
+            return Box::new(f) as BuildFuture;
+        }
+
+        Box::new(future::ok(None))
     });
 
     Box::new(f)
 }
 
-fn process(socket: TcpStream, hops: usize, read_timeout: u64) {
+// A circuit for a request is built again via other nodes while hops keep failing.
+fn build_with_retry(config: CircuitConfig, dest: Addr) -> BuildFuture {
+    let attempts = config.attempts;
+    let deadline = config.deadline;
+
+    let f = future::loop_fn(1, move |attempt| {
+        build(&config, Some(dest.clone())).then(move |res| match res {
+            Ok(circuit) => Ok(Loop::Break(circuit)),
+            Err(e) => match e.downcast::<GatewayError>() {
+                Ok(GatewayError::HopFailed { addr }) if attempt < attempts => {
+                    log::info!(
+                        "retry building a circuit since {} has failed ({}/{})",
+                        addr,
+                        attempt + 1,
+                        attempts
+                    );
+
+                    Ok(Loop::Continue(attempt + 1))
+                }
+                Ok(e) => Err(e.into()),
+                Err(e) => Err(e),
+            },
+        })
+    });
+
+    let f = Timeout::new(f, deadline).map_err(|e| match e.into_inner() {
+        Some(e) => e,
+        None => GatewayError::BuildTimedOut.into(),
+    });
+
+    Box::new(f)
+}
+
+// Keeps the pool filled up with circuits ready for new requests.
+// Entry guards are kept up to date beforehand so that new circuits start from them.
+fn replenish(config: CircuitConfig) -> impl Future<Item = (), Error = Error> {
+    let file = config.guards.file.clone();
+
+    KeepGuards::new(&config.guards)
+        .map(move |guards| {
+            if let Some(guards) = guards {
+                if let Err(e) = guard::save(&file, &guards) {
+                    log::error!("failed to save the entry guards due to error={:?}", e);
+                }
+            }
+        })
+        .and_then(move |_| RetireCircuits::expired(config.max_age).map(|n| (n, config)))
+        .and_then(move |(n, config)| {
+            // A failed build is tried again on the next tick.
+            // The circuit of a build which doesn't finish in time is torn down so that it doesn't hold a slot.
+            let builds: Vec<_> = (n..config.circuits)
+                .map(|_| {
+                    let f =
+                        Timeout::new(build(&config, None), config.deadline).map_err(|e| {
+                            match e.into_inner() {
+                                Some(e) => e,
+                                None => GatewayError::BuildTimedOut.into(),
+                            }
+                        });
+
+                    f.then(|res| {
+                        if let Err(e) = res {
+                            log::debug!("failed to build a circuit for the pool: {}", e);
+                        }
+
+                        Ok::<(), Error>(())
+                    })
+                })
+                .collect();
+
+            join_all(builds).map(|_| ())
+        })
+}
+
+// Streams are opened on a circuit in the pool, a circuit is built for the request only if none is ready.
+fn circuit(
+    req: Request,
+    addr: Addr,
+    front: Front,
+    config: CircuitConfig,
+    read_timeout: u64,
+    linger_timeout: u64,
+) -> ProcessFuture {
+    let dest = addr.clone();
+    let build_config = config.clone();
+    let f = GetCircuit::new(addr.clone())
+        .and_then(move |circuit| match circuit {
+            Some(circuit) => Either::A(future::ok(Some(circuit))),
+            None => Either::B(build_with_retry(build_config, dest)),
+        })
+        .then(move |circuit| {
+            let header_policy = req.header_policy().clone();
+            let origin = req.into_origin();
+
+            let error = match circuit {
+                Ok(Some(circuit)) => {
+                    return match Rely::new(origin, &circuit, addr, front, linger_timeout) {
+                        Ok(rely) => {
+                            // The next request on the connection may go to another host.
+                            let f = rely.and_then(move |next| match next {
+                                Some(mut next) => {
+                                    next.set_header_policy(header_policy);
+                                    serve(next, config, read_timeout, linger_timeout)
+                                }
+                                None => ignore(),
+                            });
+
+                            Box::new(f) as ProcessFuture
+                        }
+                        Err(e) => {
+                            log::warn!("couldn't open a stream on the circuit: {}", e);
+
+                            ignore()
+                        }
+                    };
+                }
+                Ok(None) => StreamError::NoCircuit,
+                Err(e) => match e.downcast::<GatewayError>() {
+                    Ok(GatewayError::NoExitAllowed { .. }) => StreamError::Refused,
+                    Ok(e) => {
+                        log::warn!("couldn't build a circuit: {}", e);
+
+                        match e {
+                            GatewayError::BuildTimedOut => StreamError::BuildTimeout,
+                            _ => StreamError::NoCircuit,
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("couldn't build a circuit due to error={:?}", e);
+
+                        StreamError::NoCircuit
+                    }
+                },
+            };
+
+            reply(origin, &addr, &front, error)
+        });
+
+    Box::new(f)
+}
+
+fn reply(origin: Origin, addr: &Addr, front: &Front, error: StreamError) -> ProcessFuture {
+    log::warn!("no stream to {}: {}", addr, error);
+
+    respond(origin, &error.response(front))
+}
+
+// The connection is closed once the response has been flushed.
+fn respond(mut origin: Origin, response: &[u8]) -> ProcessFuture {
+    origin.set_write_delim(Delim::None);
+
+    if origin.write(response).is_err() {
+        return ignore();
+    }
+
+    Box::new(Flush::new(origin).map(|_| ()))
+}
+
+fn process(
+    socket: TcpStream,
+    config: CircuitConfig,
+    read_timeout: u64,
+    linger_timeout: u64,
+    proxy: Proxy,
+    socks_auth: Option<SocksAuth>,
+    header_policy: HeaderPolicy,
+) {
     log::debug!("received new request");
 
-    let request = Request::new_with_timeout(socket, read_timeout);
-    let process = request
+    let mut request = Request::new_with_timeout(socket, read_timeout);
+
+    request.set_proxy(proxy, socks_auth);
+    request.set_header_policy(header_policy);
+
+    let process = serve(request, config, read_timeout, linger_timeout).map_err(|e| {
+        log::error!("gateway error={:?}", e);
+    });
+
+    tokio::spawn(process);
+}
+
+fn serve(
+    request: Request,
+    config: CircuitConfig,
+    read_timeout: u64,
+    linger_timeout: u64,
+) -> ProcessFuture {
+    let f = request
         .into_future()
         .map_err(|(e, _)| e)
         .and_then(move |(ctx, req)| {
@@ -66,84 +406,43 @@ fn process(socket: TcpStream, hops: usize, read_timeout: u64) {
                         }
                         _ => {}
                     },
-                    RequestContext::Http { tls, buf, ip } => {
-                        let route = GetRoute::new(hops);
-
-                        log::debug!("decided the route.");
-
-                        let f = route.and_then(move |nodes| {
-                            if let Some(nodes) = nodes {
-                                let get_pub_keys = nodes
-                                    .iter()
-                                    .map(|n| GetPubKey::new(*n))
-                                    .collect::<Vec<Result<GetPubKey>>>();
-
-                                if get_pub_keys.iter().any(|g| g.is_err()) {
-                                    return ignore();
-                                }
-
-                                let rsa_keys = get_pub_keys
-                                    .into_iter()
-                                    .map(|g| g.unwrap())
-                                    .collect::<Vec<GetPubKey>>();
-
-                                let f = join_all(rsa_keys).and_then(move |rsa_keys| {
-                                    if rsa_keys.iter().any(|r| r.is_none()) {
-                                        return ignore();
-                                    }
-
-                                    log::debug!("received public keys.");
-
-                                    let route_nodes: Vec<RouteNode> = rsa_keys
-                                        .into_iter()
-                                        .enumerate()
-                                        .map(|(idx, r)| {
-                                            if idx < nodes.len() - 1 {
-                                                RouteNode::new(
-                                                    nodes[idx],
-                                                    nodes[idx + 1],
-                                                    r.unwrap(),
-                                                )
-                                            } else {
-                                                RouteNode::new(nodes[idx], ip, r.unwrap())
-                                            }
-                                        })
-                                        .collect();
-
-                                    let origin =
-                                        Origin::new_with_timeout(req.stream(), read_timeout);
-
-                                    if let Ok(upstream) =
-                                        Upstream::new_with_timeout(nodes[0], read_timeout)
-                                    {
-                                        if let Ok(rely) =
-                                            Rely::new(origin, upstream, route_nodes, &buf, tls)
-                                        {
-                                            return Box::new(rely) as ProcessFuture;
-                                        }
-                                    }
-
-                                    ignore()
-                                });
-
-                                return Box::new(f) as ProcessFuture;
-                            }
-
-                            ignore()
-                        });
+                    RequestContext::Http {
+                        tls,
+                        buf,
+                        addr,
+                        head,
+                    } => {
+                        return circuit(
+                            req,
+                            addr,
+                            Front::Http { tls, buf, head },
+                            config,
+                            read_timeout,
+                            linger_timeout,
+                        );
+                    }
+                    RequestContext::Socks { addr } => {
+                        return circuit(
+                            req,
+                            addr,
+                            Front::Socks,
+                            config,
+                            read_timeout,
+                            linger_timeout,
+                        );
+                    }
+                    RequestContext::BadRequest { error } => {
+                        log::warn!("bad request: {}", error);
 
-                        return Box::new(f) as ProcessFuture;
+                        return respond(req.into_origin(), BAD_REQUEST);
                     }
                 }
             }
 
             ignore()
-        })
-        .map_err(|e| {
-            log::error!("gateway error={:?}", e);
         });
 
-    tokio::spawn(process);
+    Box::new(f)
 }
 
 fn sync(cloud_addr: SocketAddr) {
@@ -152,73 +451,59 @@ fn sync(cloud_addr: SocketAddr) {
             let f = if nodes.len() == 0 {
                 log::debug!("Fetch nodes from cloud...");
 
-                let f = match FetchNodes::new(cloud_addr) {
-                    Ok(f) => {
-                        let f = f.and_then(|res| {
-                            if let Some((ts, nodes)) = res {
-                                Either::A(
-                                    RegisterNodes::new(nodes)
-                                        .join(RecordTs::new(ts))
-                                        .map(|_| ()),
-                                )
-                            } else {
-                                Either::B(future::ok(()))
-                            }
-                        });
-
-                        Either::A(f)
-                    }
-                    Err(e) => {
-                        log::error!("failed to connect to the cloud due to error={:?}", e);
-
+                let f = FetchNodes::new(cloud_addr).and_then(|res| {
+                    if let Some((ts, nodes)) = res {
+                        Either::A(
+                            RegisterNodes::new(nodes)
+                                .join(RecordTs::new(ts))
+                                .map(|_| ()),
+                        )
+                    } else {
                         Either::B(future::ok(()))
                     }
-                };
+                });
 
                 Either::A(f)
             } else {
-                let f = LatestTs::new().and_then(move |ts| match SyncAudit::new(cloud_addr, ts) {
-                    Ok(f) => {
-                        let f = f.and_then(|res| {
-                            if let Some(audit) = res {
-                                let f = if audit.len() > 0 {
-                                    let ts = audit[0].ts;
-                                    let mut f: Box<Future<Item = (), Error = Error> + Send> =
-                                        Box::new(future::ok(()));
-
-                                    for a in audit.into_iter().rev() {
-                                        f = Box::new(f.and_then(move |_| match a.state {
-                                            NodeState::ACTIVE => {
-                                                log::info!("ACTIVE: {} ({})", a.addr, a.version);
-                                                Either::A(RegisterNode::new(a))
-                                            }
-                                            NodeState::PENDING_DELETE => {
-                                                log::info!("DELETE: {} ({})", a.addr, a.version);
-                                                Either::B(RemoveNode::new(a.addr))
-                                            }
-                                        }));
-                                    }
-
-                                    let f = f.and_then(move |_| RecordTs::new(ts));
-
-                                    Either::A(f)
-                                } else {
-                                    Either::B(future::ok(()))
-                                };
+                let f = LatestTs::new().and_then(move |ts| {
+                    SyncAudit::new(cloud_addr, ts).and_then(|res| {
+                        if let Some(audit) = res {
+                            let f = if audit.len() > 0 {
+                                let ts = audit[0].ts;
+                                let mut f: Box<Future<Item = (), Error = Error> + Send> =
+                                    Box::new(future::ok(()));
+
+                                for a in audit.into_iter().rev() {
+                                    f = Box::new(f.and_then(move |_| match a.state {
+                                        NodeState::ACTIVE => {
+                                            log::info!("ACTIVE: {} ({})", a.addr, a.version);
+                                            Either::A(RegisterNode::new(a))
+                                        }
+                                        NodeState::PENDING_DELETE => {
+                                            log::info!("DELETE: {} ({})", a.addr, a.version);
+                                            let addr = a.addr;
+
+                                            Either::B(
+                                                RemoveNode::new(addr)
+                                                    .and_then(move |_| RetireCircuits::via(addr))
+                                                    .map(|_| ()),
+                                            )
+                                        }
+                                    }));
+                                }
+
+                                let f = f.and_then(move |_| RecordTs::new(ts));
 
                                 Either::A(f)
                             } else {
                                 Either::B(future::ok(()))
-                            }
-                        });
-
-                        Either::A(f)
-                    }
-                    Err(e) => {
-                        log::error!("failed to connect to the cloud due to error={:?}", e);
+                            };
 
-                        Either::B(future::ok(()))
-                    }
+                            Either::A(f)
+                        } else {
+                            Either::B(future::ok(()))
+                        }
+                    })
                 });
 
                 Either::B(f)
@@ -236,17 +521,33 @@ fn sync(cloud_addr: SocketAddr) {
     tokio::spawn(f);
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn main_inner(
     addr: SocketAddr,
     cloud_addr: SocketAddr,
-    hops: usize,
+    config: CircuitConfig,
     read_timeout: u64,
+    linger_timeout: u64,
+    proxy: Proxy,
+    socks_auth: Option<SocksAuth>,
+    header_policy: HeaderPolicy,
 ) -> Result<()> {
     let listener = TcpListener::bind(&addr).unwrap();
+    let guards = guard::load(&config.guards.file)?;
+    let config_conn = config.clone();
+    let config_pool = config.clone();
     let tasks = listener
         .incoming()
         .for_each(move |socket| {
-            process(socket, hops, read_timeout);
+            process(
+                socket,
+                config_conn.clone(),
+                read_timeout,
+                linger_timeout,
+                proxy,
+                socks_auth.clone(),
+                header_policy.clone(),
+            );
             Ok(())
         })
         .map_err(|e| {
@@ -260,13 +561,40 @@ pub fn main_inner(
         })
         .map_err(|e| log::error!("failed to get node list due to error={:?}", e));
 
+    // The next tick waits for the circuits to be built so that the pool isn't overfilled.
+    let pool = Interval::new(Instant::now(), Duration::from_secs(1))
+        .map_err(Error::from)
+        .for_each(move |_| {
+            replenish(config_pool.clone()).then(|res| {
+                if let Err(e) = res {
+                    log::error!("failed to build circuits due to error={:?}", e);
+                }
+
+                Ok(())
+            })
+        })
+        .map_err(|e| log::error!("failed to keep the pool of circuits due to error={:?}", e));
+
     let mut runtime = Runtime::new()?;
 
-    log::info!("gateway running on {}", addr);
+    log::info!("gateway running on {} (proxy={:?})", addr, proxy);
+    log::info!(
+        "loaded {} entry guards from {}",
+        guards.len(),
+        config.guards.file.display()
+    );
+
+    runtime.block_on(RegisterGuards::new(guards))?;
     log::info!("start syncing nodes via cloud on {}", cloud_addr);
+    log::info!(
+        "keep {} circuits ready ({} hops)",
+        config.circuits,
+        config.hops
+    );
 
     runtime.spawn(tasks);
     runtime.spawn(sync);
+    runtime.spawn(pool);
 
     if let Err(e) = runtime.shutdown_on_idle().wait() {
         log::error!("shutdown server process due to error={:?}", e);
@@ -1,186 +1,468 @@
-use crate::error::Result;
-use crate::route_node::RouteNode;
+use crate::circuit::{CircuitHandle, Message, CELLS};
+use crate::error::{GatewayError, Result};
+use crate::reply::StreamError;
+use bytes::BytesMut;
+use dytp_connection::http::{BodyReader, RequestHead, ResponseReader};
 use dytp_connection::prelude::*;
+use dytp_connection::socks::Reply;
+use dytp_protocol::addr::Addr;
 use dytp_protocol::delim::Delim;
-use dytp_protocol::method::encrypted;
+use dytp_protocol::method::encrypted::{Cell, Status};
 use failure::Error;
 use futures::prelude::*;
-use tokio::prelude::*;
+use futures::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
 
+#[derive(Debug)]
+pub enum Front {
+    // HTTP proxy request
+    Http {
+        tls: bool,
+        buf: Vec<u8>,
+        head: RequestHead,
+    },
+    // SOCKS5 CONNECT request
+    Socks,
+}
+
+impl Front {
+    fn tls(&self) -> bool {
+        match self {
+            Front::Http { tls, .. } => *tls,
+            Front::Socks => true,
+        }
+    }
+}
+
+//
+// A plain HTTP request and its response.
+// Only one exchange is relayed on a stream, the next request on the client connection
+// is taken by a new stream since it may go to another host.
+//
+#[derive(Debug)]
+struct Exchange {
+    request: BodyReader,
+    response: ResponseReader,
+    keep_alive: bool, // The client wants the connection kept for the next request
+    excess: Vec<u8>,  // Bytes of the next requests received together with the body
+}
+
+impl Exchange {
+    fn new(head: &RequestHead) -> Result<Exchange> {
+        Ok(Exchange {
+            request: BodyReader::new(head.body()?),
+            response: ResponseReader::new(&head.method),
+            keep_alive: head.keep_alive(),
+            excess: Vec::new(),
+        })
+    }
+
+    // After switching protocols, everything from the client goes to the destination.
+    fn reads_client(&self) -> bool {
+        !self.request.is_done() || self.response.is_upgraded()
+    }
+}
+
+//
+// Relays a client connection over a stream of the circuit.
+// Resolves to the next request if the client keeps the connection.
+//
 #[derive(Debug)]
 pub struct Rely {
-    origin: Origin,
-    upstream: Upstream,
-    nodes: Vec<RouteNode>,
-    tls: bool,
-    origin_closed: bool,
-    upstream_closed: bool,
+    origin: Option<Origin>, // Taken by the next request on a persistent connection
+    addr: Addr,
+    stream: u16,
+    circuit: mpsc::Sender<Message>,
+    cells: mpsc::Receiver<Cell>,
+    pending: Option<Message>, // A message waiting for the circuit to take it
+    front: Option<Front>,
+    exchange: Option<Exchange>, // Only for plain HTTP
+    failed: bool,               // The client is being told why the stream couldn't be opened
+    handoff: bool,              // The response has been relayed, the client waits for the next one
+    origin_closed: bool,        // The client has nothing more to send
+    upstream_closed: bool,      // The destination has nothing more to send
+    origin_shutdown: bool,
+    linger_timeout: Duration,
+    linger: Option<Delay>,
 }
 
 impl Rely {
     pub fn new(
         mut origin: Origin,
-        upstream: Upstream,
-        nodes: Vec<RouteNode>,
-        http_buf: &[u8],
-        tls: bool,
+        circuit: &CircuitHandle,
+        addr: Addr,
+        front: Front,
+        linger_timeout: u64,
     ) -> Result<Rely> {
-        if tls {
-            origin.set_read_delim(Delim::None);
-        } else {
-            origin.set_read_delim(Delim::Http);
-        }
+        let exchange = match &front {
+            Front::Http {
+                tls: false, head, ..
+            } => Some(Exchange::new(head)?),
+            _ => None,
+        };
+
+        let (stream, messages) = circuit.open().ok_or(GatewayError::StreamsExhausted)?;
+        let (tx, rx) = mpsc::channel(CELLS);
 
-        origin.set_write_delim(Delim::Http);
+        origin.set_read_delim(Delim::None);
+        origin.set_write_delim(Delim::None);
+
+        let begin = Message::Begin {
+            stream,
+            addr: addr.clone(),
+            tls: front.tls(),
+            cells: tx,
+        };
 
-        let mut rely = Rely {
-            origin,
-            upstream,
-            nodes,
-            tls,
+        Ok(Rely {
+            origin: Some(origin),
+            addr,
+            stream,
+            circuit: messages,
+            cells: rx,
+            pending: Some(begin),
+            front: Some(front),
+            exchange,
+            failed: false,
+            handoff: false,
             origin_closed: false,
             upstream_closed: false,
-        };
+            origin_shutdown: false,
+            linger_timeout: Duration::from_secs(linger_timeout),
+            linger: None,
+        })
+    }
 
-        rely.handshake(http_buf)?;
+    fn origin(&mut self) -> &mut Origin {
+        self.origin.as_mut().unwrap()
+    }
 
-        log::debug!("handshake done!");
+    // Called once the exit node has connected to the destination.
+    fn establish(&mut self, front: Front) -> Result<()> {
+        match front {
+            Front::Http { tls: true, .. } => {
+                self.origin().write(b"HTTP/1.1 200 OK\r\n\r\n")?;
+                self.origin().flush()?;
+            }
+            Front::Http {
+                tls: false, buf, ..
+            } => {
+                self.send(Cell::DATA {
+                    stream: self.stream,
+                    data: buf,
+                });
+            }
+            Front::Socks => {
+                let reply: Vec<u8> = Reply::Succeeded.into();
 
-        Ok(rely)
+                self.origin().write(&reply)?;
+                self.origin().flush()?;
+            }
+        }
+
+        Ok(())
     }
 
-    fn handshake(&mut self, http_buf: &[u8]) -> Result<()> {
-        for (idx, node) in self.nodes.iter().enumerate() {
-            let hop = self.nodes.len() - idx - 1;
-            let method = encrypted::Method::RELY {
-                hop: hop as u8,
-                addr: node.next,
-                tls: self.tls,
-            };
+    // Called when the stream can't be opened, the client gets the reason instead of a bare close.
+    fn fail(&mut self, error: StreamError) {
+        log::warn!("couldn't open a stream to {}: {}", self.addr, error);
+
+        let front = self.front.take().unwrap();
 
-            let h0: Vec<u8> = method.into();
-            let h1 = node.aes_key_iv();
+        self.failed = true;
 
-            self.upstream.write(&node.rsa_encrypt(&h0))?;
-            self.upstream.write(&node.rsa_encrypt(&h1))?;
-            self.upstream.flush()?;
+        if self.origin().write(&error.response(&front)).is_err() {
+            log::debug!("client has gone away");
         }
+    }
+
+    // Only one message is in flight, the client isn't read until the circuit takes it.
+    fn send(&mut self, cell: Cell) {
+        self.pending = Some(Message::Cell(cell));
+    }
 
-        if self.tls {
-            self.origin.write(b"HTTP/1.1 200 OK")?;
-            self.origin.write(b"")?;
-            self.origin.flush()?;
-            self.origin.set_write_delim(Delim::None);
-        } else {
-            // Remove last http delimiter(\r\n)
-            let http_buf = &http_buf[0..http_buf.len() - 2];
+    fn poll_send(&mut self) -> Poll<(), Error> {
+        if let Some(message) = self.pending.take() {
+            match self.circuit.start_send(message) {
+                Ok(AsyncSink::Ready) => {}
+                Ok(AsyncSink::NotReady(message)) => {
+                    self.pending = Some(message);
 
-            self.rely(http_buf)?;
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => return Err(e.into()),
+            }
         }
 
-        Ok(())
+        Ok(Async::Ready(()))
     }
 
-    fn decrypt(&self, payload: &[u8]) -> Vec<u8> {
-        let mut payload = payload.to_owned();
+    fn reads_client(&self) -> bool {
+        self.exchange.as_ref().map_or(true, |e| e.reads_client())
+    }
+
+    // Only the body of the request goes to the stream, the rest waits for the next stream.
+    fn request_body(&mut self, mut payload: BytesMut) -> Result<BytesMut> {
+        if let Some(exchange) = self.exchange.as_mut() {
+            if !exchange.response.is_upgraded() {
+                let n = exchange.request.consume(&payload)?;
 
-        for node in self.nodes.iter() {
-            payload = node.aes_decrypt(&payload);
+                exchange.excess.extend_from_slice(&payload.split_off(n));
+            }
         }
 
-        payload.to_owned()
+        Ok(payload)
     }
 
-    fn rely(&mut self, buf: &[u8]) -> Result<()> {
-        let mut buf = buf.to_vec();
+    // Returns how many bytes of the data belong to the response.
+    fn response(&mut self, data: &[u8]) -> Result<usize> {
+        let exchange = match self.exchange.as_mut() {
+            Some(exchange) => exchange,
+            None => return Ok(data.len()),
+        };
+
+        let n = exchange.response.feed(data)?;
 
-        for node in self.nodes.iter().rev() {
-            buf = node.aes_encrypt(&buf);
+        if n < data.len() {
+            log::debug!("drop {} bytes after the response", data.len() - n);
         }
 
-        self.upstream.write(&buf)?;
-        self.upstream.flush()?;
+        if exchange.response.is_done() {
+            if exchange.keep_alive && exchange.request.is_done() && exchange.response.keep_alive() {
+                self.handoff = true;
+            } else {
+                // The client is closed after the response as the destination would do.
+                self.upstream_closed = true;
 
-        Ok(())
+                if !self.origin_closed {
+                    self.origin_closed = true;
+                    self.send(Cell::END {
+                        stream: self.stream,
+                    });
+                }
+            }
+        }
+
+        Ok(n)
+    }
+
+    // The stream is ended and the client is handed to the next request once the response is sent.
+    fn poll_handoff(&mut self) -> Poll<Option<Request>, Error> {
+        match self.origin().poll_flush() {
+            Ok(Async::Ready(())) => {}
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(_) => return Ok(Async::Ready(None)),
+        }
+
+        let origin = self.origin.take().unwrap();
+        let excess = self.exchange.take().unwrap().excess;
+
+        log::debug!("keep the connection for the next request");
+
+        Ok(Async::Ready(Some(Request::keep_alive(origin, &excess))))
+    }
+
+    // Bounds how long the stream is kept once one of the directions has ended.
+    fn lingered(&mut self) -> bool {
+        if self.linger.is_none() {
+            self.linger = Some(Delay::new(Instant::now() + self.linger_timeout));
+        }
+
+        match self.linger.as_mut().unwrap().poll() {
+            Ok(Async::NotReady) => false,
+            _ => true,
+        }
     }
 }
 
 impl Future for Rely {
-    type Item = ();
+    type Item = Option<Request>;
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let mut notify: bool = false;
+        if self.failed {
+            return match self.origin().poll_flush() {
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                _ => Ok(Async::Ready(None)),
+            };
+        }
 
-        match self.origin.poll() {
-            Ok(Async::Ready(Some(payload))) => {
-                notify = true;
+        if self.handoff {
+            return self.poll_handoff();
+        }
 
-                self.rely(&payload)?;
-            }
-            Ok(Async::Ready(None)) => {
-                self.origin_closed = true;
-            }
-            Ok(Async::NotReady) => {
-                notify = true;
+        // Waits for the exit node to connect to the destination.
+        while self.front.is_some() {
+            match self.poll_send() {
+                Ok(Async::Ready(())) => {}
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(_) => {
+                    self.fail(StreamError::CircuitClosed);
+
+                    return self.poll();
+                }
             }
-            Err(_) => {
-                notify = true;
 
-                self.origin_closed = true;
+            match self.cells.poll() {
+                Ok(Async::Ready(Some(Cell::STATUS { status, .. }))) => {
+                    if status != Status::OK {
+                        self.fail(status.into());
+
+                        return self.poll();
+                    }
+
+                    log::debug!("stream {} opened to {}", self.stream, self.addr);
+
+                    let front = self.front.take().unwrap();
+
+                    self.establish(front)?;
+                }
+                Ok(Async::Ready(Some(_))) => {
+                    log::warn!("stream to {} received an invalid cell", self.addr);
+
+                    self.fail(StreamError::CircuitClosed);
+
+                    return self.poll();
+                }
+                Ok(Async::Ready(None)) | Err(_) => {
+                    self.fail(StreamError::CircuitClosed);
+
+                    return self.poll();
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
             }
         }
 
-        match self.upstream.poll() {
-            Ok(Async::Ready(Some(payload))) => {
-                notify = true;
+        // Both sides are polled until neither of them makes progress.
+        // Each of them has registered a wakeup by then.
+        while !self.origin_closed || !self.upstream_closed {
+            let mut progress: bool = false;
+
+            match self.poll_send() {
+                Ok(Async::Ready(())) => {}
+                Ok(Async::NotReady) => {}
+                Err(_) => {
+                    self.origin_closed = true;
+                    self.upstream_closed = true;
+                }
+            }
 
-                let decrypted = self.decrypt(&payload);
+            // The client is read only while the circuit can take what is read.
+            if !self.origin_closed && self.pending.is_none() && self.reads_client() {
+                match self.origin().poll() {
+                    Ok(Async::Ready(Some(payload))) => {
+                        progress = true;
+
+                        let data = self.request_body(payload)?;
+
+                        if !data.is_empty() {
+                            self.send(Cell::DATA {
+                                stream: self.stream,
+                                data: data.to_vec(),
+                            });
+                        }
+                    }
+                    Ok(Async::Ready(None)) | Err(_) => {
+                        progress = true;
+
+                        // The client has nothing more to send but may still wait for the response.
+                        self.origin_closed = true;
+                        self.send(Cell::END {
+                            stream: self.stream,
+                        });
+                    }
+                    Ok(Async::NotReady) => {}
+                }
+            } else if !self.origin_closed {
+                self.origin().reset_read_timer();
+            }
 
-                self.origin.write(&decrypted)?;
-                self.origin.flush()?;
+            // Cells wait in the channel while the client is slow.
+            if !self.upstream_closed && !self.origin().wb_full() {
+                match self.cells.poll() {
+                    Ok(Async::Ready(Some(Cell::DATA { data, .. }))) => {
+                        progress = true;
+
+                        let n = self.response(&data)?;
+
+                        self.origin().write(&data[..n])?;
+                    }
+                    Ok(Async::Ready(Some(Cell::END { .. }))) => {
+                        progress = true;
+
+                        self.upstream_closed = true;
+
+                        // A response without its length ends here.
+                        if let Some(exchange) = self.exchange.as_mut() {
+                            exchange.response.close();
+                        }
+                    }
+                    Ok(Async::Ready(Some(_))) => {
+                        log::warn!("stream to {} received an invalid cell", self.addr);
+
+                        return Ok(Async::Ready(None));
+                    }
+                    Ok(Async::Ready(None)) | Err(_) => {
+                        log::debug!("circuit closed before the end of the stream");
+
+                        self.origin_closed = true;
+                        self.upstream_closed = true;
+                        self.pending = None;
+                    }
+                    Ok(Async::NotReady) => {}
+                }
             }
-            Ok(Async::Ready(None)) => {
-                self.upstream_closed = true;
+
+            if self.origin().poll_flush().is_err() {
+                log::debug!("client has gone away");
+
+                return Ok(Async::Ready(None));
             }
-            Ok(Async::NotReady) => {
-                notify = true;
+
+            if self.handoff {
+                return self.poll_handoff();
             }
-            Err(_) => {
-                notify = true;
 
-                self.upstream_closed = true;
+            if !progress {
+                break;
             }
         }
 
-        if notify {
-            task::current().notify();
+        // The client gets FIN once everything sent from the destination has been delivered.
+        if self.upstream_closed && !self.origin_shutdown {
+            match self.origin().poll_shutdown() {
+                Ok(Async::Ready(())) => self.origin_shutdown = true,
+                Ok(Async::NotReady) => {}
+                Err(_) => return Ok(Async::Ready(None)),
+            }
         }
 
-        if self.origin_closed && self.origin.wb_remaining() {
-            log::debug!("origin closed but write buffer is remaining");
-            // TODO
+        // END may be still waiting for the circuit to take it.
+        if self.origin_closed && self.upstream_closed && self.origin_shutdown {
+            if self.pending.is_none() {
+                return Ok(Async::Ready(None));
+            }
         }
 
-        if self.origin_closed && self.origin.rb_remaining() {
-            log::debug!("origin closed but read buffer is remaining");
-            // TODO
-        }
+        if (self.origin_closed || self.upstream_closed) && self.lingered() {
+            log::debug!("linger timeout");
 
-        if self.upstream_closed && self.upstream.wb_remaining() {
-            // TODO
-            log::debug!("upstream closed but write buffer is remaining");
+            return Ok(Async::Ready(None));
         }
 
-        if self.upstream_closed && self.upstream.rb_remaining() {
-            // TODO
-            log::debug!("upstream closed but read buffer is remaining");
-        }
+        Ok(Async::NotReady)
+    }
+}
 
-        if self.origin_closed || self.upstream_closed {
-            Ok(Async::Ready(()))
-        } else {
-            Ok(Async::NotReady)
+impl Drop for Rely {
+    // The destination still gets FIN if the client has gone away in the middle of the stream.
+    // A stream whose response has been relayed to a persistent connection is ended here too.
+    fn drop(&mut self) {
+        if !self.origin_closed || self.pending.is_some() {
+            let _ = self.circuit.try_send(Message::Cell(Cell::END {
+                stream: self.stream,
+            }));
         }
     }
 }
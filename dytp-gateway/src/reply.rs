@@ -0,0 +1,92 @@
+use crate::rely::Front;
+use dytp_connection::socks::Reply;
+use dytp_protocol::method::encrypted::Status;
+
+//
+// Why a stream couldn't be opened for the client.
+// HTTP clients get an error response and SOCKS clients get a reply code instead of a bare close.
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamError {
+    NoCircuit,         // No circuit could be built for the destination
+    BuildTimeout,      // No circuit could be built before the deadline
+    CircuitClosed,     // The circuit was torn down before the stream was opened
+    LookupFailure,     // The exit node couldn't resolve the destination
+    ConnectionFailure, // The exit node couldn't connect to the destination
+    Timeout,           // The exit node gave up connecting to the destination
+    Refused,           // The exit nodes don't connect to the destination
+    InvalidAddress,    // The exit node couldn't read the destination
+}
+
+impl From<Status> for StreamError {
+    fn from(status: Status) -> StreamError {
+        match status {
+            Status::LOOKUP_FAILURE => StreamError::LookupFailure,
+            Status::CONNECTION_FAILURE => StreamError::ConnectionFailure,
+            Status::REFUSED => StreamError::Refused,
+            Status::TIMEOUT => StreamError::Timeout,
+            Status::INVALID_ADDRESS => StreamError::InvalidAddress,
+            Status::OK | Status::E => StreamError::CircuitClosed,
+        }
+    }
+}
+
+impl StreamError {
+    fn status_line(self) -> &'static str {
+        match self {
+            StreamError::NoCircuit => "503 Service Unavailable",
+            StreamError::Timeout | StreamError::BuildTimeout => "504 Gateway Timeout",
+            StreamError::InvalidAddress => "400 Bad Request",
+            _ => "502 Bad Gateway",
+        }
+    }
+
+    fn reason(self) -> &'static str {
+        match self {
+            StreamError::NoCircuit => "no circuit is available",
+            StreamError::BuildTimeout => "no circuit could be built in time",
+            StreamError::CircuitClosed => "the circuit was closed",
+            StreamError::LookupFailure => "the destination host couldn't be resolved",
+            StreamError::ConnectionFailure => "the destination refused the connection",
+            StreamError::Timeout => "the destination didn't respond in time",
+            StreamError::Refused => "the exit node refused to connect to the destination",
+            StreamError::InvalidAddress => "the destination address is invalid",
+        }
+    }
+
+    fn socks_reply(self) -> Reply {
+        match self {
+            StreamError::NoCircuit => Reply::NetworkUnreachable,
+            StreamError::CircuitClosed => Reply::GeneralFailure,
+            StreamError::LookupFailure => Reply::HostUnreachable,
+            StreamError::ConnectionFailure => Reply::ConnectionRefused,
+            StreamError::Timeout | StreamError::BuildTimeout => Reply::TtlExpired,
+            StreamError::Refused => Reply::ConnectionNotAllowed,
+            StreamError::InvalidAddress => Reply::AddressTypeNotSupported,
+        }
+    }
+
+    // Bytes written back to the client as is (without any delimiter).
+    pub fn response(self, front: &Front) -> Vec<u8> {
+        match front {
+            Front::Http { .. } => {
+                let body = format!("{}\n", self.reason());
+
+                format!(
+                    "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    self.status_line(),
+                    body.len(),
+                    body
+                )
+                .into_bytes()
+            }
+            Front::Socks => self.socks_reply().into(),
+        }
+    }
+}
+
+impl std::fmt::Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.reason())
+    }
+}
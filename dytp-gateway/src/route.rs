@@ -1,63 +1,222 @@
+use crate::error::GatewayError;
+use crate::failures::{Failures, FAILURES};
+use crate::guard::{Guard, GUARDS};
+use crate::latency::LATENCIES;
+use crate::selection::{exits_to, Diversity, PathSelection, Weighted};
 use dytp_component::audit::Audit;
 use dytp_component::node::Node;
+use dytp_future::lock::{Acquire, Lock};
+use dytp_protocol::addr::Addr;
 use failure::Error;
 use futures::prelude::*;
+use futures::try_ready;
 use lazy_static::lazy_static;
-use rand::seq::SliceRandom;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
-use tokio::prelude::*;
+use std::time::Duration;
 
 lazy_static! {
-    pub static ref NODES: Arc<RwLock<Vec<Node>>> = Arc::new(RwLock::new(Vec::new()));
+    pub static ref NODES: Lock<Vec<Node>> = Lock::new(Vec::new());
 }
 
+//
+// Chooses the nodes of a new circuit.
+// The first hop is always one of the entry guards.
+// Nodes are weighted by their bandwidth and latency unless another selection is given.
+// If the destination is known, a node at its address is never chosen
+// and the last hop is one whose exit policy allows it.
+// Nodes which have failed recently are left out.
+//
+// The exit is chosen first since it's the scarcest, then the guard and the middles.
+// When no node fits a hop under the diversity, the previous choice is taken back and another is tried.
+//
 #[derive(Debug)]
-pub struct GetRoute {
+pub struct GetRoute<S = Weighted> {
     hops: usize,
+    diversity: Diversity,
+    dest: Option<Addr>,
+    selection: S,
+    nodes: Acquire<Vec<Node>>,
+    guards: Acquire<Vec<Guard>>,
+    failures: Acquire<HashMap<SocketAddr, Failures>>,
+    latencies: Acquire<HashMap<SocketAddr, Duration>>,
 }
 
 impl GetRoute {
-    pub fn new(hops: usize) -> GetRoute {
-        GetRoute { hops }
+    pub fn new(hops: usize, diversity: Diversity, dest: Option<Addr>) -> GetRoute {
+        GetRoute::with_selection(hops, diversity, dest, Weighted)
     }
 }
 
-impl Future for GetRoute {
-    type Item = Option<Vec<SocketAddr>>;
+impl<S: PathSelection> GetRoute<S> {
+    pub fn with_selection(
+        hops: usize,
+        diversity: Diversity,
+        dest: Option<Addr>,
+        selection: S,
+    ) -> GetRoute<S> {
+        GetRoute {
+            hops,
+            diversity,
+            dest,
+            selection,
+            nodes: NODES.acquire(),
+            guards: GUARDS.acquire(),
+            failures: FAILURES.acquire(),
+            latencies: LATENCIES.acquire(),
+        }
+    }
+}
+
+impl<S: PathSelection> Future for GetRoute<S> {
+    type Item = Option<Vec<Node>>;
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(nodes) = NODES.try_read() {
-            if nodes.len() < self.hops {
-                log::warn!("gateway doesn't know enough nodes for hops={}", self.hops);
-                log::warn!("wait for a while...");
+        let hops = self.hops;
+        let diversity = self.diversity;
+        let dest = self.dest.as_ref();
+        let avoid = dest.and_then(|d| d.ip());
+
+        // always in this order to avoid a deadlock
+        let nodes = try_ready!(self.nodes.poll_lock());
+        let guards = try_ready!(self.guards.poll_lock());
+        let failures = try_ready!(self.failures.poll_lock());
+        let latencies = try_ready!(self.latencies.poll_lock());
+
+        if nodes.len() < hops {
+            log::warn!("gateway doesn't know enough nodes for hops={}", hops);
+            log::warn!("wait for a while...");
+
+            return Ok(Async::Ready(None));
+        }
+
+        if guards.is_empty() {
+            log::warn!("gateway hasn't chosen entry guards yet");
+
+            return Ok(Async::Ready(None));
+        }
 
-                return Ok(Async::Ready(None));
+        let usable: Vec<&Node> = nodes
+            .iter()
+            .filter(|n| Some(n.addr.ip()) != avoid)
+            .filter(|n| !failures.get(&n.addr).map_or(false, |f| f.is_suspended()))
+            .collect();
+
+        let search = Search {
+            hops,
+            diversity,
+            dest,
+            usable,
+            guards: &guards,
+            selection: &self.selection,
+            latencies: &latencies,
+        };
+
+        if !search.usable.iter().any(|n| search.is_guard(n)) {
+            return Err(GatewayError::NoUsableGuard.into());
+        }
+
+        // Blamed on the exit policies only if no usable node exits to the destination.
+        if let Some(dest) = dest {
+            if !search.usable.iter().any(|n| search.is_exit(n)) {
+                return Err(GatewayError::NoExitAllowed {
+                    addr: dest.to_string(),
+                }
+                .into());
             }
+        }
+
+        let mut route: Vec<Node> = Vec::with_capacity(hops);
+        let mut dead_ends = 0;
+
+        if !search.fill(&mut route, &mut dead_ends) {
+            return Err(GatewayError::NotEnoughDiverseNodes { hops }.into());
+        }
+
+        // Chosen as [exit, guard, middles...], the exit goes last.
+        route.rotate_left(1);
+
+        Ok(Async::Ready(Some(route)))
+    }
+}
+
+// Dead ends tried before giving up, so that a large network without a diverse route is searched in bounded time.
+const MAX_DEAD_ENDS: usize = 256;
+
+struct Search<'a, S> {
+    hops: usize,
+    diversity: Diversity,
+    dest: Option<&'a Addr>,
+    usable: Vec<&'a Node>,
+    guards: &'a [Guard],
+    selection: &'a S,
+    latencies: &'a HashMap<SocketAddr, Duration>,
+}
+
+impl<'a, S: PathSelection> Search<'a, S> {
+    fn is_guard(&self, node: &Node) -> bool {
+        self.guards.iter().any(|g| g.addr == node.addr)
+    }
+
+    fn is_exit(&self, node: &Node) -> bool {
+        self.dest
+            .map(|d| exits_to(&node.exit_policy, d))
+            .unwrap_or(true)
+    }
+
+    // The k-th chosen node is the exit if k is 0, the guard if k is 1 and a middle otherwise.
+    // A single hop is both the exit and the guard.
+    fn fits(&self, k: usize, node: &Node) -> bool {
+        match k {
+            0 if self.hops == 1 => self.is_exit(node) && self.is_guard(node),
+            0 => self.is_exit(node),
+            1 => self.is_guard(node),
+            _ => true,
+        }
+    }
+
+    fn fill(&self, route: &mut Vec<Node>, dead_ends: &mut usize) -> bool {
+        if route.len() == self.hops {
+            return true;
+        }
 
-            let mut rng = &mut rand::thread_rng();
-            let route: Vec<SocketAddr> = nodes
-                .choose_multiple(&mut rng, self.hops as usize)
-                .cloned()
-                .map(|node| node.addr)
-                .collect();
+        let k = route.len();
+        let mut candidates: Vec<&Node> = self
+            .usable
+            .iter()
+            .filter(|n| self.fits(k, n) && self.diversity.allows(route, n))
+            .cloned()
+            .collect();
 
-            Ok(Async::Ready(Some(route)))
-        } else {
-            task::current().notify();
+        while !candidates.is_empty() && *dead_ends < MAX_DEAD_ENDS {
+            let idx = self.selection.choose(&candidates, self.latencies);
 
-            Ok(Async::NotReady)
+            route.push(candidates.swap_remove(idx).clone());
+
+            if self.fill(route, dead_ends) {
+                return true;
+            }
+
+            route.pop();
         }
+
+        *dead_ends += 1;
+
+        false
     }
 }
 
 #[derive(Debug)]
-pub struct GetAllNodes;
+pub struct GetAllNodes {
+    nodes: Acquire<Vec<Node>>,
+}
 
 impl GetAllNodes {
     pub fn new() -> GetAllNodes {
-        GetAllNodes {}
+        GetAllNodes {
+            nodes: NODES.acquire(),
+        }
     }
 }
 
@@ -66,24 +225,24 @@ impl Future for GetAllNodes {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(nodes) = NODES.try_read() {
-            return Ok(Async::Ready(nodes.clone()));
-        }
+        let nodes = try_ready!(self.nodes.poll_lock());
 
-        task::current().notify();
-
-        Ok(Async::NotReady)
+        Ok(Async::Ready(nodes.clone()))
     }
 }
 
 #[derive(Debug)]
 pub struct RegisterNodes {
     nodes: Vec<Node>,
+    ns: Acquire<Vec<Node>>,
 }
 
 impl RegisterNodes {
     pub fn new(nodes: Vec<Node>) -> RegisterNodes {
-        RegisterNodes { nodes }
+        RegisterNodes {
+            nodes,
+            ns: NODES.acquire(),
+        }
     }
 }
 
@@ -92,31 +251,36 @@ impl Future for RegisterNodes {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(mut ns) = NODES.try_write() {
-            ns.clear();
+        let mut ns = try_ready!(self.ns.poll_lock());
 
-            self.nodes.iter().for_each(|node| {
-                log::info!("ADD: {} ({})", node.addr, node.version);
-                ns.push(node.clone());
-            });
+        ns.clear();
 
-            Ok(Async::Ready(()))
-        } else {
-            task::current().notify();
+        self.nodes.iter().for_each(|node| {
+            log::info!(
+                "ADD: {} ({}, {}KB/s)",
+                node.addr,
+                node.version,
+                node.bandwidth
+            );
+            ns.push(node.clone());
+        });
 
-            Ok(Async::NotReady)
-        }
+        Ok(Async::Ready(()))
     }
 }
 
 #[derive(Debug)]
 pub struct RegisterNode {
     audit: Audit,
+    nodes: Acquire<Vec<Node>>,
 }
 
 impl RegisterNode {
     pub fn new(audit: Audit) -> RegisterNode {
-        RegisterNode { audit }
+        RegisterNode {
+            audit,
+            nodes: NODES.acquire(),
+        }
     }
 }
 
@@ -125,28 +289,29 @@ impl Future for RegisterNode {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(mut ns) = NODES.try_write() {
-            if None == ns.iter().find(|n| n.addr == self.audit.addr) {
-                ns.push(self.audit.clone().into());
-            }
+        let addr = self.audit.addr;
+        let mut ns = try_ready!(self.nodes.poll_lock());
 
-            return Ok(Async::Ready(()));
+        if None == ns.iter().find(|n| n.addr == addr) {
+            ns.push(self.audit.clone().into());
         }
 
-        task::current().notify();
-
-        Ok(Async::NotReady)
+        Ok(Async::Ready(()))
     }
 }
 
 #[derive(Debug)]
 pub struct RemoveNode {
     addr: SocketAddr,
+    nodes: Acquire<Vec<Node>>,
 }
 
 impl RemoveNode {
     pub fn new(addr: SocketAddr) -> RemoveNode {
-        RemoveNode { addr }
+        RemoveNode {
+            addr,
+            nodes: NODES.acquire(),
+        }
     }
 }
 
@@ -155,21 +320,129 @@ impl Future for RemoveNode {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(mut ns) = NODES.try_write() {
-            if let Some(idx) = ns
-                .iter()
-                .enumerate()
-                .find(|(_, n)| n.addr == self.addr)
-                .map(|(idx, _)| idx)
-            {
-                ns.remove(idx);
-            }
+        let addr = self.addr;
+        let mut ns = try_ready!(self.nodes.poll_lock());
+
+        if let Some(idx) = ns
+            .iter()
+            .enumerate()
+            .find(|(_, n)| n.addr == addr)
+            .map(|(idx, _)| idx)
+        {
+            ns.remove(idx);
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
 
-            return Ok(Async::Ready(()));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selection::Uniform;
+
+    fn node(addr: &str, family: Option<&str>, exit_policy: &str) -> Node {
+        Node::new(
+            &addr.parse().unwrap(),
+            &"0.1.0".parse().unwrap(),
+            "",
+            1024,
+            family.map(|f| f.to_owned()),
+            exit_policy.parse().unwrap(),
+        )
+    }
+
+    fn guard(node: &Node) -> Guard {
+        Guard {
+            addr: node.addr,
+            fingerprint: node.fingerprint.clone(),
+            added: 0,
         }
+    }
 
-        task::current().notify();
+    fn search<'a>(
+        hops: usize,
+        dest: Option<&'a Addr>,
+        nodes: &'a [Node],
+        guards: &'a [Guard],
+        latencies: &'a HashMap<SocketAddr, Duration>,
+    ) -> Search<'a, Uniform> {
+        Search {
+            hops,
+            diversity: Diversity {
+                subnets: true,
+                families: true,
+            },
+            dest,
+            usable: nodes.iter().collect(),
+            guards,
+            selection: &Uniform,
+            latencies,
+        }
+    }
+
+    #[test]
+    fn chooses_the_exit_and_the_guard_first() {
+        let nodes = vec![
+            node("192.0.2.1:3000", None, "reject:*:*"),
+            node("198.51.100.1:3000", None, "accept:*:80"),
+            node("203.0.113.1:3000", None, "reject:*:*"),
+        ];
+        let guards = vec![guard(&nodes[2])];
+        let latencies = HashMap::new();
+        let dest: Addr = "example.com:80".parse().unwrap();
+        let search = search(3, Some(&dest), &nodes, &guards, &latencies);
+
+        for _ in 0..16 {
+            let mut route = Vec::new();
+            let mut dead_ends = 0;
+
+            assert!(search.fill(&mut route, &mut dead_ends));
+            assert_eq!(route[0].addr, nodes[1].addr);
+            assert_eq!(route[1].addr, nodes[2].addr);
+            assert_eq!(route[2].addr, nodes[0].addr);
+        }
+    }
+
+    #[test]
+    fn takes_back_a_choice_leading_to_a_dead_end() {
+        // The exit may be chosen in the subnet of the only guard, which leaves no guard for the route.
+        let nodes = vec![
+            node("192.0.2.1:3000", None, "accept:*:*"),
+            node("192.0.2.2:3000", None, "accept:*:*"),
+            node("198.51.100.1:3000", Some("a"), "accept:*:*"),
+            node("203.0.113.1:3000", Some("b"), "accept:*:*"),
+        ];
+        let guards = vec![guard(&nodes[0])];
+        let latencies = HashMap::new();
+        let search = search(3, None, &nodes, &guards, &latencies);
+
+        for _ in 0..16 {
+            let mut route = Vec::new();
+            let mut dead_ends = 0;
+
+            assert!(search.fill(&mut route, &mut dead_ends));
+            assert_eq!(route[1].addr, nodes[0].addr);
+            assert!(route.iter().all(|n| n.addr != nodes[1].addr));
+        }
+    }
 
-        Ok(Async::NotReady)
+    #[test]
+    fn gives_up_without_a_diverse_route() {
+        let nodes = vec![
+            node("192.0.2.1:3000", None, "accept:*:*"),
+            node("192.0.2.2:3000", None, "accept:*:*"),
+            node("198.51.100.1:3000", Some("a"), "accept:*:*"),
+            node("203.0.113.1:3000", Some("a"), "accept:*:*"),
+        ];
+        let guards: Vec<Guard> = nodes.iter().map(guard).collect();
+        let latencies = HashMap::new();
+        let search = search(4, None, &nodes, &guards, &latencies);
+        let mut route = Vec::new();
+        let mut dead_ends = 0;
+
+        assert!(!search.fill(&mut route, &mut dead_ends));
+        assert!(route.is_empty());
+        assert!(dead_ends > 0);
     }
 }
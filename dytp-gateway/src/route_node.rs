@@ -1,61 +1,58 @@
-use openssl::pkey::Public;
-use openssl::rand::rand_bytes;
-use openssl::rsa::Padding;
-use openssl::rsa::Rsa;
-use openssl::symm::{decrypt, encrypt, Cipher};
+use crate::error::Result;
+use dytp_protocol::addr::Addr;
+use dytp_protocol::crypto::{HopKeys, NtorClient, NtorKey};
+use dytp_protocol::link;
+use dytp_protocol::method::plain;
 use std::net::SocketAddr;
 
 #[derive(Debug)]
 pub struct RouteNode {
     pub addr: SocketAddr,
-    pub next: SocketAddr,
-    pub rsa: Rsa<Public>,
-    pub aes: (Vec<u8>, Vec<u8>),
+    pub next: Option<Addr>, // None for the exit node
+    pub ntor_key: NtorKey,
+    pub link: Vec<u8>, // Keys of the link from the previous hop
+    ntor: Option<NtorClient>,
+    keys: Option<HopKeys>,
 }
 
 impl RouteNode {
-    pub fn new(addr: SocketAddr, next: SocketAddr, rsa: Rsa<Public>) -> RouteNode {
-        let aes = create_aes_key();
-
-        RouteNode {
+    pub fn new(addr: SocketAddr, next: Option<Addr>, ntor_key: NtorKey) -> Result<RouteNode> {
+        Ok(RouteNode {
             addr,
             next,
-            rsa,
-            aes,
-        }
+            ntor_key,
+            link: link::link_keys()?,
+            ntor: None,
+            keys: None,
+        })
     }
 
-    pub fn rsa_encrypt(&self, data: &[u8]) -> Vec<u8> {
-        let mut buf = vec![0; self.rsa.size() as usize];
+    // Starts the handshake, returns a CREATE to be sent to the node.
+    pub fn create(&mut self) -> Result<Vec<u8>> {
+        let ntor = NtorClient::new(self.ntor_key.clone())?;
+        let create = plain::ToNode::CREATE {
+            onionskin: ntor.onionskin(),
+        };
 
-        self.rsa
-            .public_encrypt(data, &mut buf, Padding::PKCS1)
-            .unwrap();
+        self.ntor = Some(ntor);
 
-        buf
+        create.encode()
     }
 
-    pub fn aes_encrypt(&self, data: &[u8]) -> Vec<u8> {
-        encrypt(Cipher::aes_256_cbc(), &self.aes.0, Some(&self.aes.1), data).unwrap()
-    }
+    // Completes the handshake with the reply to the CREATE.
+    pub fn created(&mut self, created: &[u8]) -> Result<()> {
+        if let Some(ntor) = self.ntor.take() {
+            self.keys = Some(ntor.complete(created)?);
+        }
 
-    pub fn aes_decrypt(&self, data: &[u8]) -> Vec<u8> {
-        decrypt(Cipher::aes_256_cbc(), &self.aes.0, Some(&self.aes.1), data).unwrap()
+        Ok(())
     }
 
-    pub fn aes_key_iv(&self) -> Vec<u8> {
-        let mut k = self.aes.0.clone();
-        k.extend_from_slice(&self.aes.1);
-        k
+    pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        self.keys.as_mut().unwrap().forward.seal(data)
     }
-}
-
-fn create_aes_key() -> (Vec<u8>, Vec<u8>) {
-    let mut key = [0; 32];
-    let mut iv = [0; 16];
 
-    rand_bytes(&mut key).unwrap();
-    rand_bytes(&mut iv).unwrap();
-
-    (key.to_vec(), iv.to_vec())
+    pub fn open(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.keys.as_mut().unwrap().backward.open(data)
+    }
 }
@@ -0,0 +1,205 @@
+use dytp_component::exit_policy::ExitPolicy;
+use dytp_component::node::{Node, MAX_BANDWIDTH};
+use dytp_protocol::addr::Addr;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+// Nodes answering faster than this aren't preferred any more.
+const REFERENCE_LATENCY_MS: u128 = 50;
+
+// Bandwidths advertised over this many times the median of the candidates are taken as that.
+const MEDIAN_MULTIPLE: u128 = 4;
+
+//
+// Decides which node to take for the next hop of a circuit.
+// Candidates are never empty and already satisfy the diversity of the circuit.
+// Latencies are known only for the nodes this gateway has reached so far.
+//
+pub trait PathSelection {
+    fn choose(&self, candidates: &[&Node], latencies: &HashMap<SocketAddr, Duration>) -> usize;
+}
+
+//
+// Every node is chosen with the same probability.
+//
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Uniform;
+
+impl PathSelection for Uniform {
+    fn choose(&self, candidates: &[&Node], _: &HashMap<SocketAddr, Duration>) -> usize {
+        rand::thread_rng().gen_range(0, candidates.len() as u32) as usize
+    }
+}
+
+//
+// Nodes are chosen in proportion to the bandwidth they advertise,
+// divided by how much slower than the reference they answer.
+// Bandwidths are capped near the median so that a node can't attract most of the circuits by lying.
+//
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Weighted;
+
+impl Weighted {
+    // Nodes advertising no bandwidth are still chosen, but rarely.
+    fn bandwidth(node: &Node) -> u128 {
+        u128::from(node.bandwidth.min(MAX_BANDWIDTH).max(1))
+    }
+
+    fn cap(candidates: &[&Node]) -> u128 {
+        let mut bandwidths: Vec<u128> = candidates.iter().map(|n| Weighted::bandwidth(n)).collect();
+
+        bandwidths.sort();
+
+        bandwidths[bandwidths.len() / 2] * MEDIAN_MULTIPLE
+    }
+
+    fn weight(node: &Node, cap: u128, latencies: &HashMap<SocketAddr, Duration>) -> u128 {
+        let bandwidth = Weighted::bandwidth(node).min(cap);
+
+        match latencies.get(&node.addr) {
+            Some(latency) => {
+                let ms = latency.as_millis().max(REFERENCE_LATENCY_MS);
+
+                (bandwidth * REFERENCE_LATENCY_MS / ms).max(1)
+            }
+            None => bandwidth,
+        }
+    }
+}
+
+impl PathSelection for Weighted {
+    fn choose(&self, candidates: &[&Node], latencies: &HashMap<SocketAddr, Duration>) -> usize {
+        let cap = Weighted::cap(candidates);
+        let weights: Vec<u128> = candidates
+            .iter()
+            .map(|n| Weighted::weight(n, cap, latencies))
+            .collect();
+        let total: u128 = weights.iter().sum();
+
+        // Scales a random u32 into [0, total).
+        let mut point = (u128::from(rand::thread_rng().gen::<u32>()) * total) >> 32;
+
+        for (idx, weight) in weights.iter().enumerate() {
+            if point < *weight {
+                return idx;
+            }
+
+            point -= weight;
+        }
+
+        candidates.len() - 1
+    }
+}
+
+//
+// Which nodes may not share a circuit.
+//
+#[derive(Clone, Copy, Debug)]
+pub struct Diversity {
+    pub subnets: bool,  // No two hops in the same IPv4 /16 or IPv6 /32
+    pub families: bool, // No two hops in the same family declared by the operators
+}
+
+impl Diversity {
+    pub fn allows(&self, route: &[Node], node: &Node) -> bool {
+        route.iter().all(|hop| {
+            if hop.addr == node.addr {
+                return false;
+            }
+
+            if self.subnets && same_subnet(hop.addr.ip(), node.addr.ip()) {
+                return false;
+            }
+
+            if self.families && hop.family.is_some() && hop.family == node.family {
+                return false;
+            }
+
+            true
+        })
+    }
+}
+
+fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..2] == b.octets()[..2],
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.segments()[..2] == b.segments()[..2],
+        _ => false,
+    }
+}
+
+// Hostnames are resolved by the exit node, so they're allowed unless the port is rejected for any address.
+pub fn exits_to(exit_policy: &ExitPolicy, addr: &Addr) -> bool {
+    match addr.ip() {
+        Some(ip) => exit_policy.allows(ip, addr.port()),
+        None => exit_policy.may_allow(addr.port()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(addr: &str, family: Option<&str>) -> Node {
+        Node::new(
+            &addr.parse().unwrap(),
+            &"0.1.0".parse().unwrap(),
+            "",
+            1024,
+            family.map(|f| f.to_owned()),
+            "accept:*:*".parse().unwrap(),
+        )
+    }
+
+    const BOTH: Diversity = Diversity {
+        subnets: true,
+        families: true,
+    };
+
+    const NONE: Diversity = Diversity {
+        subnets: false,
+        families: false,
+    };
+
+    #[test]
+    fn never_allows_the_same_node_twice() {
+        let route = vec![node("192.0.2.1:3000", None)];
+
+        assert!(!NONE.allows(&route, &node("192.0.2.1:3000", None)));
+        assert!(NONE.allows(&route, &node("192.0.2.1:3001", None)));
+    }
+
+    #[test]
+    fn separates_subnets() {
+        let route = vec![
+            node("192.0.2.1:3000", None),
+            node("[2001:db8::1]:3000", None),
+        ];
+
+        assert!(!BOTH.allows(&route, &node("192.0.200.1:3000", None)));
+        assert!(BOTH.allows(&route, &node("192.1.2.1:3000", None)));
+        assert!(!BOTH.allows(&route, &node("[2001:db8:ffff::1]:3000", None)));
+        assert!(BOTH.allows(&route, &node("[2001:db9::1]:3000", None)));
+
+        // Addresses of different families are never in the same subnet.
+        assert!(BOTH.allows(&route, &node("[::ffff:192.0.2.2]:3000", None)));
+        assert!(NONE.allows(&route, &node("192.0.2.2:3000", None)));
+    }
+
+    #[test]
+    fn separates_families() {
+        let route = vec![
+            node("192.0.2.1:3000", Some("a")),
+            node("198.51.100.1:3000", None),
+        ];
+
+        assert!(!BOTH.allows(&route, &node("203.0.113.1:3000", Some("a"))));
+        assert!(BOTH.allows(&route, &node("203.0.113.1:3000", Some("b"))));
+        assert!(NONE.allows(&route, &node("203.0.113.1:3000", Some("a"))));
+
+        // Nodes without a family don't share one.
+        assert!(BOTH.allows(&route, &node("203.0.113.1:3000", None)));
+    }
+}
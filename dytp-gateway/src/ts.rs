@@ -1,18 +1,22 @@
+use dytp_future::lock::{Acquire, Lock};
 use failure::Error;
 use futures::prelude::*;
+use futures::try_ready;
 use lazy_static::lazy_static;
-use std::sync::{Arc, RwLock};
-use tokio::prelude::*;
 
 lazy_static! {
-    pub static ref LATEST_TS: Arc<RwLock<i64>> = Arc::new(RwLock::new(0));
+    pub static ref LATEST_TS: Lock<i64> = Lock::new(0);
 }
 
-pub struct LatestTs;
+pub struct LatestTs {
+    latest_ts: Acquire<i64>,
+}
 
 impl LatestTs {
     pub fn new() -> LatestTs {
-        LatestTs {}
+        LatestTs {
+            latest_ts: LATEST_TS.acquire(),
+        }
     }
 }
 
@@ -21,23 +25,23 @@ impl Future for LatestTs {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(latest_ts) = LATEST_TS.read() {
-            return Ok(Async::Ready(*latest_ts));
-        }
-
-        task::current().notify();
+        let latest_ts = try_ready!(self.latest_ts.poll_lock());
 
-        Ok(Async::NotReady)
+        Ok(Async::Ready(*latest_ts))
     }
 }
 
 pub struct RecordTs {
     ts: i64,
+    latest_ts: Acquire<i64>,
 }
 
 impl RecordTs {
     pub fn new(ts: i64) -> RecordTs {
-        RecordTs { ts }
+        RecordTs {
+            ts,
+            latest_ts: LATEST_TS.acquire(),
+        }
     }
 }
 
@@ -46,14 +50,10 @@ impl Future for RecordTs {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(mut latest_ts) = LATEST_TS.write() {
-            *latest_ts = self.ts;
-
-            return Ok(Async::Ready(()));
-        }
+        let mut latest_ts = try_ready!(self.latest_ts.poll_lock());
 
-        task::current().notify();
+        *latest_ts = self.ts;
 
-        Ok(Async::NotReady)
+        Ok(Async::Ready(()))
     }
 }
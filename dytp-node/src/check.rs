@@ -1,27 +1,24 @@
-use crate::error::Result;
 use dytp_component::node_state::NodeState;
-use dytp_connection::prelude::*;
+use dytp_future::query::Query;
 use dytp_protocol::method::plain;
 use failure::Error;
 use futures::prelude::*;
 use std::net::SocketAddr;
 use std::str::FromStr;
-use tokio::prelude::*;
 
 #[derive(Debug)]
 pub struct Check {
-    upstream: Upstream,
+    query: Query,
 }
 
 impl Check {
-    pub fn new(global_addr: SocketAddr, cloud_addr: SocketAddr) -> Result<Check> {
-        let mut upstream = Upstream::new(cloud_addr)?;
-        let buf: Vec<u8> = plain::ToCloud::CHECK { addr: global_addr }.into();
-
-        upstream.write(&buf)?;
-        upstream.flush()?;
-
-        Ok(Check { upstream })
+    pub fn new(global_addr: SocketAddr, cloud_addr: SocketAddr) -> Check {
+        Check {
+            query: Query::new(
+                cloud_addr,
+                plain::ToCloud::CHECK { addr: global_addr }.encode(),
+            ),
+        }
     }
 }
 
@@ -30,24 +27,20 @@ impl Future for Check {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match self.upstream.poll()? {
-            Async::Ready(state) => {
-                let state = state
-                    .map(|s| std::str::from_utf8(&s).unwrap().to_owned())
-                    .as_ref()
-                    .and_then(|s| {
-                        if s == "E" {
-                            None
-                        } else {
-                            Some(NodeState::from_str(s).unwrap())
-                        }
-                    });
+        match self.query.poll()? {
+            Async::Ready(Some(s)) => {
+                let s = std::str::from_utf8(&s)?;
 
-                return Ok(Async::Ready(state));
+                if s == "E" {
+                    return Ok(Async::Ready(None));
+                }
+
+                return Ok(Async::Ready(Some(NodeState::from_str(s)?)));
+            }
+            Async::Ready(None) => {
+                return Ok(Async::Ready(None));
             }
             Async::NotReady => {
-                task::current().notify();
-
                 return Ok(Async::NotReady);
             }
         }
@@ -0,0 +1,56 @@
+use crate::error::{NodeError, Result};
+use crate::state::State;
+use dytp_connection::prelude::*;
+use dytp_protocol::crypto::{ntor_server, HopKeys};
+use dytp_protocol::delim::Delim;
+use dytp_protocol::method::encrypted;
+use failure::Error;
+use futures::prelude::*;
+use futures::try_ready;
+use std::sync::Arc;
+
+//
+// Node side of the circuit handshake.
+// Replies to the CREATE, then waits for the RELY telling where to extend the circuit.
+//
+#[derive(Debug)]
+pub struct Create {
+    origin: Option<Origin>,
+    keys: Option<HopKeys>,
+}
+
+impl Create {
+    pub fn new(state: Arc<State>, mut origin: Origin, onionskin: &[u8]) -> Result<Create> {
+        origin.set_read_delim(Delim::Dytp);
+        origin.set_write_delim(Delim::Dytp);
+
+        let (created, keys) = ntor_server(&state.ntor_key(), &state.onion_key, onionskin)?;
+
+        origin.write(&created)?;
+        origin.flush()?;
+
+        Ok(Create {
+            origin: Some(origin),
+            keys: Some(keys),
+        })
+    }
+}
+
+impl Future for Create {
+    type Item = (Origin, HopKeys, encrypted::Method);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match try_ready!(self.origin.as_mut().unwrap().poll()) {
+            Some(payload) => {
+                let mut keys = self.keys.take().unwrap();
+                let method = keys.forward.open(&payload)?;
+                // The gateway speaking another version is told by the error.
+                let method = encrypted::Method::decode(&method)?;
+
+                Ok(Async::Ready((self.origin.take().unwrap(), keys, method)))
+            }
+            None => Err(NodeError::HandshakeFailure.into()),
+        }
+    }
+}
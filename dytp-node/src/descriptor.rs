@@ -0,0 +1,11 @@
+use dytp_component::exit_policy::ExitPolicy;
+
+//
+// What the node publishes about itself when it joins the cloud.
+//
+#[derive(Debug, Clone)]
+pub struct Descriptor {
+    pub bandwidth: u64,          // Bandwidth the node can relay as KB/s
+    pub family: Option<String>,  // Shared by the nodes of the same operator
+    pub exit_policy: ExitPolicy, // Destinations the node connects to as the exit
+}
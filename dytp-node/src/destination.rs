@@ -0,0 +1,195 @@
+use dytp_component::exit_policy::ExitPolicy;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+//
+// Decides which destinations the exit node connects to.
+// Internal addresses and the node itself are refused whatever the exit policy says
+// so that clients can't reach into the network the node runs in.
+//
+#[derive(Debug)]
+pub struct Destinations {
+    exit_policy: ExitPolicy,
+    listeners: Vec<SocketAddr>, // Where the node itself accepts connections
+    allow_internal: bool,       // Only for test networks
+}
+
+impl Destinations {
+    pub fn new(
+        exit_policy: ExitPolicy,
+        listeners: Vec<SocketAddr>,
+        allow_internal: bool,
+    ) -> Destinations {
+        Destinations {
+            exit_policy,
+            listeners,
+            allow_internal,
+        }
+    }
+
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        let ip = canonical(addr.ip());
+
+        self.allows_relay(addr) && self.exit_policy.allows(ip, addr.port())
+    }
+
+    // The next hop of a circuit isn't an exit destination, so the exit policy doesn't apply.
+    pub fn allows_relay(&self, addr: &SocketAddr) -> bool {
+        let ip = canonical(addr.ip());
+
+        if self.is_listener(ip, addr.port()) {
+            return false;
+        }
+
+        self.allow_internal || !is_internal(ip)
+    }
+
+    // A listener on the unspecified address is reached via any local address.
+    fn is_listener(&self, ip: IpAddr, port: u16) -> bool {
+        self.listeners.iter().any(|l| {
+            l.port() == port
+                && (l.ip() == ip
+                    || (l.ip().is_unspecified() && (ip.is_loopback() || ip.is_unspecified())))
+        })
+    }
+}
+
+// IPv6 addresses embedding an IPv4 address are checked as the IPv4 address:
+// IPv4-mapped (::ffff:a.b.c.d), IPv4-compatible (::a.b.c.d), NAT64 (64:ff9b::a.b.c.d) and 6to4 (2002:aabb:ccdd::).
+fn canonical(ip: IpAddr) -> IpAddr {
+    let v6 = match ip {
+        IpAddr::V6(v6) if !v6.is_loopback() && !v6.is_unspecified() => v6,
+        _ => return ip,
+    };
+
+    let embedded =
+        |hi: u16, lo: u16| IpAddr::V4(Ipv4Addr::from(u32::from(hi) << 16 | u32::from(lo)));
+
+    match v6.segments() {
+        [0x2002, hi, lo, _, _, _, _, _] => embedded(hi, lo),
+        [0x64, 0xff9b, 0, 0, 0, 0, hi, lo] => embedded(hi, lo),
+        _ => match v6.to_ipv4() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+    }
+}
+
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_internal_v4(v4),
+        IpAddr::V6(v6) => is_internal_v6(v6),
+    }
+}
+
+// Loopback, RFC1918, link-local (where 169.254.169.254 of cloud metadata is),
+// shared address space (where 100.100.100.200 of cloud metadata is), "this network",
+// multicast and the reserved 240.0.0.0/4 (where the broadcast address is).
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    let o = ip.octets();
+
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || o[0] >= 240
+        || o[0] == 0
+        || (o[0] == 100 && (o[1] & 0xc0) == 64)
+}
+
+// Loopback, unique local (where fd00:ec2::254 of cloud metadata is), link-local and multicast.
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    let s = ip.segments();
+
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || (s[0] & 0xfe00) == 0xfc00
+        || (s[0] & 0xffc0) == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn destinations(allow_internal: bool) -> Destinations {
+        Destinations::new(
+            "reject:*:25,accept:*:*".parse().unwrap(),
+            vec!["0.0.0.0:3000".parse().unwrap()],
+            allow_internal,
+        )
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn allows_public_destinations_by_the_exit_policy() {
+        let d = destinations(false);
+
+        assert!(d.allows(&addr("93.184.216.34:80")));
+        assert!(d.allows(&addr("[2606:2800:220:1::1]:80")));
+        assert!(!d.allows(&addr("93.184.216.34:25")));
+
+        // The next hop isn't an exit destination.
+        assert!(d.allows_relay(&addr("93.184.216.34:25")));
+    }
+
+    #[test]
+    fn refuses_internal_destinations() {
+        let d = destinations(false);
+
+        for s in &[
+            "127.0.0.1:80",
+            "10.1.2.3:80",
+            "172.16.0.1:80",
+            "192.168.1.1:80",
+            "169.254.169.254:80",
+            "100.100.100.200:80",
+            "0.0.0.0:80",
+            "224.0.0.1:80",
+            "240.0.0.1:80",
+            "255.255.255.255:80",
+            "[::1]:80",
+            "[::]:80",
+            "[fd00:ec2::254]:80",
+            "[fe80::1]:80",
+            "[ff02::1]:80",
+        ] {
+            assert!(!d.allows(&addr(s)), "{}", s);
+            assert!(!d.allows_relay(&addr(s)), "{}", s);
+        }
+    }
+
+    #[test]
+    fn checks_embedded_ipv4_addresses() {
+        let d = destinations(false);
+
+        for s in &[
+            "[::ffff:127.0.0.1]:80",
+            "[::ffff:10.0.0.1]:80",
+            "[::169.254.169.254]:80",
+            "[64:ff9b::a9fe:a9fe]:80",
+            "[64:ff9b::7f00:1]:80",
+            "[2002:a9fe:a9fe::]:80",
+            "[2002:c0a8:101:1::1]:80",
+        ] {
+            assert!(!d.allows(&addr(s)), "{}", s);
+        }
+
+        assert!(d.allows(&addr("[::ffff:93.184.216.34]:80")));
+        assert!(d.allows(&addr("[64:ff9b::5db8:d822]:80")));
+        assert!(d.allows(&addr("[2002:5db8:d822::]:80")));
+        assert!(!d.allows(&addr("[64:ff9b::5db8:d822]:25")));
+    }
+
+    #[test]
+    fn refuses_the_node_itself() {
+        let d = destinations(true);
+
+        assert!(d.allows(&addr("127.0.0.1:3001")));
+        assert!(!d.allows_relay(&addr("127.0.0.1:3000")));
+        assert!(!d.allows_relay(&addr("0.0.0.0:3000")));
+        assert!(!d.allows_relay(&addr("[::ffff:127.0.0.1]:3000")));
+    }
+}
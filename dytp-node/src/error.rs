@@ -7,4 +7,7 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum NodeError {
     #[fail(display = "failed to join to the cloud")]
     JoiningFailure,
+
+    #[fail(display = "connection closed during the circuit handshake")]
+    HandshakeFailure,
 }
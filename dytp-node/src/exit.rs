@@ -0,0 +1,439 @@
+use crate::destination::Destinations;
+use crate::error::Result;
+use crate::traffic;
+use dytp_connection::prelude::*;
+use dytp_protocol::addr::Addr;
+use dytp_protocol::cover::Cover;
+use dytp_protocol::crypto::HopKeys;
+use dytp_protocol::delim::Delim;
+use dytp_protocol::error::WireError;
+use dytp_protocol::link::{Frame, Link};
+use dytp_protocol::method::encrypted::{Cell, Padding, Schedule, Status};
+use failure::Error;
+use futures::prelude::*;
+use futures::try_ready;
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+// Destinations not resolved and connected within this are reported as TIMEOUT,
+// so are the next hops not accepting the connection (secs).
+pub const CONNECT_TIMEOUT: u64 = 10;
+
+// Hostnames of destinations are resolved here, at the exit node.
+// The lookup blocks the thread, so the pool is told to move the other tasks
+// off this worker meanwhile.
+fn resolve(addr: &Addr) -> Poll<Vec<SocketAddr>, Status> {
+    let (host, port) = match addr {
+        Addr::Socket(addr) => return Ok(Async::Ready(vec![*addr])),
+        Addr::Host { host, port } => (host, *port),
+    };
+
+    let res = tokio_threadpool::blocking(|| {
+        (host.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<SocketAddr>>())
+    });
+
+    match res {
+        Ok(Async::Ready(Ok(addrs))) => {
+            if addrs.is_empty() {
+                log::warn!("no address found for {}", host);
+
+                return Err(Status::LOOKUP_FAILURE);
+            }
+
+            Ok(Async::Ready(addrs))
+        }
+        Ok(Async::Ready(Err(e))) => {
+            log::warn!("failed to lookup {} due to error={:?}", host, e);
+
+            Err(Status::LOOKUP_FAILURE)
+        }
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        Err(e) => {
+            log::warn!("failed to lookup {} due to error={:?}", host, e);
+
+            Err(Status::LOOKUP_FAILURE)
+        }
+    }
+}
+
+//
+// Connects a stream to the destination once its hostname is resolved.
+// Only the resolved addresses the node may connect to are tried.
+//
+#[derive(Debug)]
+struct Open {
+    addr: Addr,
+    destinations: Arc<Destinations>,
+    read_timeout: u64,
+    connect: Option<Connect>,
+}
+
+impl Open {
+    fn new(addr: Addr, destinations: Arc<Destinations>, read_timeout: u64) -> Open {
+        Open {
+            addr,
+            destinations,
+            read_timeout,
+            connect: None,
+        }
+    }
+}
+
+impl Future for Open {
+    type Item = Upstream;
+    type Error = Status;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.connect.is_none() {
+            let addrs: Vec<SocketAddr> = try_ready!(resolve(&self.addr))
+                .into_iter()
+                .filter(|a| self.destinations.allows(a))
+                .collect();
+
+            if addrs.is_empty() {
+                log::warn!("refused to connect to {}", self.addr);
+
+                return Err(Status::REFUSED);
+            }
+
+            self.connect = Some(Connect::new(addrs, self.read_timeout));
+        }
+
+        self.connect
+            .as_mut()
+            .unwrap()
+            .poll()
+            .map_err(|_| Status::CONNECTION_FAILURE)
+    }
+}
+
+#[derive(Debug)]
+struct ExitStream {
+    upstream: Upstream,
+    origin_closed: bool,   // Nothing more is sent toward the destination
+    upstream_closed: bool, // Nothing more is sent back toward the gateway
+    upstream_shutdown: bool,
+    linger: Option<Delay>,
+}
+
+impl ExitStream {
+    // Plain HTTP is relayed as it is too, messages are framed by the gateway.
+    fn new(mut upstream: Upstream) -> ExitStream {
+        upstream.set_read_delim(Delim::None);
+        upstream.set_write_delim(Delim::None);
+
+        ExitStream {
+            upstream,
+            origin_closed: false,
+            upstream_closed: false,
+            upstream_shutdown: false,
+            linger: None,
+        }
+    }
+
+    // The destination gets FIN once everything sent by the gateway has been delivered.
+    fn poll_done(&mut self, linger_timeout: Duration) -> bool {
+        if self.origin_closed && !self.upstream_shutdown {
+            match self.upstream.poll_shutdown() {
+                Ok(Async::NotReady) => {}
+                _ => self.upstream_shutdown = true,
+            }
+        }
+
+        if self.origin_closed && self.upstream_closed && self.upstream_shutdown {
+            return true;
+        }
+
+        if self.origin_closed || self.upstream_closed {
+            if self.linger.is_none() {
+                self.linger = Some(Delay::new(Instant::now() + linger_timeout));
+            }
+
+            return match self.linger.as_mut().unwrap().poll() {
+                Ok(Async::NotReady) => false,
+                _ => true,
+            };
+        }
+
+        false
+    }
+}
+
+//
+// The last hop of the circuit.
+// Streams are opened to destinations by BEGIN cells and multiplexed on the circuit by their ids.
+//
+#[derive(Debug)]
+pub struct Exit {
+    origin: Origin,
+    keys: HopKeys,
+    link: Link, // To the previous hop
+    streams: HashMap<u16, ExitStream>,
+    connecting: HashMap<u16, (Open, Delay)>,
+    destinations: Arc<Destinations>,
+    read_timeout: u64,
+    linger_timeout: Duration,
+    padding: Padding,
+    cover: Cover,
+}
+
+impl Exit {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        mut origin: Origin,
+        keys: HopKeys,
+        link: Link,
+        destinations: Arc<Destinations>,
+        read_timeout: u64,
+        linger_timeout: u64,
+        padding: Padding,
+        cover: Schedule,
+    ) -> Exit {
+        origin.set_read_delim(Delim::Dytp);
+        origin.set_write_delim(Delim::Dytp);
+
+        // The circuit may be idle in the pool of the gateway, only the destinations time out.
+        origin.set_read_timeout(Duration::from_secs(0));
+
+        Exit {
+            origin,
+            keys,
+            link,
+            streams: HashMap::new(),
+            connecting: HashMap::new(),
+            destinations,
+            read_timeout,
+            linger_timeout: Duration::from_secs(linger_timeout),
+            padding,
+            cover: Cover::new(cover),
+        }
+    }
+
+    fn send(&mut self, cell: Cell) -> Result<()> {
+        let keys = &mut self.keys;
+        let link = &mut self.link;
+        let sealed: Vec<Vec<u8>> = cell
+            .encode(self.padding)?
+            .iter()
+            .map(|cell| link.seal(&keys.backward.seal(cell)))
+            .collect();
+
+        self.origin.try_write_all(&sealed)?;
+
+        for cell in sealed.iter() {
+            traffic::sent(cell.len(), false);
+        }
+
+        Ok(())
+    }
+
+    // The destination is resolved and connected in the background, STATUS is sent once it's done.
+    fn begin(&mut self, stream: u16, addr: &Addr) -> Result<()> {
+        if self.streams.contains_key(&stream) || self.connecting.contains_key(&stream) {
+            log::warn!("stream {} is already opened", stream);
+
+            return Ok(());
+        }
+
+        let open = Open::new(addr.clone(), self.destinations.clone(), self.read_timeout);
+        let deadline = Delay::new(Instant::now() + Duration::from_secs(CONNECT_TIMEOUT));
+
+        self.connecting.insert(stream, (open, deadline));
+
+        Ok(())
+    }
+
+    // Returns false if the gateway sent something which can't be a cell.
+    fn receive(&mut self, cell: Cell) -> Result<bool> {
+        match cell {
+            Cell::BEGIN { stream, addr, .. } => self.begin(stream, &addr)?,
+            Cell::DATA { stream, data } => {
+                if let Some(s) = self.streams.get_mut(&stream) {
+                    if !s.origin_closed && s.upstream.write(&data).is_err() {
+                        s.origin_closed = true;
+                        s.upstream_closed = true;
+                    }
+                }
+            }
+            Cell::END { stream } => {
+                if let Some(s) = self.streams.get_mut(&stream) {
+                    s.origin_closed = true;
+                }
+
+                self.connecting.remove(&stream);
+            }
+            Cell::STATUS { .. } | Cell::DESTROY { .. } | Cell::E => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
+
+impl Future for Exit {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let mut progress: bool = false;
+
+            // Cells are read only while every destination can take what is read.
+            if self.streams.values().any(|s| s.upstream.wb_full()) {
+                self.origin.reset_read_timer();
+            } else {
+                match self.origin.poll() {
+                    Ok(Async::Ready(Some(payload))) => {
+                        progress = true;
+
+                        let frame = match self.link.open(&payload) {
+                            Ok(Frame::Cell(frame)) => frame,
+                            Ok(Frame::Dummy) => {
+                                traffic::received(payload.len(), true);
+
+                                continue;
+                            }
+                            Err(e) => {
+                                log::warn!("tear down the circuit: {}", e);
+
+                                return Ok(Async::Ready(()));
+                            }
+                        };
+
+                        let cell = match self.keys.forward.open(&frame) {
+                            Ok(decrypted) => match Cell::decode_padded(&decrypted, self.padding) {
+                                Ok(cell) => cell,
+                                Err(e) => match e.downcast_ref::<WireError>() {
+                                    // Only the stream is refused, the others on the circuit go on.
+                                    Some(WireError::InvalidBegin { stream }) => {
+                                        log::warn!("refused the stream: {}", e);
+
+                                        traffic::received(payload.len(), false);
+                                        self.send(Cell::STATUS {
+                                            stream: *stream,
+                                            status: Status::INVALID_ADDRESS,
+                                        })?;
+
+                                        continue;
+                                    }
+                                    _ => Cell::E,
+                                },
+                            },
+                            Err(e) => {
+                                log::warn!("tear down the circuit: {}", e);
+
+                                return Ok(Async::Ready(()));
+                            }
+                        };
+
+                        traffic::received(payload.len(), false);
+
+                        if !self.receive(cell)? {
+                            log::warn!("tear down the circuit: invalid cell");
+
+                            return Ok(Async::Ready(()));
+                        }
+                    }
+                    Ok(Async::Ready(None)) | Err(_) => {
+                        return Ok(Async::Ready(()));
+                    }
+                    Ok(Async::NotReady) => {}
+                }
+            }
+
+            let ids: Vec<u16> = self.connecting.keys().cloned().collect();
+
+            for stream in ids {
+                let (open, deadline) = self.connecting.get_mut(&stream).unwrap();
+
+                let status = match open.poll() {
+                    Ok(Async::Ready(upstream)) => {
+                        self.streams.insert(stream, ExitStream::new(upstream));
+
+                        Status::OK
+                    }
+                    Ok(Async::NotReady) => match deadline.poll() {
+                        Ok(Async::NotReady) => continue,
+                        _ => {
+                            log::warn!("timed out connecting the stream {}", stream);
+
+                            Status::TIMEOUT
+                        }
+                    },
+                    Err(status) => status,
+                };
+
+                progress = true;
+
+                self.connecting.remove(&stream);
+                self.send(Cell::STATUS { stream, status })?;
+            }
+
+            let ids: Vec<u16> = self.streams.keys().cloned().collect();
+
+            for stream in ids {
+                if self.origin.wb_full() {
+                    break;
+                }
+
+                let s = self.streams.get_mut(&stream).unwrap();
+
+                let cell = if s.upstream_closed {
+                    None
+                } else {
+                    match s.upstream.poll() {
+                        Ok(Async::Ready(Some(payload))) => Some(Cell::DATA {
+                            stream,
+                            data: payload.to_vec(),
+                        }),
+                        Ok(Async::Ready(None)) | Err(_) => {
+                            s.upstream_closed = true;
+
+                            // The destination has finished but may still be reading.
+                            Some(Cell::END { stream })
+                        }
+                        Ok(Async::NotReady) => None,
+                    }
+                };
+
+                if s.upstream.poll_flush().is_err() {
+                    s.origin_closed = true;
+                }
+
+                if let Some(cell) = cell {
+                    progress = true;
+
+                    self.send(cell)?;
+                }
+            }
+
+            // Dummies wait for the previous hop to read what has been sent like the others.
+            if !self.origin.wb_full() && self.cover.poll_due() {
+                progress = true;
+
+                let dummy = self.link.dummy();
+
+                self.origin.write(&dummy)?;
+                traffic::sent(dummy.len(), true);
+            }
+
+            if self.origin.poll_flush().is_err() {
+                return Ok(Async::Ready(()));
+            }
+
+            let linger_timeout = self.linger_timeout;
+
+            self.streams.retain(|_, s| !s.poll_done(linger_timeout));
+
+            if !progress {
+                break;
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
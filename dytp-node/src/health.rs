@@ -1,17 +1,31 @@
+use crate::error::Result;
+use crate::traffic;
 use clap::crate_version;
 use dytp_component::health_resp_node::HealthRespNode;
 use dytp_connection::prelude::*;
+use dytp_protocol::method::plain;
 use failure::Error;
 use futures::prelude::*;
+use futures::try_ready;
 
 #[derive(Debug)]
 pub struct Health {
     origin: Origin,
+    res: Option<Vec<u8>>,
 }
 
 impl Health {
-    pub fn new(origin: Origin) -> Health {
-        Health { origin }
+    pub fn new(origin: Origin) -> Result<Health> {
+        let res = plain::Health::NODE(HealthRespNode::new(
+            crate_version!(),
+            traffic::sent_traffic(),
+            traffic::received_traffic(),
+        ));
+
+        Ok(Health {
+            origin,
+            res: Some(res.encode()?),
+        })
     }
 }
 
@@ -20,10 +34,11 @@ impl Future for Health {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let res: Vec<u8> = HealthRespNode::new(crate_version!()).into();
+        if let Some(res) = self.res.take() {
+            self.origin.write(&res)?;
+        }
 
-        self.origin.write(&res)?;
-        self.origin.flush()?;
+        try_ready!(self.origin.poll_flush());
 
         Ok(Async::Ready(()))
     }
@@ -1,39 +1,44 @@
-use crate::error::{NodeError, Result};
+use crate::descriptor::Descriptor;
+use crate::error::NodeError;
 use crate::state::State;
-use dytp_connection::prelude::*;
+use dytp_future::query::Query;
 use dytp_protocol::method::plain;
 use failure::Error;
 use futures::prelude::*;
 use openssl::rsa::Padding;
 use semver::Version;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
-use tokio::prelude::*;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct Join {
-    state: Arc<RwLock<State>>,
-    upstream: Upstream,
+    state: Arc<State>,
+    query: Query,
 }
 
 impl Join {
     pub fn new(
-        state: Arc<RwLock<State>>,
+        state: Arc<State>,
         global_addr: SocketAddr,
         cloud_addr: SocketAddr,
         version: Version,
-    ) -> Result<Join> {
-        let mut upstream = Upstream::new(cloud_addr)?;
-        let buf: Vec<u8> = plain::ToCloud::JOIN {
+        descriptor: Descriptor,
+    ) -> Join {
+        let fingerprint = state.fingerprint();
+        let buf = plain::ToCloud::JOIN {
             addr: global_addr,
             version,
+            fingerprint,
+            bandwidth: descriptor.bandwidth,
+            family: descriptor.family,
+            exit_policy: descriptor.exit_policy,
         }
-        .into();
+        .encode();
 
-        upstream.write(&buf)?;
-        upstream.flush()?;
-
-        Ok(Join { state, upstream })
+        Join {
+            state,
+            query: Query::new(cloud_addr, buf),
+        }
     }
 }
 
@@ -42,17 +47,14 @@ impl Future for Join {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match self.upstream.poll() {
+        match self.query.poll() {
             Ok(Async::Ready(Some(payload))) => {
-                let state = self.state.read().unwrap();
-                let rsa = &state.rsa;
+                let rsa = &self.state.rsa;
                 let mut buf = vec![0; rsa.size() as usize];
 
-                rsa.private_decrypt(&payload, &mut buf, Padding::PKCS1)
-                    .unwrap();
+                let len = rsa.private_decrypt(&payload, &mut buf, Padding::PKCS1)?;
 
-                self.upstream.write(&buf)?;
-                self.upstream.flush()?;
+                self.query.write(&buf[..len])?;
 
                 return Ok(Async::Ready(()));
             }
@@ -64,8 +66,6 @@ impl Future for Join {
                 return Err(NodeError::JoiningFailure.into());
             }
             Ok(Async::NotReady) => {
-                task::current().notify();
-
                 return Ok(Async::NotReady);
             }
             Err(e) => {
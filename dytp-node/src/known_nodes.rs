@@ -0,0 +1,86 @@
+use dytp_component::node::Node;
+use dytp_future::fetch_nodes::FetchNodes;
+use failure::Error;
+use futures::future::Shared;
+use futures::prelude::*;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+// Secs between the fetches for a next node which isn't known,
+// so that RELYs to made-up addresses don't flood the cloud.
+const MISS_INTERVAL: u64 = 10;
+
+type Fetch = Shared<Box<Future<Item = (), Error = Error> + Send>>;
+
+//
+// Addresses of the nodes registered in the cloud.
+// A circuit is only extended to one of them
+// so that the node can't be used to reach arbitrary hosts as a middle hop.
+//
+pub struct KnownNodes {
+    cloud_addr: SocketAddr,
+    addrs: RwLock<HashSet<SocketAddr>>,
+    last_miss: Mutex<Option<(Instant, Fetch)>>, // The last fetch for a node which wasn't known
+}
+
+impl KnownNodes {
+    pub fn new(cloud_addr: SocketAddr) -> KnownNodes {
+        KnownNodes {
+            cloud_addr,
+            addrs: RwLock::new(HashSet::new()),
+            last_miss: Mutex::new(None),
+        }
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.addrs.read().unwrap().contains(addr)
+    }
+
+    fn update(&self, nodes: &[Node]) {
+        let addrs = nodes.iter().map(|n| n.addr).collect();
+
+        *self.addrs.write().unwrap() = addrs;
+    }
+}
+
+impl std::fmt::Debug for KnownNodes {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("KnownNodes")
+            .field("cloud_addr", &self.cloud_addr)
+            .field("addrs", &self.addrs)
+            .finish()
+    }
+}
+
+// The list is kept as it is when the cloud doesn't answer.
+pub fn refresh(known_nodes: Arc<KnownNodes>) -> impl Future<Item = (), Error = Error> + Send {
+    FetchNodes::new(known_nodes.cloud_addr).map(move |res| {
+        if let Some((_, nodes)) = res {
+            known_nodes.update(&nodes);
+        }
+    })
+}
+
+// The list is fetched at most once in MISS_INTERVAL for nodes which aren't known,
+// the RELYs in the meantime wait for the same fetch.
+pub fn refresh_on_miss(
+    known_nodes: Arc<KnownNodes>,
+) -> impl Future<Item = (), Error = Error> + Send {
+    let mut last_miss = known_nodes.last_miss.lock().unwrap();
+
+    let fetch = match last_miss.as_ref() {
+        Some((at, fetch)) if at.elapsed() < Duration::from_secs(MISS_INTERVAL) => fetch.clone(),
+        _ => {
+            let f: Box<Future<Item = (), Error = Error> + Send> =
+                Box::new(refresh(known_nodes.clone()));
+            let fetch = f.shared();
+
+            *last_miss = Some((Instant::now(), fetch.clone()));
+            fetch
+        }
+    };
+
+    fetch.then(|_| Ok(()))
+}
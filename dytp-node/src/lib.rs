@@ -1,38 +1,243 @@
 pub mod check;
+pub mod create;
+pub mod descriptor;
+pub mod destination;
 pub mod error;
+pub mod exit;
 pub mod health;
 pub mod join;
+pub mod known_nodes;
+pub mod onion_key;
 pub mod pub_key;
 pub mod rely;
 pub mod state;
+pub mod traffic;
 
 use crate::check::Check;
+use crate::create::Create;
+use crate::descriptor::Descriptor;
+use crate::destination::Destinations;
 use crate::error::Result;
+use crate::exit::Exit;
 use crate::health::Health;
 use crate::join::Join;
+use crate::known_nodes::KnownNodes;
+use crate::onion_key::OnionKey;
 use crate::pub_key::PubKey;
 use crate::rely::Rely;
 use crate::state::State;
 use clap::crate_version;
 use dytp_component::node_state::NodeState;
 use dytp_connection::prelude::*;
+use dytp_protocol::addr::Addr;
+use dytp_protocol::crypto::HopKeys;
+use dytp_protocol::delim::Delim;
+use dytp_protocol::link::Link;
 use dytp_protocol::method::{encrypted, plain};
 use failure::Error;
 use futures::future;
 use futures::prelude::*;
-use openssl::rsa::Padding;
 use semver::Version;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Runtime;
-use tokio::timer::Interval;
+use tokio::timer::{Interval, Timeout};
 
 type ProcessFuture = Box<Future<Item = (), Error = Error> + Send>;
 
-fn process(socket: TcpStream, state: Arc<RwLock<State>>, read_timeout: u64) {
-    let origin = Origin::new_with_timeout(socket, read_timeout);
+fn rely(
+    origin: Origin,
+    keys: HopKeys,
+    method: encrypted::Method,
+    destinations: Arc<Destinations>,
+    known_nodes: Arc<KnownNodes>,
+    read_timeout: u64,
+    linger_timeout: u64,
+) -> ProcessFuture {
+    match method {
+        encrypted::Method::RELY {
+            addr,
+            padding,
+            cover,
+            link,
+            next_link,
+        } => {
+            let (link, next_link) = match (Link::far(&link), Link::near(&next_link)) {
+                (Ok(link), Ok(next_link)) => (link, next_link),
+                (Err(e), _) | (_, Err(e)) => {
+                    log::warn!("invalid keys of the links: {}", e);
+
+                    return Box::new(future::ok(()));
+                }
+            };
+
+            // Nodes are registered by their socket addresses, a host name is never one of them.
+            let addr = match addr {
+                Addr::Socket(addr) if destinations.allows_relay(&addr) => addr,
+                _ => {
+                    log::warn!("refuse to rely to {}", addr);
+
+                    return destroy(origin, keys, link, encrypted::Status::REFUSED, padding);
+                }
+            };
+
+            if known_nodes.contains(&addr) {
+                return extend(
+                    origin,
+                    keys,
+                    link,
+                    addr,
+                    next_link,
+                    padding,
+                    cover,
+                    read_timeout,
+                    linger_timeout,
+                );
+            }
+
+            // The next node may have joined after the last fetch.
+            let f = known_nodes::refresh_on_miss(known_nodes.clone()).then(move |_| {
+                if known_nodes.contains(&addr) {
+                    extend(
+                        origin,
+                        keys,
+                        link,
+                        addr,
+                        next_link,
+                        padding,
+                        cover,
+                        read_timeout,
+                        linger_timeout,
+                    )
+                } else {
+                    log::warn!("refuse to rely to {} which is not a node", addr);
+
+                    destroy(origin, keys, link, encrypted::Status::REFUSED, padding)
+                }
+            });
+
+            Box::new(f)
+        }
+        encrypted::Method::EXIT {
+            padding,
+            cover,
+            link,
+        } => match Link::far(&link) {
+            Ok(link) => Box::new(Exit::new(
+                origin,
+                keys,
+                link,
+                destinations,
+                read_timeout,
+                linger_timeout,
+                padding,
+                cover,
+            )),
+            Err(e) => {
+                log::warn!("invalid keys of the link: {}", e);
+
+                Box::new(future::ok(()))
+            }
+        },
+        encrypted::Method::E => {
+            log::warn!("invalid method");
+
+            Box::new(future::ok(()))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn extend(
+    origin: Origin,
+    keys: HopKeys,
+    link: Link,
+    addr: SocketAddr,
+    next_link: Link,
+    padding: encrypted::Padding,
+    cover: encrypted::Schedule,
+    read_timeout: u64,
+    linger_timeout: u64,
+) -> ProcessFuture {
+    let connect = Upstream::new_with_timeout(addr, read_timeout);
+    let connect_timeout = Duration::from_secs(exit::CONNECT_TIMEOUT);
+
+    let f = Timeout::new(connect, connect_timeout).then(move |res| match res {
+        Ok(mut upstream) => match upstream.negotiate() {
+            Ok(()) => Box::new(Rely::new(
+                origin,
+                upstream,
+                keys,
+                link,
+                next_link,
+                cover,
+                linger_timeout,
+            )) as ProcessFuture,
+            Err(_) => destroy(
+                origin,
+                keys,
+                link,
+                encrypted::Status::CONNECTION_FAILURE,
+                padding,
+            ),
+        },
+        Err(e) => {
+            log::warn!("failed to connect to {} due to error={:?}", addr, e);
+
+            let status = if e.is_elapsed() {
+                encrypted::Status::TIMEOUT
+            } else {
+                encrypted::Status::CONNECTION_FAILURE
+            };
+
+            destroy(origin, keys, link, status, padding)
+        }
+    });
+
+    Box::new(f)
+}
+
+// Tells the gateway why the circuit can't be extended beyond this node.
+fn destroy(
+    mut origin: Origin,
+    mut keys: HopKeys,
+    mut link: Link,
+    status: encrypted::Status,
+    padding: encrypted::Padding,
+) -> ProcessFuture {
+    let destroy = encrypted::Cell::DESTROY { status };
+    let cells: Vec<Vec<u8>> = match destroy.encode(padding) {
+        Ok(cells) => cells
+            .iter()
+            .map(|cell| link.seal(&keys.backward.seal(cell)))
+            .collect(),
+        Err(_) => return Box::new(future::ok(())),
+    };
+
+    origin.set_write_delim(Delim::Dytp);
+
+    if origin.try_write_all(&cells).is_err() {
+        return Box::new(future::ok(()));
+    }
+
+    Box::new(Flush::new(origin).map(|_| ()))
+}
+
+fn process(
+    socket: TcpStream,
+    state: Arc<State>,
+    destinations: Arc<Destinations>,
+    known_nodes: Arc<KnownNodes>,
+    read_timeout: u64,
+    linger_timeout: u64,
+) {
+    let mut origin = Origin::new_with_timeout(socket, read_timeout);
+
+    origin.negotiate();
+
     let process = origin
         .into_future()
         .map_err(|(e, _)| e)
@@ -42,7 +247,14 @@ fn process(socket: TcpStream, state: Arc<RwLock<State>>, read_timeout: u64) {
 
                 match plain::Common::from(buf.deref()) {
                     plain::Common::HEALTH => {
-                        return Box::new(Health::new(origin)) as ProcessFuture;
+                        return match Health::new(origin) {
+                            Ok(health) => Box::new(health) as ProcessFuture,
+                            Err(e) => {
+                                log::warn!("failed to encode the health due to {}", e);
+
+                                Box::new(future::ok::<(), Error>(())) as ProcessFuture
+                            }
+                        };
                     }
                     _ => {}
                 }
@@ -51,34 +263,39 @@ fn process(socket: TcpStream, state: Arc<RwLock<State>>, read_timeout: u64) {
                     plain::ToNode::PUB_KEY => {
                         return Box::new(PubKey::new(state.clone(), origin)) as ProcessFuture;
                     }
-                    _ => {}
-                }
-
-                let method = {
-                    let state = state.write().unwrap();
-                    let mut method = vec![0; 2048];
+                    plain::ToNode::ONION_KEY => {
+                        return match OnionKey::new(state.clone(), origin) {
+                            Ok(onion_key) => Box::new(onion_key) as ProcessFuture,
+                            Err(e) => {
+                                log::warn!("failed to sign the onion key due to {}", e);
 
-                    state
-                        .rsa
-                        .private_decrypt(&buf, &mut method, Padding::PKCS1)
-                        .map(move |b| method[0..b].to_owned())
-                };
+                                Box::new(future::ok::<(), Error>(())) as ProcessFuture
+                            }
+                        };
+                    }
+                    plain::ToNode::CREATE { onionskin } => {
+                        return match Create::new(state.clone(), origin, &onionskin) {
+                            Ok(create) => {
+                                Box::new(create.and_then(move |(origin, keys, method)| {
+                                    rely(
+                                        origin,
+                                        keys,
+                                        method,
+                                        destinations,
+                                        known_nodes,
+                                        read_timeout,
+                                        linger_timeout,
+                                    )
+                                })) as ProcessFuture
+                            }
+                            Err(e) => {
+                                log::warn!("failed to create a circuit due to {}", e);
 
-                if let Ok(m) = method {
-                    match encrypted::Method::from(m.as_slice()) {
-                        encrypted::Method::RELY { hop, addr, tls } => {
-                            if let Ok(upstream) = Upstream::new_with_timeout(addr, read_timeout) {
-                                return Box::new(Rely::new(
-                                    state.clone(),
-                                    origin,
-                                    upstream,
-                                    hop,
-                                    tls,
-                                )) as ProcessFuture;
+                                Box::new(future::ok::<(), Error>(())) as ProcessFuture
                             }
-                        }
-                        _ => {}
+                        };
                     }
+                    _ => {}
                 }
             }
 
@@ -92,19 +309,25 @@ fn process(socket: TcpStream, state: Arc<RwLock<State>>, read_timeout: u64) {
 }
 
 fn check(
-    state: Arc<RwLock<State>>,
+    state: Arc<State>,
     global_addr: SocketAddr,
     cloud_addr: SocketAddr,
     version: Version,
+    descriptor: Descriptor,
 ) {
     let check_join = Check::new(global_addr, cloud_addr)
-        .unwrap()
         .and_then(move |state_opt| {
             log::info!("node state={:?}", state_opt);
 
             let f: Box<Future<Item = (), Error = Error> + Send> =
                 if state_opt.is_none() || state_opt.unwrap() == NodeState::PENDING_DELETE {
-                    Box::new(Join::new(state.clone(), global_addr, cloud_addr, version).unwrap())
+                    Box::new(Join::new(
+                        state.clone(),
+                        global_addr,
+                        cloud_addr,
+                        version,
+                        descriptor,
+                    ))
                 } else {
                     Box::new(future::ok(()))
                 };
@@ -118,17 +341,31 @@ fn check(
     tokio::spawn(check_join);
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn main_inner(
     addr: SocketAddr,
     global_addr: SocketAddr,
     cloud_addr: SocketAddr,
     read_timeout: u64,
+    linger_timeout: u64,
+    key_file: PathBuf,
+    descriptor: Descriptor,
+    allow_internal: bool,
 ) -> Result<()> {
-    let state = Arc::new(RwLock::new(State::new()));
+    let state = Arc::new(State::new(&key_file)?);
     let state_check_join = state.clone();
+    let state_fingerprint = state.fingerprint();
     let listener = TcpListener::bind(&addr).unwrap();
     let version: Version = crate_version!().parse()?;
     let version_check_join = version.clone();
+    let descriptor_check_join = descriptor.clone();
+    let destinations = Arc::new(Destinations::new(
+        descriptor.exit_policy.clone(),
+        vec![addr, global_addr],
+        allow_internal,
+    ));
+    let known_nodes = Arc::new(KnownNodes::new(cloud_addr));
+    let known_nodes_refresh = known_nodes.clone();
 
     let check_join = Interval::new(Instant::now(), Duration::from_secs(60))
         .for_each(move |_| {
@@ -137,6 +374,11 @@ pub fn main_inner(
                 global_addr,
                 cloud_addr,
                 version_check_join.clone(),
+                descriptor_check_join.clone(),
+            );
+            tokio::spawn(
+                known_nodes::refresh(known_nodes_refresh.clone())
+                    .map_err(|e| log::error!("fetch nodes error={:?}", e)),
             );
             Ok(())
         })
@@ -145,7 +387,14 @@ pub fn main_inner(
     let tasks = listener
         .incoming()
         .for_each(move |socket| {
-            process(socket, state.clone(), read_timeout);
+            process(
+                socket,
+                state.clone(),
+                destinations.clone(),
+                known_nodes.clone(),
+                read_timeout,
+                linger_timeout,
+            );
             Ok(())
         })
         .map_err(|e| {
@@ -155,6 +404,17 @@ pub fn main_inner(
     let mut runtime = Runtime::new()?;
 
     log::info!("node start running on {}", addr);
+    log::info!("node fingerprint={}", state_fingerprint);
+    log::info!(
+        "node bandwidth={}KB/s family={:?}",
+        descriptor.bandwidth,
+        descriptor.family
+    );
+    log::info!("node exit policy={}", descriptor.exit_policy);
+
+    if allow_internal {
+        log::warn!("node connects to internal destinations as the exit, only for test networks");
+    }
 
     runtime.spawn(check_join);
     runtime.spawn(tasks);
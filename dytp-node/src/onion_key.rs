@@ -0,0 +1,37 @@
+use crate::error::Result;
+use crate::state::State;
+use dytp_connection::prelude::*;
+use failure::Error;
+use futures::prelude::*;
+use futures::try_ready;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct OnionKey {
+    origin: Origin,
+    onion_key: Option<Vec<u8>>,
+}
+
+impl OnionKey {
+    pub fn new(state: Arc<State>, origin: Origin) -> Result<OnionKey> {
+        Ok(OnionKey {
+            origin,
+            onion_key: Some(state.signed_onion_key()?.encode()?),
+        })
+    }
+}
+
+impl Future for OnionKey {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(onion_key) = self.onion_key.take() {
+            self.origin.write(&onion_key)?;
+        }
+
+        try_ready!(self.origin.poll_flush());
+
+        Ok(Async::Ready(()))
+    }
+}
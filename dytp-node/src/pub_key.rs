@@ -2,17 +2,21 @@ use crate::state::State;
 use dytp_connection::prelude::*;
 use failure::Error;
 use futures::prelude::*;
-use std::sync::{Arc, RwLock};
+use futures::try_ready;
+use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct PubKey {
-    state: Arc<RwLock<State>>,
     origin: Origin,
+    pub_key: Option<Vec<u8>>,
 }
 
 impl PubKey {
-    pub fn new(state: Arc<RwLock<State>>, origin: Origin) -> PubKey {
-        PubKey { state, origin }
+    pub fn new(state: Arc<State>, origin: Origin) -> PubKey {
+        PubKey {
+            origin,
+            pub_key: Some(state.rsa.public_key_to_der().unwrap()),
+        }
     }
 }
 
@@ -21,15 +25,12 @@ impl Future for PubKey {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        if let Ok(state) = self.state.try_read() {
-            let pub_key = state.rsa.public_key_to_der().unwrap();
-
+        if let Some(pub_key) = self.pub_key.take() {
             self.origin.write(&pub_key)?;
-            self.origin.flush()?;
-
-            Ok(Async::Ready(()))
-        } else {
-            Ok(Async::NotReady)
         }
+
+        try_ready!(self.origin.poll_flush());
+
+        Ok(Async::Ready(()))
     }
 }
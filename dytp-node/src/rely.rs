@@ -1,123 +1,159 @@
 use crate::error::Result;
-use crate::state::State;
-use bytes::BytesMut;
+use crate::traffic;
 use dytp_connection::prelude::*;
+use dytp_protocol::cover::Cover;
+use dytp_protocol::crypto::HopKeys;
 use dytp_protocol::delim::Delim;
+use dytp_protocol::link::{Frame, Link};
+use dytp_protocol::method::encrypted::Schedule;
 use failure::Error;
 use futures::prelude::*;
-use openssl::rsa::Padding;
-use openssl::symm::{decrypt, encrypt, Cipher};
-use std::sync::{Arc, RwLock};
-use tokio::prelude::*;
-
-#[derive(Debug, PartialEq)]
-enum Handshake {
-    RecvAesKey { hop: u8 },
-    RecvRely { hop: u8 },
-    Done,
-}
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
 
+//
+// A hop in the middle of the circuit.
+// Only a layer of encryption is added or removed, cells are left to the exit node.
+// Dummies are sent to both of the neighbours on the schedule and the ones from them are dropped.
+// The previous hop is sent dummies only after it has sent one, which the gateway does
+// once the whole circuit is extended, so that they don't keep the handshakes going.
+//
 #[derive(Debug)]
 pub struct Rely {
-    state: Arc<RwLock<State>>,
     origin: Origin,
     upstream: Upstream,
-    hop: u8,
-    tls: bool,
-    aes_key_iv: Option<Vec<u8>>,
-    handshake: Handshake,
-    pending_buf: BytesMut,
+    keys: HopKeys,
+    link: Link,      // To the previous hop
+    next_link: Link, // To the next hop
+    cover: Cover,
+    next_cover: Cover,
+    extended: bool, // A dummy has come from the previous hop
     origin_closed: bool,
     upstream_closed: bool,
+    upstream_shutdown: bool,
+    linger_timeout: Duration,
+    linger: Option<Delay>,
 }
 
 impl Rely {
     pub fn new(
-        state: Arc<RwLock<State>>,
         mut origin: Origin,
         mut upstream: Upstream,
-        hop: u8,
-        tls: bool,
+        keys: HopKeys,
+        link: Link,
+        next_link: Link,
+        cover: Schedule,
+        linger_timeout: u64,
     ) -> Rely {
         origin.set_read_delim(Delim::Dytp);
         origin.set_write_delim(Delim::Dytp);
+        upstream.set_read_delim(Delim::Dytp);
+        upstream.set_write_delim(Delim::Dytp);
 
-        if hop == 0 {
-            if tls {
-                upstream.set_read_delim(Delim::None);
-                upstream.set_write_delim(Delim::None);
-            } else {
-                upstream.set_read_delim(Delim::Http);
-                upstream.set_write_delim(Delim::Http);
-                upstream.parse_http = true;
-            }
-        } else {
-            upstream.set_read_delim(Delim::Dytp);
-            upstream.set_write_delim(Delim::Dytp);
-        }
+        // Neither side times out, it's up to the gateway how long the circuit is kept.
+        origin.set_read_timeout(Duration::from_secs(0));
+        upstream.set_read_timeout(Duration::from_secs(0));
 
         Rely {
-            state,
             origin,
             upstream,
-            hop,
-            tls,
-            aes_key_iv: None,
-            handshake: Handshake::RecvAesKey { hop },
-            pending_buf: BytesMut::new(),
+            keys,
+            link,
+            next_link,
+            cover: Cover::new(cover),
+            next_cover: Cover::new(cover),
+            extended: false,
             origin_closed: false,
             upstream_closed: false,
+            upstream_shutdown: false,
+            linger_timeout: Duration::from_secs(linger_timeout),
+            linger: None,
         }
     }
 
-    fn rsa_decrypt(&self, payload: &[u8]) -> Vec<u8> {
-        let state = self.state.read().unwrap();
-        let mut buf = vec![0; state.rsa.size() as usize];
+    // None for a dummy from the previous hop.
+    fn forward(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.link.open(payload)? {
+            Frame::Cell(frame) => {
+                traffic::received(payload.len(), false);
 
-        let d = state
-            .rsa
-            .private_decrypt(payload, &mut buf, Padding::PKCS1)
-            .unwrap();
+                Ok(Some(self.keys.forward.open(&frame)?))
+            }
+            Frame::Dummy => {
+                traffic::received(payload.len(), true);
+                self.extended = true;
 
-        buf[0..d].to_vec()
+                Ok(None)
+            }
+        }
     }
 
-    fn aes_decrypt(&mut self, payload: &[u8]) -> Vec<u8> {
-        self.aes_key_iv
-            .as_ref()
-            .map(|key_iv| {
-                decrypt(
-                    Cipher::aes_256_cbc(),
-                    &key_iv[0..32],
-                    Some(&key_iv[32..48]),
-                    payload,
-                )
-                .unwrap()
-            })
-            .unwrap()
-    }
+    // None for a dummy from the next hop.
+    fn backward(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.next_link.open(payload)? {
+            Frame::Cell(frame) => {
+                traffic::received(payload.len(), false);
 
-    fn aes_encrypt(&mut self, payload: &[u8]) -> Vec<u8> {
-        self.aes_key_iv
-            .as_ref()
-            .map(|key_iv| {
-                encrypt(
-                    Cipher::aes_256_cbc(),
-                    &key_iv[0..32],
-                    Some(&key_iv[32..48]),
-                    payload,
-                )
-                .unwrap()
-            })
-            .unwrap()
+                let sealed = self.keys.backward.seal(&frame);
+
+                Ok(Some(self.link.seal(&sealed)))
+            }
+            Frame::Dummy => {
+                traffic::received(payload.len(), true);
+
+                Ok(None)
+            }
+        }
     }
 
     fn proxy(&mut self, payload: &[u8]) -> Result<()> {
-        self.upstream.write(payload)?;
-        self.upstream.flush()?;
+        let payload = self.next_link.seal(payload);
+
+        self.upstream.write(&payload)?;
+
+        traffic::sent(payload.len(), false);
 
         Ok(())
     }
+
+    // Dummies are sent only while the side takes what is written.
+    fn pad(&mut self) -> Result<bool> {
+        let mut padded = false;
+
+        if !self.origin_closed && !self.origin.wb_full() && self.extended && self.cover.poll_due() {
+            let dummy = self.link.dummy();
+
+            self.origin.write(&dummy)?;
+            traffic::sent(dummy.len(), true);
+            padded = true;
+        }
+
+        // The next hop drops dummies once it has been told the link.
+        if !self.upstream_closed
+            && !self.upstream.wb_full()
+            && self.next_link.is_sealed()
+            && self.next_cover.poll_due()
+        {
+            let dummy = self.next_link.dummy();
+
+            self.upstream.write(&dummy)?;
+            traffic::sent(dummy.len(), true);
+            padded = true;
+        }
+
+        Ok(padded)
+    }
+
+    fn lingered(&mut self) -> bool {
+        if self.linger.is_none() {
+            self.linger = Some(Delay::new(Instant::now() + self.linger_timeout));
+        }
+
+        match self.linger.as_mut().unwrap().poll() {
+            Ok(Async::NotReady) => false,
+            _ => true,
+        }
+    }
 }
 
 impl Future for Rely {
@@ -125,107 +161,116 @@ impl Future for Rely {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let mut notify: bool = false;
-
-        match self.origin.poll() {
-            Ok(Async::Ready(Some(payload))) => {
-                notify = true;
-
-                match self.handshake {
-                    Handshake::RecvAesKey { hop } => {
-                        if hop == self.hop {
-                            self.aes_key_iv = Some(self.rsa_decrypt(&payload));
-                        } else {
-                            self.proxy(&payload)?;
-                        }
+        // Both sides are polled until neither of them makes progress.
+        // Each of them has registered a wakeup on its socket by then.
+        while !self.origin_closed || !self.upstream_closed {
+            let mut progress: bool = false;
+
+            // A side is read only while the other side can take what is read.
+            // Otherwise it waits for the other side to drain its write buffer.
+            if !self.origin_closed {
+                if self.upstream.wb_full() {
+                    self.origin.reset_read_timer();
+                } else {
+                    match self.origin.poll() {
+                        Ok(Async::Ready(Some(payload))) => {
+                            progress = true;
+
+                            match self.forward(&payload) {
+                                Ok(Some(decrypted)) => {
+                                    self.proxy(&decrypted)?;
+                                }
+                                Ok(None) => {}
+                                Err(e) => {
+                                    log::warn!("tear down the circuit: {}", e);
 
-                        if hop == 0 {
-                            self.handshake = Handshake::Done;
-                        } else {
-                            self.handshake = Handshake::RecvRely { hop: hop - 1 };
+                                    return Ok(Async::Ready(()));
+                                }
+                            }
                         }
-                    }
-                    Handshake::RecvRely { hop } => {
-                        self.proxy(&payload)?;
-                        self.handshake = Handshake::RecvAesKey { hop };
-                    }
-                    Handshake::Done => {
-                        let decrypted = self.aes_decrypt(&payload);
+                        Ok(Async::Ready(None)) | Err(_) => {
+                            progress = true;
 
-                        self.proxy(&decrypted)?;
+                            self.origin_closed = true;
+                            self.upstream_closed = true;
+                        }
+                        Ok(Async::NotReady) => {}
                     }
                 }
             }
-            Ok(Async::Ready(None)) => {
-                self.origin_closed = true;
-            }
-            Ok(Async::NotReady) => {
-                notify = true;
-            }
-            Err(_) => {
-                notify = true;
 
-                self.origin_closed = true;
-            }
-        }
+            if !self.upstream_closed {
+                if self.origin.wb_full() {
+                    self.upstream.reset_read_timer();
+                } else {
+                    match self.upstream.poll() {
+                        Ok(Async::Ready(Some(payload))) => {
+                            progress = true;
 
-        match self.upstream.poll() {
-            Ok(Async::Ready(Some(payload))) => {
-                notify = true;
+                            match self.backward(&payload) {
+                                Ok(Some(sealed)) => {
+                                    self.origin.write(&sealed)?;
 
-                match self.handshake {
-                    Handshake::Done => {
-                        let encrypted = self.aes_encrypt(&payload);
+                                    traffic::sent(sealed.len(), false);
+                                }
+                                Ok(None) => {}
+                                Err(e) => {
+                                    log::warn!("tear down the circuit: {}", e);
 
-                        self.origin.write(&encrypted)?;
-                        self.origin.flush()?;
-                    }
-                    _ => {
-                        log::warn!("drop a payload from upstream coming during handshake.");
+                                    return Ok(Async::Ready(()));
+                                }
+                            }
+                        }
+                        Ok(Async::Ready(None)) | Err(_) => {
+                            progress = true;
+
+                            self.origin_closed = true;
+                            self.upstream_closed = true;
+                        }
+                        Ok(Async::NotReady) => {}
                     }
                 }
             }
-            Ok(Async::Ready(None)) => {
-                self.upstream_closed = true;
+
+            if self.pad()? {
+                progress = true;
             }
-            Ok(Async::NotReady) => {
-                notify = true;
+
+            if self.origin.poll_flush().is_err() {
+                return Ok(Async::Ready(()));
             }
-            Err(_) => {
-                notify = true;
 
+            if self.upstream.poll_flush().is_err() {
+                self.origin_closed = true;
                 self.upstream_closed = true;
             }
-        }
 
-        if notify {
-            task::current().notify();
+            if !progress {
+                break;
+            }
         }
 
-        if self.origin_closed && self.origin.wb_remaining() {
-            // TODO
-            log::debug!("origin closed but write buffer is remaining");
+        // The next hop gets FIN once everything read from the circuit has been delivered.
+        if self.origin_closed && !self.upstream_shutdown {
+            match self.upstream.poll_shutdown() {
+                Ok(Async::NotReady) => {}
+                _ => self.upstream_shutdown = true,
+            }
         }
 
-        if self.origin_closed && self.origin.rb_remaining() {
-            // TODO
-            log::debug!("origin closed but read buffer is remaining");
+        if self.origin_closed && self.upstream_closed && self.upstream_shutdown {
+            match self.origin.poll_flush() {
+                Ok(Async::NotReady) => {}
+                _ => return Ok(Async::Ready(())),
+            }
         }
 
-        if self.upstream_closed && self.upstream.wb_remaining() {
-            // TODO
-            log::debug!("upstream closed but write buffer is remaining");
-        }
+        if (self.origin_closed || self.upstream_closed) && self.lingered() {
+            log::debug!("linger timeout");
 
-        if self.upstream_closed && self.upstream.rb_remaining() {
-            // TODO
-            log::debug!("upstream closed but read buffer is remaining");
+            return Ok(Async::Ready(()));
         }
 
-        if self.origin_closed || self.upstream_closed {
-            Ok(Async::Ready(()))
-        } else {
-            Ok(Async::NotReady)
-        }
+        Ok(Async::NotReady)
     }
 }
@@ -1,15 +1,73 @@
-use openssl::pkey::Private;
+use crate::error::Result;
+use dytp_protocol::crypto::{self, NtorKey, SignedOnionKey};
+use openssl::pkey::{PKey, Private};
 use openssl::rsa::Rsa;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
 
 #[derive(Debug)]
 pub struct State {
     pub rsa: Rsa<Private>,
+    pub onion_key: PKey<Private>,
 }
 
 impl State {
-    pub fn new() -> State {
-        let rsa = Rsa::generate(2048).unwrap();
+    pub fn new(key_file: &Path) -> Result<State> {
+        let rsa = load_or_create_identity(key_file)?;
+        let onion_key = PKey::generate_x25519()?;
 
-        State { rsa }
+        Ok(State { rsa, onion_key })
     }
+
+    pub fn fingerprint(&self) -> String {
+        crypto::hex(&self.id())
+    }
+
+    pub fn ntor_key(&self) -> NtorKey {
+        NtorKey {
+            id: self.id(),
+            key: self.onion_key.raw_public_key().unwrap(),
+        }
+    }
+
+    // Signed for each request so that the timestamp tells the gateway it's fresh.
+    pub fn signed_onion_key(&self) -> Result<SignedOnionKey> {
+        SignedOnionKey::sign(&self.rsa, &self.onion_key.raw_public_key()?, crypto::now())
+    }
+
+    fn id(&self) -> Vec<u8> {
+        crypto::fingerprint(&self.rsa.public_key_to_der().unwrap())
+    }
+}
+
+//
+// The identity key survives restarts of the node so that
+// gateways and the cloud can recognize the node by its fingerprint.
+// The onion key is generated for each run.
+//
+fn load_or_create_identity(key_file: &Path) -> Result<Rsa<Private>> {
+    if key_file.exists() {
+        log::info!("load the identity key from {}", key_file.display());
+
+        return Ok(Rsa::private_key_from_pem(&fs::read(key_file)?)?);
+    }
+
+    log::info!("create a new identity key at {}", key_file.display());
+
+    if let Some(dir) = key_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let rsa = Rsa::generate(2048)?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(key_file)?;
+
+    file.write_all(&rsa.private_key_to_pem()?)?;
+
+    Ok(rsa)
 }
@@ -0,0 +1,65 @@
+use dytp_component::health_resp_node::Traffic;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+//
+// Counts the frames of the circuits relied or exited by this node, reported by the health check.
+// Frames on the links to both of the neighbours are counted, so a relied cell is counted on each.
+//
+struct Counter {
+    cells: AtomicU64,
+    bytes: AtomicU64,
+    padding_cells: AtomicU64,
+    padding_bytes: AtomicU64,
+}
+
+impl Counter {
+    const fn new() -> Counter {
+        Counter {
+            cells: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            padding_cells: AtomicU64::new(0),
+            padding_bytes: AtomicU64::new(0),
+        }
+    }
+
+    fn add(&self, bytes: usize, padding: bool) {
+        self.cells.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+
+        if padding {
+            self.padding_cells.fetch_add(1, Ordering::Relaxed);
+            self.padding_bytes
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self) -> Traffic {
+        Traffic {
+            cells: self.cells.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            padding_cells: self.padding_cells.load(Ordering::Relaxed),
+            padding_bytes: self.padding_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static SENT: Counter = Counter::new();
+static RECEIVED: Counter = Counter::new();
+
+// A frame written to either of the neighbours.
+pub fn sent(bytes: usize, padding: bool) {
+    SENT.add(bytes, padding);
+}
+
+// A frame read from either of the neighbours.
+pub fn received(bytes: usize, padding: bool) {
+    RECEIVED.add(bytes, padding);
+}
+
+pub fn sent_traffic() -> Traffic {
+    SENT.get()
+}
+
+pub fn received_traffic() -> Traffic {
+    RECEIVED.get()
+}
@@ -0,0 +1,69 @@
+use std::net::{IpAddr, SocketAddr};
+
+//
+// A destination of the circuit.
+// Hostnames are carried as is and resolved at the exit node
+// so that the gateway never looks up the destination by itself.
+//
+#[derive(Debug, Clone, PartialEq)]
+pub enum Addr {
+    Socket(SocketAddr),
+    Host { host: String, port: u16 },
+}
+
+impl Addr {
+    pub fn port(&self) -> u16 {
+        match self {
+            Addr::Socket(addr) => addr.port(),
+            Addr::Host { port, .. } => *port,
+        }
+    }
+
+    // The address of the destination if it's known without a lookup.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Addr::Socket(addr) => Some(addr.ip()),
+            Addr::Host { host, .. } => host.parse().ok(),
+        }
+    }
+}
+
+impl From<SocketAddr> for Addr {
+    fn from(addr: SocketAddr) -> Addr {
+        Addr::Socket(addr)
+    }
+}
+
+impl std::fmt::Display for Addr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Addr::Socket(addr) => write!(f, "{}", addr),
+            Addr::Host { host, port } => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl std::str::FromStr for Addr {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(Addr::Socket(addr));
+        }
+
+        let mut split = s.rsplitn(2, ':');
+
+        match (split.next().map(|p| p.parse()), split.next()) {
+            // IPv6 addresses must be bracketed, which is handled by `SocketAddr` above.
+            (Some(Ok(port)), Some(host)) if is_valid_host(host) => Ok(Addr::Host {
+                host: host.to_owned(),
+                port,
+            }),
+            _ => s.parse::<SocketAddr>().map(Addr::Socket),
+        }
+    }
+}
+
+pub fn is_valid_host(host: &str) -> bool {
+    !host.is_empty() && !host.contains(|c: char| c == ':' || c.is_whitespace())
+}
@@ -0,0 +1,317 @@
+use crate::delim::Delim;
+use crate::error::ProtocolError;
+use crate::size::Size;
+use bytes::{BufMut, Bytes, BytesMut};
+use failure::Error;
+use tokio::codec::{Decoder, Encoder};
+
+// Payloads without a delimiter are chopped into this size at most
+// so that relaying one of them can't overflow the write buffer of the other side.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+//
+// Frames of the dytp protocol, see `Protocol`.
+//
+#[derive(Debug, Clone, Copy)]
+pub struct DytpCodec {
+    max_frame_size: usize,
+}
+
+impl DytpCodec {
+    pub fn new(max_frame_size: usize) -> DytpCodec {
+        DytpCodec { max_frame_size }
+    }
+
+    // A frame announcing more than the max is refused as soon as its header is read.
+    pub fn read_limit(&self, src: &[u8]) -> usize {
+        let too_large = src.len() >= 4
+            && Size::parse(src)
+                .map(|size| size.0 as usize > self.max_frame_size)
+                .unwrap_or(false);
+
+        if too_large {
+            src.len()
+        } else {
+            4 + self.max_frame_size
+        }
+    }
+
+    pub fn put(&self, buf: &[u8], dst: &mut BytesMut) {
+        dst.reserve(4 + buf.len());
+        dst.put_u32_be(buf.len() as u32);
+        dst.put(buf);
+    }
+}
+
+impl Decoder for DytpCodec {
+    type Item = BytesMut;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, Error> {
+        Ok(crate::parse(src, self.max_frame_size)?.map(|p| (p.1).0))
+    }
+}
+
+impl Encoder for DytpCodec {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Error> {
+        self.put(&item, dst);
+        Ok(())
+    }
+}
+
+//
+// Lines ending with CRLF, which is not a part of the payload.
+//
+#[derive(Debug, Clone, Copy)]
+pub struct HttpCodec {
+    max_frame_size: usize,
+}
+
+impl HttpCodec {
+    pub fn new(max_frame_size: usize) -> HttpCodec {
+        HttpCodec { max_frame_size }
+    }
+
+    pub fn read_limit(&self) -> usize {
+        self.max_frame_size + 2
+    }
+
+    pub fn put(&self, buf: &[u8], dst: &mut BytesMut) {
+        dst.reserve(buf.len() + 2);
+        dst.put(buf);
+        dst.put(b"\r\n" as &[u8]);
+    }
+}
+
+impl Decoder for HttpCodec {
+    type Item = BytesMut;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, Error> {
+        let line = src.windows(2).position(|bytes| bytes == b"\r\n").map(|i| {
+            let mut line = src.split_to(i + 2);
+            line.truncate(i);
+            line
+        });
+
+        // A line never ending is refused the same as a too large frame.
+        if line.is_none() && src.len() > self.max_frame_size {
+            return Err(ProtocolError::FrameTooLarge {
+                size: src.len(),
+                max: self.max_frame_size,
+            }
+            .into());
+        }
+
+        Ok(line)
+    }
+}
+
+impl Encoder for HttpCodec {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Error> {
+        self.put(&item, dst);
+        Ok(())
+    }
+}
+
+//
+// Bytes as they are, read in chunks of CHUNK_SIZE at most.
+//
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawCodec;
+
+impl RawCodec {
+    pub fn read_limit(&self) -> usize {
+        CHUNK_SIZE
+    }
+
+    pub fn put(&self, buf: &[u8], dst: &mut BytesMut) {
+        dst.reserve(buf.len());
+        dst.put(buf);
+    }
+}
+
+impl Decoder for RawCodec {
+    type Item = BytesMut;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        let len = std::cmp::min(src.len(), CHUNK_SIZE);
+
+        Ok(Some(src.split_to(len)))
+    }
+}
+
+impl Encoder for RawCodec {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Error> {
+        self.put(&item, dst);
+        Ok(())
+    }
+}
+
+//
+// One of the codecs chosen by a delimiter, for connections switching it on the way.
+// (e.g. Http for the request head, then None for the tunnel)
+//
+#[derive(Debug, Clone, Copy)]
+pub enum Codec {
+    Dytp(DytpCodec),
+    Http(HttpCodec),
+    Raw(RawCodec),
+}
+
+impl Codec {
+    pub fn new(delim: &Delim, max_frame_size: usize) -> Codec {
+        match delim {
+            Delim::Dytp => Codec::Dytp(DytpCodec::new(max_frame_size)),
+            Delim::Http => Codec::Http(HttpCodec::new(max_frame_size)),
+            Delim::None => Codec::Raw(RawCodec),
+        }
+    }
+
+    // Bytes a reader buffers at most before a frame must be decodable from them,
+    // so that a peer can't make the read buffer grow beyond one frame.
+    pub fn read_limit(&self, src: &[u8]) -> usize {
+        match self {
+            Codec::Dytp(codec) => codec.read_limit(src),
+            Codec::Http(codec) => codec.read_limit(),
+            Codec::Raw(codec) => codec.read_limit(),
+        }
+    }
+
+    // Encodes a payload without copying it into `Bytes` first.
+    pub fn put(&self, buf: &[u8], dst: &mut BytesMut) {
+        match self {
+            Codec::Dytp(codec) => codec.put(buf, dst),
+            Codec::Http(codec) => codec.put(buf, dst),
+            Codec::Raw(codec) => codec.put(buf, dst),
+        }
+    }
+}
+
+impl Decoder for Codec {
+    type Item = BytesMut;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, Error> {
+        match self {
+            Codec::Dytp(codec) => codec.decode(src),
+            Codec::Http(codec) => codec.decode(src),
+            Codec::Raw(codec) => codec.decode(src),
+        }
+    }
+}
+
+impl Encoder for Codec {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Error> {
+        self.put(&item, dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn too_large(e: Error) -> (usize, usize) {
+        match e.downcast::<ProtocolError>() {
+            Ok(ProtocolError::FrameTooLarge { size, max }) => (size, max),
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn decodes_dytp_frames() {
+        let mut codec = DytpCodec::new(16);
+        let mut buf = BytesMut::new();
+
+        codec.encode(Bytes::from(&b"hello"[..]), &mut buf).unwrap();
+        codec.encode(Bytes::from(&b""[..]), &mut buf).unwrap();
+        buf.extend_from_slice(&[0, 0]);
+
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"hello");
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 2);
+    }
+
+    #[test]
+    fn refuses_oversized_dytp_frames() {
+        let mut codec = DytpCodec::new(16);
+        let mut buf = BytesMut::from(&[0, 0, 0, 17][..]);
+
+        assert_eq!(codec.read_limit(&buf), 4);
+        assert_eq!(too_large(codec.decode(&mut buf).unwrap_err()), (17, 16));
+
+        assert_eq!(codec.read_limit(&[0, 0, 0, 16]), 20);
+    }
+
+    #[test]
+    fn decodes_http_lines() {
+        let mut codec = HttpCodec::new(16);
+        let mut buf = BytesMut::from(&b"GET / HTTP/1.1\r\n\r\nHost"[..]);
+
+        assert_eq!(
+            &codec.decode(&mut buf).unwrap().unwrap()[..],
+            b"GET / HTTP/1.1"
+        );
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap()[..], b"");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(&buf[..], b"Host");
+    }
+
+    #[test]
+    fn refuses_http_lines_never_ending() {
+        let mut codec = HttpCodec::new(16);
+        let mut buf = BytesMut::from(&[b'a'; 16][..]);
+
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(b"a");
+
+        assert_eq!(too_large(codec.decode(&mut buf).unwrap_err()), (17, 16));
+    }
+
+    #[test]
+    fn decodes_raw_bytes_in_chunks() {
+        let mut codec = RawCodec;
+        let mut buf = BytesMut::from(vec![1; CHUNK_SIZE + 1]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().len(), CHUNK_SIZE);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().len(), 1);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn switches_codecs_by_the_delimiter() {
+        let mut buf = BytesMut::from(&b"line\r\n"[..]);
+
+        match Codec::new(&Delim::Http, 16).decode(&mut buf).unwrap() {
+            Some(line) => assert_eq!(&line[..], b"line"),
+            None => panic!("no line"),
+        }
+
+        let mut buf = BytesMut::from(&b"line\r\n"[..]);
+
+        match Codec::new(&Delim::None, 16).decode(&mut buf).unwrap() {
+            Some(raw) => assert_eq!(&raw[..], b"line\r\n"),
+            None => panic!("no bytes"),
+        }
+    }
+}
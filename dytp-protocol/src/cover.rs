@@ -0,0 +1,48 @@
+use crate::method::encrypted::Schedule;
+use futures::prelude::*;
+use std::time::Instant;
+use tokio::timer::Delay;
+
+//
+// Tells an end of a link when to send a dummy frame on the schedule.
+// A dummy is due at every tick whatever has been sent in between,
+// so the rate of dummies doesn't tell when cells flow.
+//
+#[derive(Debug)]
+pub struct Cover {
+    schedule: Schedule,
+    timer: Option<Delay>,
+}
+
+impl Cover {
+    pub fn new(schedule: Schedule) -> Cover {
+        Cover {
+            schedule,
+            timer: None,
+        }
+    }
+
+    // The task is woken up at the next tick.
+    pub fn poll_due(&mut self) -> bool {
+        if self.schedule == Schedule::None {
+            return false;
+        }
+
+        if let Some(timer) = self.timer.as_mut() {
+            if let Ok(Async::NotReady) = timer.poll() {
+                return false;
+            }
+        }
+
+        // The first tick only starts the timer.
+        let due = self.timer.is_some();
+        let interval = self.schedule.next_interval().unwrap_or_default();
+        let mut timer = Delay::new(Instant::now() + interval);
+
+        let _ = timer.poll();
+
+        self.timer = Some(timer);
+
+        due
+    }
+}
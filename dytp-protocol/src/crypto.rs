@@ -0,0 +1,552 @@
+use crate::error::{CryptoError, Result};
+use crate::wire::{Reader, Writer};
+use openssl::derive::Deriver;
+use openssl::hash::MessageDigest;
+use openssl::md::Md;
+use openssl::memcmp;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::pkey_ctx::PkeyCtx;
+use openssl::rsa::Rsa;
+use openssl::sha::sha256;
+use openssl::sign::{Signer, Verifier};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+pub const ID_LEN: usize = 32;
+pub const DH_LEN: usize = 32;
+pub const MAC_LEN: usize = 32;
+pub const ONIONSKIN_LEN: usize = ID_LEN + DH_LEN * 2;
+pub const CREATED_LEN: usize = DH_LEN + MAC_LEN;
+
+const PROTO_ID: &[u8] = b"dytp-ntor-curve25519-sha256-1";
+const T_MAC: &[u8] = b"dytp-ntor-curve25519-sha256-1:mac";
+const T_KEY: &[u8] = b"dytp-ntor-curve25519-sha256-1:key_extract";
+const T_VERIFY: &[u8] = b"dytp-ntor-curve25519-sha256-1:verify";
+const M_EXPAND: &[u8] = b"dytp-ntor-curve25519-sha256-1:key_expand";
+const T_ONION_KEY: &[u8] = b"dytp-onion-key-rsa-sha256-1";
+
+// Signed onion keys older (or newer) than this are refused (secs).
+pub const ONION_KEY_MAX_AGE: i64 = 60 * 60;
+
+//
+// AES-256-GCM for one direction of a hop.
+// A nonce is derived from the sequence number of the frame,
+// so both ends must seal and open frames in the same order.
+//
+// +--------------------------------------------------+
+// |[any bytes: encrypted payload] | [16 bytes: tag]  |
+// +--------------------------------------------------+
+//
+#[derive(Debug)]
+pub struct Aead {
+    key: Vec<u8>,
+    seq: u64,
+}
+
+impl Aead {
+    pub fn new(key: &[u8]) -> Aead {
+        Aead {
+            key: key.to_vec(),
+            seq: 0,
+        }
+    }
+
+    fn nonce(&mut self) -> [u8; NONCE_LEN] {
+        let mut nonce = [0; NONCE_LEN];
+
+        nonce[NONCE_LEN - 8..].copy_from_slice(&self.seq.to_be_bytes());
+
+        self.seq += 1;
+
+        nonce
+    }
+
+    pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce();
+        let mut tag = [0; TAG_LEN];
+        let mut sealed = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &[],
+            data,
+            &mut tag,
+        )
+        .unwrap();
+
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    pub fn open(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < TAG_LEN {
+            return Err(CryptoError::Tampered.into());
+        }
+
+        let nonce = self.nonce();
+        let (data, tag) = data.split_at(data.len() - TAG_LEN);
+
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            &[],
+            data,
+            tag,
+        )
+        .map_err(|_| CryptoError::Tampered.into())
+    }
+}
+
+//
+// Keys shared between the gateway and a hop.
+// `forward` is used for frames going to the destination,
+// `backward` is used for frames coming back to the gateway.
+//
+#[derive(Debug)]
+pub struct HopKeys {
+    pub forward: Aead,
+    pub backward: Aead,
+}
+
+impl HopKeys {
+    pub fn from_material(material: &[u8]) -> Result<HopKeys> {
+        if material.len() != KEY_LEN * 2 {
+            return Err(CryptoError::InvalidKey.into());
+        }
+
+        Ok(HopKeys {
+            forward: Aead::new(&material[0..KEY_LEN]),
+            backward: Aead::new(&material[KEY_LEN..]),
+        })
+    }
+}
+
+// Fingerprint of the identity key of a node.
+pub fn fingerprint(der: &[u8]) -> Vec<u8> {
+    sha256(der).to_vec()
+}
+
+// Hex representation of a fingerprint published to the cloud.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+//
+// Public part of the keys used by a node for the circuit handshake.
+// `id` is the fingerprint of the identity key and
+// `key` is the X25519 onion key (B).
+//
+// +------------------------------------------+
+// | [32 bytes: id] | [32 bytes: onion key]   |
+// +------------------------------------------+
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct NtorKey {
+    pub id: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+impl Into<Vec<u8>> for NtorKey {
+    fn into(self) -> Vec<u8> {
+        let mut buf = self.id;
+        buf.extend_from_slice(&self.key);
+        buf
+    }
+}
+
+impl NtorKey {
+    pub fn from_bytes(buf: &[u8]) -> Result<NtorKey> {
+        if buf.len() != ID_LEN + DH_LEN {
+            return Err(CryptoError::InvalidKey.into());
+        }
+
+        Ok(NtorKey {
+            id: buf[0..ID_LEN].to_vec(),
+            key: buf[ID_LEN..].to_vec(),
+        })
+    }
+}
+
+// Seconds since the epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+//
+// The onion key of a node certified by its identity key, sent to the gateway by ONION_KEY.
+// The gateway uses the onion key only if the identity key hashes to the fingerprint
+// published by the cloud and the signature over the onion key and the timestamp holds,
+// so that nobody else on the way can hand out their own onion key.
+//
+// +---------------------------------------------------------------------------------------+
+// | [32 bytes: onion key] | [8 bytes: ts] | [field: identity key (DER)] | [field: signature] |
+// +---------------------------------------------------------------------------------------+
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedOnionKey {
+    pub key: Vec<u8>,
+    pub ts: i64,
+    pub identity: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedOnionKey {
+    pub fn sign(rsa: &Rsa<Private>, key: &[u8], ts: i64) -> Result<SignedOnionKey> {
+        let identity = rsa.public_key_to_der()?;
+        let pkey = PKey::from_rsa(rsa.clone())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+
+        signer.update(&SignedOnionKey::signed_data(key, ts))?;
+
+        Ok(SignedOnionKey {
+            key: key.to_vec(),
+            ts,
+            identity,
+            signature: signer.sign_to_vec()?,
+        })
+    }
+
+    fn signed_data(key: &[u8], ts: i64) -> Vec<u8> {
+        [T_ONION_KEY, key, &ts.to_be_bytes()].concat()
+    }
+
+    // Returns the key for the handshake once the onion key is proven to be of the fingerprint.
+    pub fn verify(&self, fingerprint: &str, now: i64) -> Result<NtorKey> {
+        let id = self::fingerprint(&self.identity);
+
+        if hex(&id) != fingerprint {
+            return Err(CryptoError::UnknownIdentity.into());
+        }
+
+        if (now - self.ts).abs() > ONION_KEY_MAX_AGE {
+            return Err(CryptoError::StaleOnionKey.into());
+        }
+
+        let pkey = PKey::public_key_from_der(&self.identity)?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &pkey)?;
+
+        verifier.update(&SignedOnionKey::signed_data(&self.key, self.ts))?;
+
+        if !verifier.verify(&self.signature).unwrap_or(false) {
+            return Err(CryptoError::InvalidSignature.into());
+        }
+
+        Ok(NtorKey {
+            id,
+            key: self.key.clone(),
+        })
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<SignedOnionKey> {
+        let mut r = Reader::untagged(buf);
+        let signed = SignedOnionKey {
+            key: r.raw(DH_LEN)?.to_vec(),
+            ts: r.i64()?,
+            identity: r.bytes()?.to_vec(),
+            signature: r.bytes()?.to_vec(),
+        };
+
+        r.finish()?;
+
+        Ok(signed)
+    }
+
+    pub fn encode(self) -> Result<Vec<u8>> {
+        Ok(Writer::untagged()
+            .raw(&self.key)
+            .i64(self.ts)
+            .bytes(&self.identity)?
+            .bytes(&self.signature)?
+            .finish())
+    }
+}
+
+//
+// Client side of the ntor handshake (the gateway).
+//
+// The client sends an onionskin and the node replies with its ephemeral key and an auth.
+// Keys of the hop are derived from both of the ephemeral keys and the onion key of the node,
+// so recorded traffic can't be decrypted even if the onion key is leaked later.
+//
+// onionskin:
+// +--------------------------------------------------------------+
+// | [32 bytes: id] | [32 bytes: onion key (B)] | [32 bytes: X]   |
+// +--------------------------------------------------------------+
+//
+// created:
+// +--------------------------------------+
+// | [32 bytes: Y] | [32 bytes: auth]     |
+// +--------------------------------------+
+//
+#[derive(Debug)]
+pub struct NtorClient {
+    ntor_key: NtorKey,
+    x: PKey<Private>,
+}
+
+impl NtorClient {
+    pub fn new(ntor_key: NtorKey) -> Result<NtorClient> {
+        let x = PKey::generate_x25519()?;
+
+        Ok(NtorClient { ntor_key, x })
+    }
+
+    pub fn onionskin(&self) -> Vec<u8> {
+        let mut onionskin: Vec<u8> = self.ntor_key.clone().into();
+
+        onionskin.extend_from_slice(&self.x.raw_public_key().unwrap());
+        onionskin
+    }
+
+    pub fn complete(&self, created: &[u8]) -> Result<HopKeys> {
+        if created.len() != CREATED_LEN {
+            return Err(CryptoError::HandshakeFailure.into());
+        }
+
+        let (y, auth) = created.split_at(DH_LEN);
+        let x = self.x.raw_public_key()?;
+        let secret_input = [
+            dh(&self.x, y)?,
+            dh(&self.x, &self.ntor_key.key)?,
+            self.ntor_key.id.clone(),
+            self.ntor_key.key.clone(),
+            x.clone(),
+            y.to_vec(),
+            PROTO_ID.to_vec(),
+        ]
+        .concat();
+
+        let (expected, keys) = derive(&secret_input, &self.ntor_key, &x, y)?;
+
+        if !memcmp::eq(auth, &expected) {
+            return Err(CryptoError::HandshakeFailure.into());
+        }
+
+        Ok(keys)
+    }
+}
+
+// Server side of the ntor handshake (a node).
+// Returns the reply to the onionskin with keys of the hop.
+pub fn ntor_server(
+    ntor_key: &NtorKey,
+    onion_key: &PKey<Private>,
+    onionskin: &[u8],
+) -> Result<(Vec<u8>, HopKeys)> {
+    if onionskin.len() != ONIONSKIN_LEN {
+        return Err(CryptoError::HandshakeFailure.into());
+    }
+
+    // The gateway may know an onion key of the previous run.
+    if onionskin[0..ID_LEN] != ntor_key.id[..]
+        || onionskin[ID_LEN..ID_LEN + DH_LEN] != ntor_key.key[..]
+    {
+        return Err(CryptoError::HandshakeFailure.into());
+    }
+
+    let x = &onionskin[ID_LEN + DH_LEN..];
+    let y = PKey::generate_x25519()?;
+    let y_pub = y.raw_public_key()?;
+    let secret_input = [
+        dh(&y, x)?,
+        dh(onion_key, x)?,
+        ntor_key.id.clone(),
+        ntor_key.key.clone(),
+        x.to_vec(),
+        y_pub.clone(),
+        PROTO_ID.to_vec(),
+    ]
+    .concat();
+
+    let (auth, keys) = derive(&secret_input, ntor_key, x, &y_pub)?;
+
+    Ok(([y_pub, auth].concat(), keys))
+}
+
+fn dh(private: &PKey<Private>, public: &[u8]) -> Result<Vec<u8>> {
+    let public = PKey::public_key_from_raw_bytes(public, Id::X25519)?;
+    let mut deriver = Deriver::new(private)?;
+
+    deriver.set_peer(&public)?;
+
+    let shared = deriver.derive_to_vec()?;
+
+    // Reject low order points.
+    if shared.iter().all(|b| *b == 0) {
+        return Err(CryptoError::HandshakeFailure.into());
+    }
+
+    Ok(shared)
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+
+    signer.update(data)?;
+
+    Ok(signer.sign_to_vec()?)
+}
+
+// Returns the auth and keys of the hop derived from the secret input.
+fn derive(
+    secret_input: &[u8],
+    ntor_key: &NtorKey,
+    x: &[u8],
+    y: &[u8],
+) -> Result<(Vec<u8>, HopKeys)> {
+    let verify = hmac(T_VERIFY, secret_input)?;
+    let auth_input = [
+        &verify[..],
+        &ntor_key.id,
+        &ntor_key.key,
+        y,
+        x,
+        PROTO_ID,
+        b"Server",
+    ]
+    .concat();
+    let auth = hmac(T_MAC, &auth_input)?;
+
+    let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+    let mut material = [0; KEY_LEN * 2];
+
+    ctx.derive_init()?;
+    ctx.set_hkdf_md(Md::sha256())?;
+    ctx.set_hkdf_key(secret_input)?;
+    ctx.set_hkdf_salt(T_KEY)?;
+    ctx.add_hkdf_info(M_EXPAND)?;
+    ctx.derive(Some(&mut material))?;
+
+    Ok((auth, HopKeys::from_material(&material)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Aead, Aead) {
+        let key = [7; KEY_LEN];
+
+        (Aead::new(&key), Aead::new(&key))
+    }
+
+    #[test]
+    fn aead_opens_sealed_frames_in_order() {
+        let (mut sealer, mut opener) = pair();
+
+        let first = sealer.seal(b"first");
+        let second = sealer.seal(b"second");
+
+        assert_eq!(opener.open(&first).unwrap(), b"first");
+        assert_eq!(opener.open(&second).unwrap(), b"second");
+    }
+
+    #[test]
+    fn aead_refuses_flipped_byte() {
+        let (mut sealer, mut opener) = pair();
+
+        let mut sealed = sealer.seal(b"payload");
+
+        sealed[0] ^= 0x01;
+
+        assert!(opener.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn aead_refuses_flipped_tag() {
+        let (mut sealer, mut opener) = pair();
+
+        let mut sealed = sealer.seal(b"payload");
+        let last = sealed.len() - 1;
+
+        sealed[last] ^= 0x80;
+
+        assert!(opener.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn aead_refuses_replayed_frame() {
+        let (mut sealer, mut opener) = pair();
+
+        let sealed = sealer.seal(b"payload");
+
+        assert!(opener.open(&sealed).is_ok());
+        assert!(opener.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn aead_refuses_reordered_frames() {
+        let (mut sealer, mut opener) = pair();
+
+        let _first = sealer.seal(b"first");
+        let second = sealer.seal(b"second");
+
+        assert!(opener.open(&second).is_err());
+    }
+
+    #[test]
+    fn aead_refuses_frame_shorter_than_tag() {
+        let (_, mut opener) = pair();
+
+        assert!(opener.open(&[0; TAG_LEN - 1]).is_err());
+    }
+
+    fn onion_key() -> (NtorKey, PKey<Private>) {
+        let onion_key = PKey::generate_x25519().unwrap();
+        let ntor_key = NtorKey {
+            id: vec![1; ID_LEN],
+            key: onion_key.raw_public_key().unwrap(),
+        };
+
+        (ntor_key, onion_key)
+    }
+
+    #[test]
+    fn ntor_derives_same_keys() {
+        let (ntor_key, onion_key) = onion_key();
+        let client = NtorClient::new(ntor_key.clone()).unwrap();
+
+        let (created, mut server_keys) =
+            ntor_server(&ntor_key, &onion_key, &client.onionskin()).unwrap();
+        let mut client_keys = client.complete(&created).unwrap();
+
+        let forward = client_keys.forward.seal(b"to the node");
+        let backward = server_keys.backward.seal(b"to the gateway");
+
+        assert_eq!(server_keys.forward.open(&forward).unwrap(), b"to the node");
+        assert_eq!(
+            client_keys.backward.open(&backward).unwrap(),
+            b"to the gateway"
+        );
+    }
+
+    #[test]
+    fn ntor_refuses_tampered_created() {
+        let (ntor_key, onion_key) = onion_key();
+        let client = NtorClient::new(ntor_key.clone()).unwrap();
+
+        let (mut created, _) = ntor_server(&ntor_key, &onion_key, &client.onionskin()).unwrap();
+
+        created[CREATED_LEN - 1] ^= 0x01;
+
+        assert!(client.complete(&created).is_err());
+    }
+
+    #[test]
+    fn ntor_refuses_onionskin_for_other_onion_key() {
+        let (ntor_key, _) = onion_key();
+        let (other_key, other) = onion_key();
+        let client = NtorClient::new(ntor_key).unwrap();
+
+        assert!(ntor_server(&other_key, &other, &client.onionskin()).is_err());
+    }
+}
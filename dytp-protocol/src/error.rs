@@ -0,0 +1,72 @@
+use failure::Error;
+use failure::Fail;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Fail)]
+pub enum CryptoError {
+    #[fail(display = "invalid key material")]
+    InvalidKey,
+
+    #[fail(display = "circuit handshake failed")]
+    HandshakeFailure,
+
+    #[fail(display = "failed to authenticate a cell (tampered or out of order)")]
+    Tampered,
+
+    #[fail(display = "the identity key doesn't match the fingerprint")]
+    UnknownIdentity,
+
+    #[fail(display = "the onion key isn't signed by the identity key")]
+    InvalidSignature,
+
+    #[fail(display = "the onion key was signed too long ago")]
+    StaleOnionKey,
+}
+
+#[derive(Debug, Fail)]
+pub enum ProtocolError {
+    #[fail(display = "too short frame header len={}", len)]
+    ShortHeader { len: usize },
+
+    #[fail(display = "too large frame size={} (max={})", size, max)]
+    FrameTooLarge { size: usize, max: usize },
+}
+
+#[derive(Debug, Fail)]
+pub enum WireError {
+    #[fail(display = "truncated message")]
+    Truncated,
+
+    #[fail(display = "unsupported protocol version={}", version)]
+    UnsupportedVersion { version: u8 },
+
+    #[fail(display = "unknown message type={:#04x}", tag)]
+    UnknownType { tag: u8 },
+
+    #[fail(display = "invalid field={} of message", field)]
+    InvalidField { field: &'static str },
+
+    #[fail(display = "trailing bytes after message")]
+    TrailingBytes,
+
+    #[fail(display = "too long field len={} (max={})", len, max)]
+    TooLong { len: usize, max: usize },
+
+    #[fail(display = "BEGIN of stream={} can't be read", stream)]
+    InvalidBegin { stream: u16 },
+
+    #[fail(display = "the connection hasn't started with the version negotiation")]
+    NotNegotiated,
+
+    #[fail(
+        display = "incompatible protocol versions (peer speaks {}..={}, this speaks {}..={})",
+        min, max, own_min, own_max
+    )]
+    IncompatibleVersion {
+        min: u8,
+        max: u8,
+        own_min: u8,
+        own_max: u8,
+    },
+}
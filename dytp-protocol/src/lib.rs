@@ -1,11 +1,37 @@
+pub mod addr;
+pub mod codec;
+pub mod cover;
+pub mod crypto;
 pub mod delim;
+pub mod error;
+pub mod link;
 pub mod method;
 pub mod raw;
 pub mod size;
+pub mod wire;
 
+use crate::error::{ProtocolError, Result};
 use crate::raw::Raw;
 use crate::size::Size;
 use bytes::BytesMut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Version of the circuit protocol.
+// Nodes refuse to relay for a gateway speaking another version.
+//
+// 1: AES-256-CBC with a fixed IV
+// 2: AES-256-GCM with per-direction keys and sequence number nonces
+// 3: Keys of each hop are agreed by the ntor handshake with onion keys signed by the identity keys
+// 4: The stream is carried in DATA cells and half-closed by an END cell
+// 5: A circuit carries many streams opened by BEGIN cells at the exit node
+// 6: Methods and answers are encoded in binary and connections start with the version negotiation
+// 7: Cells may be padded to a fixed size told by the methods
+// 8: Adjacent hops seal frames by the keys of their link and exchange dummies on it
+pub const VERSION: u8 = 8;
+
+// The oldest version this build still speaks.
+pub const MIN_VERSION: u8 = 8;
+
 //
 // +------------------------------------------------------+
 // |[4 bytes: raw payload size] | [any bytes: raw payload]|
@@ -20,22 +46,49 @@ impl Protocol {
     }
 }
 
-pub fn parse(buf: &mut BytesMut) -> Option<Protocol> {
-    let size = if buf.len() >= 4 {
-        Size::parse(&buf as &[u8])
-    } else {
-        return None;
-    };
-
-    let raw = if buf.len() - 4 >= size.0 as usize {
-        let mut raw = buf.split_to(size.0 as usize + 4);
-        raw.split_to(4);
-        Raw::wrap(raw)
-    } else {
-        return None;
-    };
-
-    Some(Protocol(size, raw))
+// Frames announcing a larger payload are refused before it's buffered.
+// The largest ones sent by components are lists of nodes, far below this.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+// Data cells must fit in a frame whatever the limit is.
+pub const MIN_FRAME_SIZE: usize = 64 * 1024;
+
+static MAX_FRAME_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FRAME_SIZE);
+
+// Configured once on start, applied to every connection of the process.
+pub fn set_max_frame_size(max: usize) {
+    MAX_FRAME_SIZE.store(max, Ordering::Relaxed);
+}
+
+pub fn max_frame_size() -> usize {
+    MAX_FRAME_SIZE.load(Ordering::Relaxed)
+}
+
+// Returns None until the whole frame has been received.
+// The size is checked as soon as the header arrives so that a peer can't make the buffer grow without bound.
+pub fn parse(buf: &mut BytesMut, max: usize) -> Result<Option<Protocol>> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+
+    let size = Size::parse(&buf as &[u8])?;
+
+    if size.0 as usize > max {
+        return Err(ProtocolError::FrameTooLarge {
+            size: size.0 as usize,
+            max,
+        }
+        .into());
+    }
+
+    if buf.len() - 4 < size.0 as usize {
+        return Ok(None);
+    }
+
+    let mut raw = buf.split_to(size.0 as usize + 4);
+    raw.split_to(4);
+
+    Ok(Some(Protocol(size, Raw::wrap(raw))))
 }
 
 impl From<&[u8]> for Protocol {
@@ -57,3 +110,49 @@ impl Into<BytesMut> for Protocol {
         buf
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(payload: &[u8]) -> BytesMut {
+        Protocol::from(payload).into()
+    }
+
+    #[test]
+    fn waits_for_the_whole_frame() {
+        let whole = frame(b"hello");
+        let mut buf = BytesMut::from(&whole[..3]);
+
+        assert!(parse(&mut buf, 16).unwrap().is_none());
+
+        buf.extend_from_slice(&whole[3..8]);
+
+        assert!(parse(&mut buf, 16).unwrap().is_none());
+
+        buf.extend_from_slice(&whole[8..]);
+        buf.extend_from_slice(b"next");
+
+        let p = parse(&mut buf, 16).unwrap().unwrap();
+
+        assert_eq!(&(p.1).0[..], b"hello");
+        assert_eq!(&buf[..], b"next");
+    }
+
+    #[test]
+    fn refuses_an_oversized_frame_by_its_header() {
+        let mut buf = BytesMut::from(&frame(&[0; 17])[..4]);
+
+        match parse(&mut buf, 16).unwrap_err().downcast::<ProtocolError>() {
+            Ok(ProtocolError::FrameTooLarge { size, max }) => {
+                assert_eq!(size, 17);
+                assert_eq!(max, 16);
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let mut buf = frame(&[0; 16]);
+
+        assert_eq!((parse(&mut buf, 16).unwrap().unwrap().1).0.len(), 16);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::crypto::{Aead, HopKeys, KEY_LEN};
+use crate::error::{Result, WireError};
+use openssl::rand::rand_bytes;
+
+pub const LINK_KEYS_LEN: usize = KEY_LEN * 2;
+
+const CELL: u8 = 0x01;
+const DUMMY: u8 = 0x02;
+
+// Keys of a link made up by the gateway, told to both ends of the link by their methods.
+pub fn link_keys() -> Result<Vec<u8>> {
+    let mut keys = vec![0; LINK_KEYS_LEN];
+
+    rand_bytes(&mut keys)?;
+
+    Ok(keys)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Cell(Vec<u8>), // A frame of the circuit to be relied or read
+    Dummy,         // Dropped by the end which has received it
+}
+
+//
+// Frames between adjacent hops of a circuit, where the gateway is the first end of the first link.
+// CREATE and the method going forward and CREATED coming back are sent as they are,
+// since the farther end is told the keys of the link by the method.
+// Every frame after them is sealed by the keys of the link so that dummies can't be told from the others.
+//
+// +-----------------------------------------------------------------------+
+// |[1 byte: frame type] | [any bytes: frame of the circuit] | [16 bytes: tag] |
+// +-----------------------------------------------------------------------+
+//
+#[derive(Debug)]
+pub struct Link {
+    sealer: Aead,
+    opener: Aead,
+    clear_sent: usize,     // Frames sent as they are before the link is sealed
+    clear_received: usize, // Frames received as they are before the link is sealed
+    last_len: usize,       // Dummies are as long as the last frame sent
+}
+
+impl Link {
+    // The end nearer to the gateway, which sends CREATE and the method through the link.
+    pub fn near(keys: &[u8]) -> Result<Link> {
+        let keys = HopKeys::from_material(keys)?;
+
+        Ok(Link {
+            sealer: keys.forward,
+            opener: keys.backward,
+            clear_sent: 2,
+            clear_received: 1,
+            last_len: 0,
+        })
+    }
+
+    // The end farther from the gateway, which has read its method already.
+    pub fn far(keys: &[u8]) -> Result<Link> {
+        let keys = HopKeys::from_material(keys)?;
+
+        Ok(Link {
+            sealer: keys.backward,
+            opener: keys.forward,
+            clear_sent: 0,
+            clear_received: 0,
+            last_len: 0,
+        })
+    }
+
+    // The other end drops dummies only once the link is sealed.
+    pub fn is_sealed(&self) -> bool {
+        self.clear_sent == 0 && self.clear_received == 0
+    }
+
+    pub fn seal(&mut self, frame: &[u8]) -> Vec<u8> {
+        self.last_len = frame.len();
+
+        if self.clear_sent > 0 {
+            self.clear_sent -= 1;
+
+            return frame.to_vec();
+        }
+
+        self.sealer.seal(&[&[CELL], frame].concat())
+    }
+
+    pub fn dummy(&mut self) -> Vec<u8> {
+        let mut frame = vec![0; 1 + self.last_len];
+
+        frame[0] = DUMMY;
+
+        self.sealer.seal(&frame)
+    }
+
+    // Fails if the frame doesn't authenticate, then the circuit must be torn down.
+    pub fn open(&mut self, frame: &[u8]) -> Result<Frame> {
+        if self.clear_received > 0 {
+            self.clear_received -= 1;
+
+            return Ok(Frame::Cell(frame.to_vec()));
+        }
+
+        let opened = self.opener.open(frame)?;
+
+        match opened.split_first() {
+            Some((&CELL, frame)) => Ok(Frame::Cell(frame.to_vec())),
+            Some((&DUMMY, _)) => Ok(Frame::Dummy),
+            Some((&tag, _)) => Err(WireError::UnknownType { tag }.into()),
+            None => Err(WireError::Truncated.into()),
+        }
+    }
+}
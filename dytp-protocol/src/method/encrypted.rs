@@ -1,47 +1,559 @@
-use std::net::SocketAddr;
+use crate::addr::Addr;
+use crate::error::{Result, WireError};
+use crate::link::LINK_KEYS_LEN;
+use crate::wire::{Reader, Writer};
+use rand::Rng;
+use std::time::Duration;
 
+const RELY: u8 = 0x40;
+const EXIT: u8 = 0x41;
+
+// Fixed-size cells must be able to carry a BEGIN to a hostname of usual length.
+pub const MIN_CELL_SIZE: u16 = 256;
+
+//
+// How cells are sized on a circuit, told to every hop by the method.
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Padding {
+    None,       // Cells are as long as their content
+    Fixed(u16), // Cells are padded to the size so that frames don't tell how much is sent
+}
+
+impl Padding {
+    // 0 for cells as long as their content.
+    pub fn from_size(size: u16) -> Result<Padding> {
+        match size {
+            0 => Ok(Padding::None),
+            size if size >= MIN_CELL_SIZE => Ok(Padding::Fixed(size)),
+            _ => Err(WireError::InvalidField { field: "cell_size" }.into()),
+        }
+    }
+
+    pub fn size(self) -> u16 {
+        match self {
+            Padding::None => 0,
+            Padding::Fixed(size) => size,
+        }
+    }
+}
+
+// Cover traffic more frequent than this (millis) is refused so that a gateway can't flood the exit node.
+pub const MIN_COVER_INTERVAL: u16 = 10;
+
+//
+// When dummy frames are sent on the links of a circuit, told to every hop by the method.
+// Both ends of each link send one at every tick whether or not cells flow.
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schedule {
+    None,          // No dummies
+    Constant(u16), // Intervals of the millis
+    Random(u16),   // Intervals drawn from the exponential distribution with the mean of the millis
+}
+
+impl Schedule {
+    pub fn new(kind: u8, interval: u16) -> Result<Schedule> {
+        let schedule = match kind {
+            0 => return Ok(Schedule::None),
+            1 => Schedule::Constant(interval),
+            2 => Schedule::Random(interval),
+            _ => return Err(WireError::InvalidField { field: "cover" }.into()),
+        };
+
+        if interval < MIN_COVER_INTERVAL {
+            return Err(WireError::InvalidField { field: "cover" }.into());
+        }
+
+        Ok(schedule)
+    }
+
+    fn kind(self) -> u8 {
+        match self {
+            Schedule::None => 0,
+            Schedule::Constant(_) => 1,
+            Schedule::Random(_) => 2,
+        }
+    }
+
+    fn interval(self) -> u16 {
+        match self {
+            Schedule::None => 0,
+            Schedule::Constant(interval) | Schedule::Random(interval) => interval,
+        }
+    }
+
+    // None if no dummies are sent.
+    pub fn next_interval(self) -> Option<Duration> {
+        match self {
+            Schedule::None => None,
+            Schedule::Constant(interval) => Some(Duration::from_millis(interval as u64)),
+            Schedule::Random(interval) => {
+                // Inverse transform of a uniform sample in (0, 1).
+                // Drawn from 32 bits since `next_u64` of the rand_core in use reads misaligned memory.
+                let u = (rand::thread_rng().gen::<u32>() as f64 + 1.0)
+                    / (u32::max_value() as f64 + 2.0);
+
+                Some(Duration::from_millis((-u.ln() * interval as f64) as u64))
+            }
+        }
+    }
+}
+
+// (e.g. "none", "constant:500", "random:500")
+impl std::str::FromStr for Schedule {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Schedule> {
+        let invalid = || WireError::InvalidField { field: "cover" };
+
+        if s == "none" {
+            return Ok(Schedule::None);
+        }
+
+        let mut parts = s.splitn(2, ':');
+        let kind = match parts.next() {
+            Some("constant") => 1,
+            Some("random") => 2,
+            _ => return Err(invalid().into()),
+        };
+        let interval = parts
+            .next()
+            .and_then(|i| i.parse().ok())
+            .ok_or_else(invalid)?;
+
+        Schedule::new(kind, interval)
+    }
+}
+
+//
+// Tells a hop what to do once the handshake is done.
+// `link` is the keys of the link from the previous hop and `next_link` the ones to the next hop,
+// made up by the gateway so that adjacent hops can exchange dummy frames.
+//
 #[derive(PartialEq, Debug)]
 pub enum Method {
+    // Rely to another node
     RELY {
-        hop: u8,
-        addr: SocketAddr,
-        tls: bool,
-    }, // Rely to another node
+        addr: Addr,
+        padding: Padding,
+        cover: Schedule,
+        link: Vec<u8>,
+        next_link: Vec<u8>,
+    },
+    // Be the exit of the circuit and open streams to destinations
+    EXIT {
+        padding: Padding,
+        cover: Schedule,
+        link: Vec<u8>,
+    },
     E, // Invalid method
 }
 
-impl Into<Vec<u8>> for Method {
-    fn into(self) -> Vec<u8> {
+impl Method {
+    // Fails with the version if the gateway speaks another one.
+    pub fn decode(m: &[u8]) -> Result<Method> {
+        let (tag, mut r) = Reader::new(m)?;
+
+        let method = match tag {
+            RELY => Method::RELY {
+                addr: r.addr()?,
+                padding: Padding::from_size(r.u16()?)?,
+                cover: Schedule::new(r.u8()?, r.u16()?)?,
+                link: r.raw(LINK_KEYS_LEN)?.to_vec(),
+                next_link: r.raw(LINK_KEYS_LEN)?.to_vec(),
+            },
+            EXIT => Method::EXIT {
+                padding: Padding::from_size(r.u16()?)?,
+                cover: Schedule::new(r.u8()?, r.u16()?)?,
+                link: r.raw(LINK_KEYS_LEN)?.to_vec(),
+            },
+            _ => return Err(WireError::UnknownType { tag }.into()),
+        };
+
+        r.finish()?;
+
+        Ok(method)
+    }
+
+    pub fn encode(self) -> Result<Vec<u8>> {
+        let m = match self {
+            Method::RELY {
+                addr,
+                padding,
+                cover,
+                link,
+                next_link,
+            } => Writer::new(RELY)
+                .addr(&addr)?
+                .u16(padding.size())
+                .u8(cover.kind())
+                .u16(cover.interval())
+                .raw(&link)
+                .raw(&next_link)
+                .finish(),
+            Method::EXIT {
+                padding,
+                cover,
+                link,
+            } => Writer::new(EXIT)
+                .u16(padding.size())
+                .u8(cover.kind())
+                .u16(cover.interval())
+                .raw(&link)
+                .finish(),
+            Method::E => Vec::new(),
+        };
+
+        Ok(m)
+    }
+}
+
+impl From<&[u8]> for Method {
+    fn from(m: &[u8]) -> Method {
+        Method::decode(m).unwrap_or(Method::E)
+    }
+}
+
+//
+// Sent back from the exit node once it tried to connect to the destination.
+//
+#[allow(non_camel_case_types)]
+#[derive(PartialEq, Debug)]
+pub enum Status {
+    OK,                 // Connected to the destination
+    LOOKUP_FAILURE,     // Failed to resolve the destination host
+    CONNECTION_FAILURE, // Failed to connect to the destination
+    REFUSED,            // The destination isn't allowed by the exit node
+    TIMEOUT,            // Gave up connecting to the destination
+    INVALID_ADDRESS,    // The destination in BEGIN can't be read
+    E,                  // Invalid status
+}
+
+impl Into<u8> for Status {
+    fn into(self) -> u8 {
         match self {
-            Method::RELY { hop, addr, tls } => {
-                format!("RELY {} {} {}", hop, addr, tls as u8).into_bytes()
+            Status::OK => 0x00,
+            Status::LOOKUP_FAILURE => 0x01,
+            Status::CONNECTION_FAILURE => 0x02,
+            Status::REFUSED => 0x03,
+            Status::TIMEOUT => 0x04,
+            Status::INVALID_ADDRESS => 0x05,
+            Status::E => 0xff,
+        }
+    }
+}
+
+impl From<u8> for Status {
+    fn from(m: u8) -> Status {
+        match m {
+            0x00 => Status::OK,
+            0x01 => Status::LOOKUP_FAILURE,
+            0x02 => Status::CONNECTION_FAILURE,
+            0x03 => Status::REFUSED,
+            0x04 => Status::TIMEOUT,
+            0x05 => Status::INVALID_ADDRESS,
+            _ => Status::E,
+        }
+    }
+}
+
+//
+// Carries streams between the gateway and the exit node once the circuit is established.
+// Nodes in the middle can't read cells since they are sealed by the exit node or the gateway.
+// Only DESTROY may come from a node in the middle, sealed by the layers up to the node.
+//
+// Cells have no version since it has been agreed by the method of the circuit.
+//
+// +-------------------------------------------------------------------+
+// |[1 byte: cell type] | [2 bytes: stream id] | [any bytes: cell body] |
+// +-------------------------------------------------------------------+
+//
+#[derive(PartialEq, Debug)]
+pub enum Cell {
+    BEGIN { stream: u16, addr: Addr, tls: bool }, // Open a stream to the destination
+    STATUS { stream: u16, status: Status },       // Reply to the BEGIN
+    DATA { stream: u16, data: Vec<u8> },          // A piece of the stream
+    END { stream: u16 }, // The sender has nothing more to send (half-close)
+    DESTROY { status: Status }, // The circuit is torn down by the hop which has failed
+    E,                   // Invalid cell
+}
+
+const BEGIN: u8 = 0x01;
+const STATUS: u8 = 0x02;
+const DATA: u8 = 0x03;
+const END: u8 = 0x04;
+const DESTROY: u8 = 0x05;
+
+impl Cell {
+    fn header(tag: u8, stream: u16) -> Writer {
+        Writer::untagged().u8(tag).u16(stream)
+    }
+
+    pub fn decode(m: &[u8]) -> Result<Cell> {
+        let mut r = Reader::untagged(m);
+        let tag = r.u8()?;
+        let stream = r.u16()?;
+
+        let cell = match tag {
+            BEGIN => {
+                return Cell::decode_begin(stream, r)
+                    .map_err(|_| WireError::InvalidBegin { stream }.into());
             }
-            _ => b"E".to_vec(),
+            STATUS => Cell::STATUS {
+                stream,
+                status: Status::from(r.u8()?),
+            },
+            DATA => Cell::DATA {
+                stream,
+                data: r.rest().to_vec(),
+            },
+            END => Cell::END { stream },
+            DESTROY if stream == 0 => Cell::DESTROY {
+                status: Status::from(r.u8()?),
+            },
+            _ => return Err(WireError::UnknownType { tag }.into()),
+        };
+
+        r.finish()?;
+
+        Ok(cell)
+    }
+
+    // The stream id is told in the error even if the rest can't be read,
+    // so that the exit node refuses the stream alone instead of the circuit.
+    fn decode_begin(stream: u16, mut r: Reader) -> Result<Cell> {
+        let cell = Cell::BEGIN {
+            stream,
+            addr: r.addr()?,
+            tls: r.u8()? != 0,
+        };
+
+        r.finish()?;
+
+        Ok(cell)
+    }
+
+    //
+    // Encodes the cell into the ones sent on a circuit with the padding.
+    // Fixed-size cells are prefixed with the length of the content and filled with zeros.
+    // DATA longer than a cell can carry is split, any other cell is padded to a multiple of the size.
+    //
+    // +--------------------------------------------------------------------+
+    // |[2 bytes: cell length] | [any bytes: cell] | [zeros up to cell size] |
+    // +--------------------------------------------------------------------+
+    //
+    pub fn encode(self, padding: Padding) -> Result<Vec<Vec<u8>>> {
+        let size = match padding {
+            Padding::None => return Ok(vec![self.encode_unpadded()?]),
+            Padding::Fixed(size) => size as usize,
+        };
+
+        let cells = match self {
+            Cell::DATA { stream, data } if data.len() > size - PADDED_HEADER => data
+                .chunks(size - PADDED_HEADER)
+                .map(|data| Cell::DATA {
+                    stream,
+                    data: data.to_vec(),
+                })
+                .collect(),
+            cell => vec![cell],
+        };
+
+        cells
+            .into_iter()
+            .map(|cell| {
+                let cell = cell.encode_unpadded()?;
+                let len = 2 + cell.len();
+                let padded = (len + size - 1) / size * size;
+
+                Ok(Writer::untagged()
+                    .bytes(&cell)?
+                    .raw(&vec![0; padded - len])
+                    .finish())
+            })
+            .collect()
+    }
+
+    // Reads a cell received on a circuit with the padding.
+    pub fn decode_padded(m: &[u8], padding: Padding) -> Result<Cell> {
+        let size = match padding {
+            Padding::None => return Cell::decode(m),
+            Padding::Fixed(size) => size as usize,
+        };
+
+        if m.is_empty() || m.len() % size != 0 {
+            return Err(WireError::InvalidField { field: "cell_size" }.into());
         }
+
+        Cell::decode(Reader::untagged(m).bytes()?)
     }
 }
 
-impl From<&[u8]> for Method {
-    fn from(m: &[u8]) -> Method {
-        use std::net::AddrParseError;
-        use std::num::ParseIntError;
-
-        let re = regex::Regex::new(r"^RELY\s(\d{1})\s(.+?)\s(\d{1})$").unwrap();
-
-        for cap in re.captures_iter(std::str::from_utf8(m).unwrap()) {
-            let hop: Result<u8, ParseIntError> = cap[1].parse();
-            let addr: Result<SocketAddr, AddrParseError> = cap[2].parse();
-            let tls: Result<u8, ParseIntError> = cap[3].parse();
-
-            if hop.is_ok() && addr.is_ok() && tls.is_ok() {
-                return Method::RELY {
-                    hop: hop.unwrap(),
-                    addr: addr.unwrap(),
-                    tls: tls.unwrap() != 0,
-                };
+// Bytes of a fixed-size cell taken by the length, the type and the stream id of DATA.
+const PADDED_HEADER: usize = 2 + 1 + 2;
+
+impl Cell {
+    fn encode_unpadded(self) -> Result<Vec<u8>> {
+        let m = match self {
+            Cell::BEGIN { stream, addr, tls } => Cell::header(BEGIN, stream)
+                .addr(&addr)?
+                .u8(tls as u8)
+                .finish(),
+            Cell::STATUS { stream, status } => {
+                Cell::header(STATUS, stream).u8(status.into()).finish()
+            }
+            Cell::DATA { stream, data } => Cell::header(DATA, stream).raw(&data).finish(),
+            Cell::END { stream } => Cell::header(END, stream).finish(),
+            Cell::DESTROY { status } => Cell::header(DESTROY, 0).u8(status.into()).finish(),
+            Cell::E => Vec::new(),
+        };
+
+        Ok(m)
+    }
+}
+
+impl From<&[u8]> for Cell {
+    fn from(m: &[u8]) -> Cell {
+        Cell::decode(m).unwrap_or(Cell::E)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u16 = 256;
+
+    fn cells() -> Vec<Cell> {
+        vec![
+            Cell::BEGIN {
+                stream: 1,
+                addr: Addr::Host {
+                    host: "example.com".to_owned(),
+                    port: 443,
+                },
+                tls: true,
+            },
+            Cell::STATUS {
+                stream: 1,
+                status: Status::REFUSED,
+            },
+            Cell::DATA {
+                stream: 1,
+                data: vec![1, 2, 3],
+            },
+            Cell::END { stream: 1 },
+            Cell::DESTROY {
+                status: Status::TIMEOUT,
+            },
+        ]
+    }
+
+    fn round_trip(cell: Cell, padding: Padding) -> Vec<Cell> {
+        cell.encode(padding)
+            .unwrap()
+            .iter()
+            .map(|m| Cell::decode_padded(m, padding).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_cells_encoded() {
+        for padding in &[Padding::None, Padding::Fixed(SIZE)] {
+            for (cell, expected) in cells().into_iter().zip(cells()) {
+                assert_eq!(round_trip(cell, *padding), vec![expected]);
+            }
+        }
+    }
+
+    #[test]
+    fn pads_cells_to_size() {
+        for cell in cells() {
+            for m in cell.encode(Padding::Fixed(SIZE)).unwrap() {
+                assert_eq!(m.len(), SIZE as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn splits_long_data() {
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let cells = round_trip(
+            Cell::DATA {
+                stream: 3,
+                data: data.clone(),
+            },
+            Padding::Fixed(SIZE),
+        );
+
+        assert!(cells.len() > 1);
+
+        let joined: Vec<u8> = cells
+            .into_iter()
+            .flat_map(|cell| match cell {
+                Cell::DATA { stream: 3, data } => data,
+                cell => panic!("unexpected cell={:?}", cell),
+            })
+            .collect();
+
+        assert_eq!(joined, data);
+    }
+
+    #[test]
+    fn refuses_cells_not_of_size() {
+        let m = Cell::END { stream: 1 }
+            .encode(Padding::Fixed(SIZE))
+            .unwrap()
+            .remove(0);
+
+        assert!(Cell::decode_padded(&[], Padding::Fixed(SIZE)).is_err());
+        assert!(Cell::decode_padded(&m[..m.len() - 1], Padding::Fixed(SIZE)).is_err());
+        assert!(Cell::decode_padded(&[&m[..], &[0]].concat(), Padding::Fixed(SIZE)).is_err());
+    }
+
+    #[test]
+    fn refuses_length_beyond_cell() {
+        let mut m = vec![0; SIZE as usize];
+
+        m[0..2].copy_from_slice(&(SIZE - 1).to_be_bytes());
+
+        assert!(Cell::decode_padded(&m, Padding::Fixed(SIZE)).is_err());
+    }
+
+    #[test]
+    fn refuses_truncated_cells() {
+        for cell in cells() {
+            let m = cell.encode(Padding::None).unwrap().remove(0);
+
+            // DATA and END end with the stream id.
+            for len in 0..3.min(m.len()) {
+                assert!(Cell::decode(&m[..len]).is_err());
             }
         }
 
-        Method::E
+        assert!(Cell::decode(&[STATUS, 0, 1]).is_err());
+        assert!(Cell::decode(&[DESTROY, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn refuses_unreadable_begin_with_stream() {
+        let e = Cell::decode(&[BEGIN, 0, 7, 0xff]).unwrap_err();
+
+        match e.downcast_ref::<WireError>() {
+            Some(WireError::InvalidBegin { stream: 7 }) => {}
+            _ => panic!("unexpected error={}", e),
+        }
+    }
+
+    #[test]
+    fn refuses_trailing_bytes() {
+        assert!(Cell::decode(&[END, 0, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn refuses_small_cell_size() {
+        assert!(Padding::from_size(MIN_CELL_SIZE - 1).is_err());
+        assert_eq!(Padding::from_size(0).unwrap(), Padding::None);
     }
 }
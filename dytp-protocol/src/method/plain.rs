@@ -1,115 +1,504 @@
+use crate::error::{Result, WireError};
+use crate::wire::{Reader, Writer};
+use crate::{MIN_VERSION, VERSION};
+use dytp_component::audit::Audit;
+use dytp_component::exit_policy::ExitPolicy;
+use dytp_component::health_resp_cloud::HealthRespCloud;
+use dytp_component::health_resp_gateway::{GatewayNode, HealthRespGateway};
+use dytp_component::health_resp_node::{HealthRespNode, Traffic};
+use dytp_component::node::Node;
 use semver::Version;
 use std::net::SocketAddr;
 
+// Message types, unique over the methods so that a message is never taken for another one.
+const HELLO: u8 = 0x01;
+const ACCEPT: u8 = 0x02;
+const REJECT: u8 = 0x03;
+const HEALTH: u8 = 0x10;
+const PUB_KEY: u8 = 0x20;
+const ONION_KEY: u8 = 0x21;
+const CREATE: u8 = 0x22;
+const FETCH: u8 = 0x30;
+const SYNC: u8 = 0x31;
+const JOIN: u8 = 0x32;
+const CHECK: u8 = 0x33;
+const NODES: u8 = 0x34;
+const AUDITS: u8 = 0x35;
+const HEALTH_CLOUD: u8 = 0x40;
+const HEALTH_GATEWAY: u8 = 0x41;
+const HEALTH_NODE: u8 = 0x42;
+
+//
+// Exchanged first on a connection between components.
+// The connecting side offers the versions it speaks and the other side picks one or rejects them.
+// These are never changed so that peers of any version can read them.
+//
+#[derive(PartialEq, Debug)]
+pub enum Negotiation {
+    HELLO { min: u8, max: u8 },  // Versions offered by the connecting side
+    ACCEPT { version: u8 },      // The version spoken on the connection
+    REJECT { min: u8, max: u8 }, // None of the versions is spoken, followed by the close
+    E,                           // Invalid message
+}
+
+impl Negotiation {
+    pub fn hello() -> Negotiation {
+        Negotiation::HELLO {
+            min: MIN_VERSION,
+            max: VERSION,
+        }
+    }
+
+    // The answer to a HELLO, the newest version spoken by both sides is picked.
+    pub fn answer(&self) -> Negotiation {
+        match self {
+            Negotiation::HELLO { min, max } if *min <= VERSION && MIN_VERSION <= *max => {
+                Negotiation::ACCEPT {
+                    version: std::cmp::min(*max, VERSION),
+                }
+            }
+            _ => Negotiation::REJECT {
+                min: MIN_VERSION,
+                max: VERSION,
+            },
+        }
+    }
+
+    // Fails unless the answer accepts a version spoken by this side.
+    pub fn accepted(&self) -> Result<u8> {
+        match self {
+            Negotiation::ACCEPT { version } if MIN_VERSION <= *version && *version <= VERSION => {
+                Ok(*version)
+            }
+            Negotiation::ACCEPT { version } => Err(WireError::IncompatibleVersion {
+                min: *version,
+                max: *version,
+                own_min: MIN_VERSION,
+                own_max: VERSION,
+            }
+            .into()),
+            Negotiation::REJECT { min, max } => Err(WireError::IncompatibleVersion {
+                min: *min,
+                max: *max,
+                own_min: MIN_VERSION,
+                own_max: VERSION,
+            }
+            .into()),
+            // Only an answer is expected.
+            _ => Err(WireError::UnknownType { tag: HELLO }.into()),
+        }
+    }
+
+    pub fn decode(m: &[u8]) -> Result<Negotiation> {
+        let (_, tag, mut r) = Reader::any_version(m)?;
+
+        let negotiation = match tag {
+            HELLO => Negotiation::HELLO {
+                min: r.u8()?,
+                max: r.u8()?,
+            },
+            ACCEPT => Negotiation::ACCEPT { version: r.u8()? },
+            REJECT => Negotiation::REJECT {
+                min: r.u8()?,
+                max: r.u8()?,
+            },
+            _ => return Err(WireError::UnknownType { tag }.into()),
+        };
+
+        r.finish()?;
+
+        Ok(negotiation)
+    }
+}
+
+impl Into<Vec<u8>> for Negotiation {
+    fn into(self) -> Vec<u8> {
+        match self {
+            Negotiation::HELLO { min, max } => Writer::new(HELLO).u8(min).u8(max).finish(),
+            Negotiation::ACCEPT { version } => Writer::new(ACCEPT).u8(version).finish(),
+            Negotiation::REJECT { min, max } => Writer::new(REJECT).u8(min).u8(max).finish(),
+            Negotiation::E => Vec::new(),
+        }
+    }
+}
+
+impl From<&[u8]> for Negotiation {
+    fn from(m: &[u8]) -> Negotiation {
+        Negotiation::decode(m).unwrap_or(Negotiation::E)
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub enum Common {
     HEALTH, // Healcheck method
     E,      // Invalid method
 }
 
+impl Common {
+    pub fn decode(m: &[u8]) -> Result<Common> {
+        let (tag, r) = Reader::new(m)?;
+
+        let common = match tag {
+            HEALTH => Common::HEALTH,
+            _ => return Err(WireError::UnknownType { tag }.into()),
+        };
+
+        r.finish()?;
+
+        Ok(common)
+    }
+}
+
 impl Into<Vec<u8>> for Common {
     fn into(self) -> Vec<u8> {
         match self {
-            Common::HEALTH => b"HT".to_vec(),
-            Common::E => b"E".to_vec(),
+            Common::HEALTH => Writer::new(HEALTH).finish(),
+            Common::E => Vec::new(),
         }
     }
 }
 
 impl From<&[u8]> for Common {
     fn from(m: &[u8]) -> Common {
-        match m {
-            b"HT" => Common::HEALTH,
-            _ => Common::E,
-        }
+        Common::decode(m).unwrap_or(Common::E)
     }
 }
 
 #[allow(non_camel_case_types)]
 #[derive(PartialEq, Debug)]
 pub enum ToNode {
-    PUB_KEY, // Send a public key
-    E,       // Invalid metod
+    PUB_KEY,                       // Send a public key
+    ONION_KEY,                     // Send keys for the circuit handshake
+    CREATE { onionskin: Vec<u8> }, // Start the circuit handshake
+    E,                             // Invalid metod
 }
 
-impl Into<Vec<u8>> for ToNode {
-    fn into(self) -> Vec<u8> {
-        match self {
-            ToNode::PUB_KEY => b"PK".to_vec(),
-            _ => b"E".to_vec(),
-        }
+impl ToNode {
+    pub fn decode(m: &[u8]) -> Result<ToNode> {
+        let (tag, mut r) = Reader::new(m)?;
+
+        let to_node = match tag {
+            PUB_KEY => ToNode::PUB_KEY,
+            ONION_KEY => ToNode::ONION_KEY,
+            CREATE => ToNode::CREATE {
+                onionskin: r.bytes()?.to_vec(),
+            },
+            _ => return Err(WireError::UnknownType { tag }.into()),
+        };
+
+        r.finish()?;
+
+        Ok(to_node)
+    }
+
+    pub fn encode(self) -> Result<Vec<u8>> {
+        let m = match self {
+            ToNode::PUB_KEY => Writer::new(PUB_KEY).finish(),
+            ToNode::ONION_KEY => Writer::new(ONION_KEY).finish(),
+            ToNode::CREATE { onionskin } => Writer::new(CREATE).bytes(&onionskin)?.finish(),
+            ToNode::E => Vec::new(),
+        };
+
+        Ok(m)
     }
 }
 
 impl From<&[u8]> for ToNode {
     fn from(m: &[u8]) -> ToNode {
-        match m {
-            b"PK" => ToNode::PUB_KEY,
-            _ => ToNode::E,
-        }
+        ToNode::decode(m).unwrap_or(ToNode::E)
     }
 }
 
 #[derive(PartialEq, Debug)]
 pub enum ToCloud {
-    FETCH,                                       // Fetch a list of nodes
-    SYNC { ts: i64 },                            // Sync audit logs with latest timestamp
-    JOIN { addr: SocketAddr, version: Version }, // Joining request
-    CHECK { addr: SocketAddr },                  // Check current status of node
-    E,                                           // Invalid method
+    FETCH, // Fetch a list of nodes
+    SYNC {
+        ts: i64,
+    }, // Sync audit logs with latest timestamp
+    JOIN {
+        addr: SocketAddr,
+        version: Version,
+        fingerprint: String,
+        bandwidth: u64,
+        family: Option<String>,
+        exit_policy: ExitPolicy,
+    }, // Joining request
+    CHECK {
+        addr: SocketAddr,
+    }, // Check current status of node
+    E,     // Invalid method
 }
 
-impl Into<Vec<u8>> for ToCloud {
-    fn into(self) -> Vec<u8> {
-        match self {
-            ToCloud::FETCH => b"FC".to_vec(),
-            ToCloud::SYNC { ts } => format!("SY {}", ts).into_bytes(),
-            ToCloud::JOIN { addr, version } => format!("JN {} {}", addr, version).into_bytes(),
-            ToCloud::CHECK { addr } => format!("CH {}", addr).into_bytes(),
-            _ => b"E".to_vec(),
-        }
+impl ToCloud {
+    pub fn decode(m: &[u8]) -> Result<ToCloud> {
+        let (tag, mut r) = Reader::new(m)?;
+
+        let to_cloud = match tag {
+            FETCH => ToCloud::FETCH,
+            SYNC => ToCloud::SYNC { ts: r.i64()? },
+            JOIN => ToCloud::JOIN {
+                addr: r.socket_addr()?,
+                version: r.parse("version")?,
+                fingerprint: r.str("fingerprint")?.to_owned(),
+                bandwidth: r.u64()?,
+                // An empty family is no family.
+                family: read_family(&mut r)?,
+                exit_policy: r.parse("exit_policy")?,
+            },
+            CHECK => ToCloud::CHECK {
+                addr: r.socket_addr()?,
+            },
+            _ => return Err(WireError::UnknownType { tag }.into()),
+        };
+
+        r.finish()?;
+
+        Ok(to_cloud)
+    }
+
+    pub fn encode(self) -> Result<Vec<u8>> {
+        let m = match self {
+            ToCloud::FETCH => Writer::new(FETCH).finish(),
+            ToCloud::SYNC { ts } => Writer::new(SYNC).i64(ts).finish(),
+            ToCloud::JOIN {
+                addr,
+                version,
+                fingerprint,
+                bandwidth,
+                family,
+                exit_policy,
+            } => Writer::new(JOIN)
+                .socket_addr(&addr)
+                .str(&version.to_string())?
+                .str(&fingerprint)?
+                .u64(bandwidth)
+                .str(family.as_ref().map(|f| f.as_str()).unwrap_or_default())?
+                .str(&exit_policy.to_string())?
+                .finish(),
+            ToCloud::CHECK { addr } => Writer::new(CHECK).socket_addr(&addr).finish(),
+            ToCloud::E => Vec::new(),
+        };
+
+        Ok(m)
     }
 }
 
 impl From<&[u8]> for ToCloud {
     fn from(m: &[u8]) -> ToCloud {
-        match m {
-            b"FC" => ToCloud::FETCH,
-            _ => {
-                let re_sync = regex::Regex::new(r"^SY\s(.+?)$").unwrap();
-
-                for cap in re_sync.captures_iter(std::str::from_utf8(m).unwrap()) {
-                    if let Ok(ts) = cap[1].parse() {
-                        return ToCloud::SYNC { ts };
-                    }
+        ToCloud::decode(m).unwrap_or(ToCloud::E)
+    }
+}
+
+//
+// Answers of the cloud to FETCH and SYNC.
+// Lists are prefixed with the number of their entries.
+//
+#[allow(non_camel_case_types)]
+#[derive(PartialEq, Debug)]
+pub enum FromCloud {
+    NODES { ts: i64, nodes: Vec<Node> }, // Active nodes and the timestamp of the latest audit
+    AUDITS { audits: Vec<Audit> },       // Audits newer than the timestamp of SYNC, newest first
+    E,                                   // Invalid answer
+}
+
+impl FromCloud {
+    pub fn decode(m: &[u8]) -> Result<FromCloud> {
+        let (tag, mut r) = Reader::new(m)?;
+
+        let from_cloud = match tag {
+            NODES => {
+                let ts = r.i64()?;
+                let mut nodes = Vec::new();
+
+                for _ in 0..r.u32()? {
+                    nodes.push(read_node(&mut r)?);
                 }
 
-                let re_check = regex::Regex::new(r"^CH\s(.+?)$").unwrap();
+                FromCloud::NODES { ts, nodes }
+            }
+            AUDITS => {
+                let mut audits = Vec::new();
+
+                for _ in 0..r.u32()? {
+                    let node = read_node(&mut r)?;
+                    let state = node.state.clone();
 
-                for cap in re_check.captures_iter(std::str::from_utf8(m).unwrap()) {
-                    if let Ok(addr) = cap[1].parse() {
-                        return ToCloud::CHECK { addr };
-                    }
+                    audits.push(Audit::new(&node, state, r.i64()?));
                 }
 
-                let re_join = regex::Regex::new(r"^JN\s(.+?)\s(.+?)$").unwrap();
+                FromCloud::AUDITS { audits }
+            }
+            _ => return Err(WireError::UnknownType { tag }.into()),
+        };
 
-                for cap in re_join.captures_iter(std::str::from_utf8(m).unwrap()) {
-                    let addr = cap[1].parse();
-                    let version = cap[2].parse();
+        r.finish()?;
 
-                    if addr.is_err() || version.is_err() {
-                        return ToCloud::E;
-                    }
+        Ok(from_cloud)
+    }
 
-                    let addr = addr.unwrap();
-                    let version = version.unwrap();
+    pub fn encode(self) -> Result<Vec<u8>> {
+        let m = match self {
+            FromCloud::NODES { ts, nodes } => {
+                let mut w = Writer::new(NODES).i64(ts).u32(nodes.len() as u32);
 
-                    return ToCloud::JOIN { addr, version };
+                for node in nodes.iter() {
+                    w = write_node(w, node)?;
                 }
 
-                ToCloud::E
+                w.finish()
             }
-        }
+            FromCloud::AUDITS { audits } => {
+                let mut w = Writer::new(AUDITS).u32(audits.len() as u32);
+
+                for audit in audits.into_iter() {
+                    let ts = audit.ts;
+                    let node = Node {
+                        state: audit.state.clone(),
+                        ..audit.into()
+                    };
+
+                    w = write_node(w, &node)?.i64(ts);
+                }
+
+                w.finish()
+            }
+            FromCloud::E => Vec::new(),
+        };
+
+        Ok(m)
+    }
+}
+
+//
+// Answers to HEALTH of each component.
+//
+#[allow(non_camel_case_types)]
+#[derive(Debug)]
+pub enum Health {
+    CLOUD(HealthRespCloud),
+    GATEWAY(HealthRespGateway),
+    NODE(HealthRespNode),
+    E, // Invalid answer
+}
+
+impl Health {
+    pub fn decode(m: &[u8]) -> Result<Health> {
+        let (tag, mut r) = Reader::new(m)?;
+
+        let health = match tag {
+            HEALTH_CLOUD => {
+                let version = r.parse("version")?;
+                let mut nodes = Vec::new();
+
+                for _ in 0..r.u32()? {
+                    nodes.push(read_node(&mut r)?);
+                }
+
+                Health::CLOUD(HealthRespCloud { version, nodes })
+            }
+            HEALTH_GATEWAY => {
+                let version = r.parse("version")?;
+                let mut nodes = Vec::new();
+
+                for _ in 0..r.u32()? {
+                    nodes.push(GatewayNode {
+                        node: read_node(&mut r)?,
+                        failures: r.u64()?,
+                    });
+                }
+
+                Health::GATEWAY(HealthRespGateway { version, nodes })
+            }
+            HEALTH_NODE => Health::NODE(HealthRespNode {
+                version: r.parse("version")?,
+                sent: read_traffic(&mut r)?,
+                received: read_traffic(&mut r)?,
+            }),
+            _ => return Err(WireError::UnknownType { tag }.into()),
+        };
+
+        r.finish()?;
+
+        Ok(health)
+    }
+
+    pub fn encode(self) -> Result<Vec<u8>> {
+        let m = match self {
+            Health::CLOUD(h) => {
+                let mut w = Writer::new(HEALTH_CLOUD)
+                    .str(&h.version.to_string())?
+                    .u32(h.nodes.len() as u32);
+
+                for node in h.nodes.iter() {
+                    w = write_node(w, node)?;
+                }
+
+                w.finish()
+            }
+            Health::GATEWAY(h) => {
+                let mut w = Writer::new(HEALTH_GATEWAY)
+                    .str(&h.version.to_string())?
+                    .u32(h.nodes.len() as u32);
+
+                for n in h.nodes.iter() {
+                    w = write_node(w, &n.node)?.u64(n.failures);
+                }
+
+                w.finish()
+            }
+            Health::NODE(h) => {
+                let w = Writer::new(HEALTH_NODE).str(&h.version.to_string())?;
+
+                write_traffic(write_traffic(w, &h.sent), &h.received).finish()
+            }
+            Health::E => Vec::new(),
+        };
+
+        Ok(m)
     }
 }
+
+// An empty family is no family.
+fn read_family(r: &mut Reader) -> Result<Option<String>> {
+    Ok(Some(r.str("family")?)
+        .filter(|f| !f.is_empty())
+        .map(|f| f.to_owned()))
+}
+
+fn read_node(r: &mut Reader) -> Result<Node> {
+    Ok(Node {
+        addr: r.socket_addr()?,
+        state: r.parse("state")?,
+        version: r.parse("version")?,
+        fingerprint: r.str("fingerprint")?.to_owned(),
+        bandwidth: r.u64()?,
+        family: read_family(r)?,
+        exit_policy: r.parse("exit_policy")?,
+    })
+}
+
+fn write_node(w: Writer, node: &Node) -> Result<Writer> {
+    w.socket_addr(&node.addr)
+        .str(&node.state.to_string())?
+        .str(&node.version.to_string())?
+        .str(&node.fingerprint)?
+        .u64(node.bandwidth)
+        .str(node.family.as_ref().map(|f| f.as_str()).unwrap_or_default())?
+        .str(&node.exit_policy.to_string())
+}
+
+fn read_traffic(r: &mut Reader) -> Result<Traffic> {
+    Ok(Traffic {
+        cells: r.u64()?,
+        bytes: r.u64()?,
+        padding_cells: r.u64()?,
+        padding_bytes: r.u64()?,
+    })
+}
+
+fn write_traffic(w: Writer, traffic: &Traffic) -> Writer {
+    w.u64(traffic.cells)
+        .u64(traffic.bytes)
+        .u64(traffic.padding_cells)
+        .u64(traffic.padding_bytes)
+}
@@ -0,0 +1,380 @@
+use crate::addr::{is_valid_host, Addr};
+use crate::error::{Result, WireError};
+use crate::VERSION;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+//
+// Methods are encoded as
+//
+// +------------------------------------------------------------+
+// |[1 byte: version] | [1 byte: message type] | [any: fields]   |
+// +------------------------------------------------------------+
+//
+// Integers are big-endian, variable length fields are prefixed with their length.
+//
+// +--------------------------------------------+
+// |[2 bytes: length] | [length bytes: value]    |
+// +--------------------------------------------+
+//
+// Addresses are prefixed with their type as in SOCKS5, followed by the port.
+//
+// +-------------------------------------------------------------------------+
+// |[1 byte: type] | [4 bytes: ipv4, 16 bytes: ipv6, field: host] | [2 bytes] |
+// +-------------------------------------------------------------------------+
+//
+
+const ADDR_IPV4: u8 = 0x01;
+const ADDR_HOST: u8 = 0x03;
+const ADDR_IPV6: u8 = 0x04;
+
+#[derive(Debug)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(tag: u8) -> Writer {
+        Writer {
+            buf: vec![VERSION, tag],
+        }
+    }
+
+    // Without the version, for messages of which the version is known by both sides.
+    pub fn untagged() -> Writer {
+        Writer { buf: Vec::new() }
+    }
+
+    pub fn u8(mut self, v: u8) -> Writer {
+        self.buf.push(v);
+        self
+    }
+
+    pub fn u16(mut self, v: u16) -> Writer {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u32(mut self, v: u32) -> Writer {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn u64(mut self, v: u64) -> Writer {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    pub fn i64(mut self, v: i64) -> Writer {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+        self
+    }
+
+    // Values longer than a length prefix can tell are refused instead of being cut.
+    pub fn bytes(mut self, v: &[u8]) -> Result<Writer> {
+        if v.len() > u16::MAX as usize {
+            return Err(WireError::TooLong {
+                len: v.len(),
+                max: u16::MAX as usize,
+            }
+            .into());
+        }
+
+        self.buf.extend_from_slice(&(v.len() as u16).to_be_bytes());
+        self.buf.extend_from_slice(v);
+
+        Ok(self)
+    }
+
+    pub fn str(self, v: &str) -> Result<Writer> {
+        self.bytes(v.as_bytes())
+    }
+
+    pub fn socket_addr(self, addr: &SocketAddr) -> Writer {
+        let w = match addr.ip() {
+            IpAddr::V4(ip) => self.u8(ADDR_IPV4).raw(&ip.octets()),
+            IpAddr::V6(ip) => self.u8(ADDR_IPV6).raw(&ip.octets()),
+        };
+
+        w.u16(addr.port())
+    }
+
+    pub fn addr(self, addr: &Addr) -> Result<Writer> {
+        match addr {
+            Addr::Socket(addr) => Ok(self.socket_addr(addr)),
+            Addr::Host { host, port } => Ok(self.u8(ADDR_HOST).str(host)?.u16(*port)),
+        }
+    }
+
+    // The rest of the message, without a length.
+    pub fn raw(mut self, v: &[u8]) -> Writer {
+        self.buf.extend_from_slice(v);
+        self
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+//
+// Reads the fields in order, a message shorter than its fields is an error instead of a panic.
+//
+#[derive(Debug)]
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    // Returns the message type, the version must be the one of this build.
+    pub fn new(buf: &'a [u8]) -> Result<(u8, Reader<'a>)> {
+        let (version, tag, reader) = Reader::any_version(buf)?;
+
+        if version != VERSION {
+            return Err(WireError::UnsupportedVersion { version }.into());
+        }
+
+        Ok((tag, reader))
+    }
+
+    // Returns the version and the message type for messages readable by any version.
+    pub fn any_version(buf: &'a [u8]) -> Result<(u8, u8, Reader<'a>)> {
+        let mut reader = Reader::untagged(buf);
+        let version = reader.u8()?;
+        let tag = reader.u8()?;
+
+        Ok((version, tag, reader))
+    }
+
+    pub fn untagged(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(WireError::Truncated.into());
+        }
+
+        let (taken, rest) = self.buf.split_at(n);
+
+        self.buf = rest;
+
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut b = [0; 8];
+
+        b.copy_from_slice(self.take(8)?);
+
+        Ok(u64::from_be_bytes(b))
+    }
+
+    pub fn i64(&mut self) -> Result<i64> {
+        let mut b = [0; 8];
+
+        b.copy_from_slice(self.take(8)?);
+
+        Ok(i64::from_be_bytes(b))
+    }
+
+    // A field of the length known by both sides, without a length.
+    pub fn raw(&mut self, len: usize) -> Result<&'a [u8]> {
+        self.take(len)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()?;
+
+        self.take(len as usize)
+    }
+
+    pub fn str(&mut self, field: &'static str) -> Result<&'a str> {
+        std::str::from_utf8(self.bytes()?).map_err(|_| WireError::InvalidField { field }.into())
+    }
+
+    // Parses a text field by `FromStr`. (e.g. semver and exit policies)
+    pub fn parse<T: std::str::FromStr>(&mut self, field: &'static str) -> Result<T> {
+        self.str(field)?
+            .parse()
+            .map_err(|_| WireError::InvalidField { field }.into())
+    }
+
+    pub fn socket_addr(&mut self) -> Result<SocketAddr> {
+        match self.addr()? {
+            Addr::Socket(addr) => Ok(addr),
+            Addr::Host { .. } => Err(WireError::InvalidField { field: "addr" }.into()),
+        }
+    }
+
+    pub fn addr(&mut self) -> Result<Addr> {
+        let ip = match self.u8()? {
+            ADDR_IPV4 => {
+                let mut b = [0; 4];
+
+                b.copy_from_slice(self.take(4)?);
+
+                IpAddr::V4(Ipv4Addr::from(b))
+            }
+            ADDR_IPV6 => {
+                let mut b = [0; 16];
+
+                b.copy_from_slice(self.take(16)?);
+
+                IpAddr::V6(Ipv6Addr::from(b))
+            }
+            ADDR_HOST => {
+                let host = self.str("host")?.to_owned();
+                let port = self.u16()?;
+
+                if !is_valid_host(&host) {
+                    return Err(WireError::InvalidField { field: "host" }.into());
+                }
+
+                return Ok(Addr::Host { host, port });
+            }
+            _ => return Err(WireError::InvalidField { field: "addr" }.into()),
+        };
+
+        Ok(Addr::Socket(SocketAddr::new(ip, self.u16()?)))
+    }
+
+    // Everything left in the message.
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = self.buf;
+
+        self.buf = &[];
+
+        rest
+    }
+
+    // A message must end with its last field.
+    pub fn finish(self) -> Result<()> {
+        if !self.buf.is_empty() {
+            return Err(WireError::TrailingBytes.into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_truncated(e: failure::Error) -> bool {
+        match e.downcast_ref::<WireError>() {
+            Some(WireError::Truncated) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn reads_fields_written() {
+        let addr = Addr::Host {
+            host: "example.com".to_owned(),
+            port: 443,
+        };
+        let m = Writer::new(0x10)
+            .u8(1)
+            .u16(2)
+            .u32(3)
+            .u64(4)
+            .i64(-5)
+            .bytes(b"bytes")
+            .unwrap()
+            .addr(&addr)
+            .unwrap()
+            .finish();
+
+        let (tag, mut r) = Reader::new(&m).unwrap();
+
+        assert_eq!(tag, 0x10);
+        assert_eq!(r.u8().unwrap(), 1);
+        assert_eq!(r.u16().unwrap(), 2);
+        assert_eq!(r.u32().unwrap(), 3);
+        assert_eq!(r.u64().unwrap(), 4);
+        assert_eq!(r.i64().unwrap(), -5);
+        assert_eq!(r.bytes().unwrap(), b"bytes");
+        assert_eq!(r.addr().unwrap(), addr);
+        assert!(r.finish().is_ok());
+    }
+
+    #[test]
+    fn refuses_truncated_integers() {
+        assert!(is_truncated(Reader::untagged(&[]).u8().unwrap_err()));
+        assert!(is_truncated(Reader::untagged(&[0]).u16().unwrap_err()));
+        assert!(is_truncated(Reader::untagged(&[0; 3]).u32().unwrap_err()));
+        assert!(is_truncated(Reader::untagged(&[0; 7]).u64().unwrap_err()));
+        assert!(is_truncated(Reader::untagged(&[0; 7]).i64().unwrap_err()));
+    }
+
+    #[test]
+    fn refuses_field_longer_than_message() {
+        let m = Writer::untagged().bytes(b"bytes").unwrap().finish();
+
+        for len in 0..m.len() {
+            assert!(is_truncated(
+                Reader::untagged(&m[..len]).bytes().unwrap_err()
+            ));
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_addresses() {
+        let addrs = vec![
+            Addr::Socket("127.0.0.1:80".parse().unwrap()),
+            Addr::Socket("[::1]:80".parse().unwrap()),
+            Addr::Host {
+                host: "example.com".to_owned(),
+                port: 80,
+            },
+        ];
+
+        for addr in addrs {
+            let m = Writer::untagged().addr(&addr).unwrap().finish();
+
+            for len in 0..m.len() {
+                assert!(is_truncated(
+                    Reader::untagged(&m[..len]).addr().unwrap_err()
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_header() {
+        assert!(is_truncated(Reader::new(&[VERSION]).unwrap_err()));
+    }
+
+    #[test]
+    fn refuses_trailing_bytes() {
+        let mut r = Reader::untagged(&[0, 1, 2]);
+
+        r.u16().unwrap();
+
+        assert!(r.finish().is_err());
+    }
+
+    #[test]
+    fn refuses_too_long_field() {
+        assert!(Writer::untagged()
+            .bytes(&vec![0; u16::MAX as usize + 1])
+            .is_err());
+    }
+}
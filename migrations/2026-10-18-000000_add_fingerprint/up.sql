@@ -0,0 +1,9 @@
+ALTER TABLE nodes ADD COLUMN fingerprint VARCHAR NOT NULL DEFAULT '';
+ALTER TABLE audits ADD COLUMN fingerprint VARCHAR NOT NULL DEFAULT '';
+
+-- Nodes joined before have no fingerprint to verify their identity with.
+-- They're taken down until they join again with their keys, and gateways learn it from the audits.
+INSERT INTO audits (addr, state, version, ts)
+  SELECT addr, 'D', version, (EXTRACT(EPOCH FROM clock_timestamp()) * 1000000000)::BIGINT
+  FROM nodes WHERE state = 'A';
+UPDATE nodes SET state = 'D' WHERE state = 'A';
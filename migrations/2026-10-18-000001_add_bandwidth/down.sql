@@ -0,0 +1,2 @@
+ALTER TABLE nodes DROP COLUMN bandwidth;
+ALTER TABLE audits DROP COLUMN bandwidth;
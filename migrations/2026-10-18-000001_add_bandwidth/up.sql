@@ -0,0 +1,2 @@
+ALTER TABLE nodes ADD COLUMN bandwidth BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE audits ADD COLUMN bandwidth BIGINT NOT NULL DEFAULT 0;
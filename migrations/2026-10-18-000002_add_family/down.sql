@@ -0,0 +1,2 @@
+ALTER TABLE nodes DROP COLUMN family;
+ALTER TABLE audits DROP COLUMN family;
@@ -0,0 +1,2 @@
+ALTER TABLE nodes ADD COLUMN family VARCHAR;
+ALTER TABLE audits ADD COLUMN family VARCHAR;
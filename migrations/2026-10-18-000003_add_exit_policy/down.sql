@@ -0,0 +1,2 @@
+ALTER TABLE nodes DROP COLUMN exit_policy;
+ALTER TABLE audits DROP COLUMN exit_policy;
@@ -0,0 +1,2 @@
+ALTER TABLE nodes ADD COLUMN exit_policy VARCHAR NOT NULL DEFAULT 'accept:*:*';
+ALTER TABLE audits ADD COLUMN exit_policy VARCHAR NOT NULL DEFAULT 'accept:*:*';
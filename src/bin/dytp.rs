@@ -24,7 +24,25 @@ fn subcommand_gateway<'a, 'b>() -> clap::App<'a, 'b> {
         .arg(options::address(Some("127.0.0.1:2888")))
         .arg(options::cloud())
         .arg(options::hops())
+        .arg(options::circuits())
+        .arg(options::circuit_max_age())
+        .arg(options::circuit_attempts())
+        .arg(options::circuit_deadline())
+        .arg(options::hop_timeout())
+        .arg(options::cell_size())
+        .arg(options::cover())
+        .arg(options::allow_same_subnet())
+        .arg(options::allow_same_family())
+        .arg(options::guards())
+        .arg(options::guard_lifetime())
+        .arg(options::guard_file())
         .arg(options::read_timeout())
+        .arg(options::max_frame_size())
+        .arg(options::linger_timeout())
+        .arg(options::proxy())
+        .arg(options::socks_auth())
+        .arg(options::user_agent())
+        .arg(options::accept_language())
 }
 
 fn subcommand_node<'a, 'b>() -> clap::App<'a, 'b> {
@@ -34,6 +52,13 @@ fn subcommand_node<'a, 'b>() -> clap::App<'a, 'b> {
         .arg(options::global_address())
         .arg(options::cloud())
         .arg(options::read_timeout())
+        .arg(options::max_frame_size())
+        .arg(options::linger_timeout())
+        .arg(options::key_file())
+        .arg(options::bandwidth())
+        .arg(options::family())
+        .arg(options::exit_policy())
+        .arg(options::allow_internal_destinations())
 }
 
 fn subcommand_cloud<'a, 'b>() -> clap::App<'a, 'b> {
@@ -43,6 +68,7 @@ fn subcommand_cloud<'a, 'b>() -> clap::App<'a, 'b> {
         .arg(options::healthcheck_interval())
         .arg(options::node_deletion_timeout())
         .arg(options::read_timeout())
+        .arg(options::max_frame_size())
 }
 
 fn subcommand_cli<'a, 'b>() -> clap::App<'a, 'b> {
@@ -54,13 +80,55 @@ fn subcommand_cli<'a, 'b>() -> clap::App<'a, 'b> {
         .arg(options::pretty())
 }
 
+// Returns false if the size is too small to carry the protocol.
+#[cfg(any(
+    feature = "gateway",
+    feature = "node",
+    feature = "cloud",
+    feature = "all"
+))]
+fn set_max_frame_size(matches: &clap::ArgMatches) -> Result<bool> {
+    let max_frame_size = matches.value_of("max-frame-size").unwrap().parse()?;
+
+    if max_frame_size < dytp::protocol::MIN_FRAME_SIZE {
+        log::error!(
+            "The maximum frame size must be at least {}.",
+            dytp::protocol::MIN_FRAME_SIZE
+        );
+        return Ok(false);
+    }
+
+    dytp::protocol::set_max_frame_size(max_frame_size);
+
+    Ok(true)
+}
+
 #[cfg(any(feature = "gateway", feature = "all"))]
 fn exec_gateway(matches: &clap::ArgMatches) -> Result<()> {
     let matches = matches.subcommand_matches("gateway").unwrap();
     let addr = matches.value_of("address").unwrap().parse()?;
     let cloud_addr = matches.value_of("cloud").unwrap().parse()?;
     let hops = matches.value_of("hops").unwrap().parse()?;
+    let circuits = matches.value_of("circuits").unwrap().parse()?;
+    let circuit_max_age = matches.value_of("circuit-max-age").unwrap().parse()?;
+    let circuit_attempts = matches.value_of("circuit-attempts").unwrap().parse()?;
+    let circuit_deadline = matches.value_of("circuit-deadline").unwrap().parse()?;
+    let hop_timeout = matches.value_of("hop-timeout").unwrap().parse()?;
+    let cell_size = matches.value_of("cell-size").unwrap().parse()?;
+    let guards = matches.value_of("guards").unwrap().parse()?;
+    let guard_lifetime = matches.value_of("guard-lifetime").unwrap().parse()?;
+    let guard_file = matches.value_of("guard-file").unwrap().parse()?;
     let read_timeout = matches.value_of("read-timeout").unwrap().parse()?;
+    let linger_timeout = matches.value_of("linger-timeout").unwrap().parse()?;
+    let proxy = matches.value_of("proxy").unwrap().parse()?;
+    let socks_auth = match matches.value_of("socks-auth") {
+        Some(socks_auth) => Some(socks_auth.parse()?),
+        None => None,
+    };
+    let header_policy = dytp::connection::request::HeaderPolicy {
+        user_agent: matches.value_of("user-agent").unwrap().parse()?,
+        accept_language: matches.value_of("accept-language").unwrap().parse()?,
+    };
 
     if hops <= 2 {
         log::error!("The hop must be greater than 2.");
@@ -72,7 +140,77 @@ fn exec_gateway(matches: &clap::ArgMatches) -> Result<()> {
         return Ok(());
     }
 
-    gateway::main_inner(addr, cloud_addr, hops, read_timeout)?;
+    if guards == 0 {
+        log::error!("The number of entry guards must be greater than 0.");
+        return Ok(());
+    }
+
+    if circuit_attempts == 0 {
+        log::error!("The number of attempts to build a circuit must be greater than 0.");
+        return Ok(());
+    }
+
+    if hop_timeout == 0 {
+        log::error!("The hop timeout must be greater than 0.");
+        return Ok(());
+    }
+
+    if !set_max_frame_size(matches)? {
+        return Ok(());
+    }
+
+    let padding = match dytp::protocol::method::encrypted::Padding::from_size(cell_size) {
+        Ok(padding) => padding,
+        Err(_) => {
+            log::error!(
+                "The cell size must be 0 or at least {}.",
+                dytp::protocol::method::encrypted::MIN_CELL_SIZE
+            );
+            return Ok(());
+        }
+    };
+
+    let cover = match matches.value_of("cover").unwrap().parse() {
+        Ok(cover) => cover,
+        Err(_) => {
+            log::error!(
+                "The cover must be none, constant:MILLIS or random:MILLIS with MILLIS at least {}.",
+                dytp::protocol::method::encrypted::MIN_COVER_INTERVAL
+            );
+            return Ok(());
+        }
+    };
+
+    let config = gateway::circuit::CircuitConfig {
+        hops,
+        circuits,
+        max_age: std::time::Duration::from_secs(circuit_max_age),
+        diversity: gateway::selection::Diversity {
+            subnets: !matches.is_present("allow-same-subnet"),
+            families: !matches.is_present("allow-same-family"),
+        },
+        guards: gateway::guard::GuardConfig {
+            guards,
+            lifetime: std::time::Duration::from_secs(guard_lifetime),
+            file: guard_file,
+        },
+        attempts: circuit_attempts,
+        deadline: std::time::Duration::from_secs(circuit_deadline),
+        hop_timeout: std::time::Duration::from_secs(hop_timeout),
+        padding,
+        cover,
+    };
+
+    gateway::main_inner(
+        addr,
+        cloud_addr,
+        config,
+        read_timeout,
+        linger_timeout,
+        proxy,
+        socks_auth,
+        header_policy,
+    )?;
 
     Ok(())
 }
@@ -91,8 +229,42 @@ fn exec_node(matches: &clap::ArgMatches) -> Result<()> {
     let global_addr = matches.value_of("global-address").unwrap().parse()?;
     let cloud_addr = matches.value_of("cloud").unwrap().parse()?;
     let read_timeout = matches.value_of("read-timeout").unwrap().parse()?;
+    let linger_timeout = matches.value_of("linger-timeout").unwrap().parse()?;
+    let key_file = matches.value_of("key-file").unwrap().parse()?;
+    let bandwidth = matches.value_of("bandwidth").unwrap().parse()?;
+    let family = matches.value_of("family").map(|f| f.to_owned());
+    let exit_policy = matches.value_of("exit-policy").unwrap().parse()?;
+
+    if let Some(family) = &family {
+        if !dytp::component::node::is_valid_family(family) {
+            log::error!(
+                "The family must be a word of {} bytes at most other than \"-\".",
+                dytp::component::node::MAX_FAMILY_LEN
+            );
+            return Ok(());
+        }
+    }
 
-    node::main_inner(addr, global_addr, cloud_addr, read_timeout)?;
+    if !set_max_frame_size(matches)? {
+        return Ok(());
+    }
+
+    let descriptor = node::descriptor::Descriptor {
+        bandwidth,
+        family,
+        exit_policy,
+    };
+
+    node::main_inner(
+        addr,
+        global_addr,
+        cloud_addr,
+        read_timeout,
+        linger_timeout,
+        key_file,
+        descriptor,
+        matches.is_present("allow-internal-destinations"),
+    )?;
 
     Ok(())
 }
@@ -112,6 +284,10 @@ fn exec_cloud(matches: &clap::ArgMatches) -> Result<()> {
     let node_deletion_timeout = matches.value_of("node-deletion-timeout").unwrap().parse()?;
     let read_timeout = matches.value_of("read-timeout").unwrap().parse()?;
 
+    if !set_max_frame_size(matches)? {
+        return Ok(());
+    }
+
     cloud::main_inner(
         addr,
         healthcheck_interval,
@@ -2,7 +2,7 @@ pub fn address<'a, 'b>(default: Option<&'a str>) -> clap::Arg<'a, 'b> {
     let mut arg = clap::Arg::with_name("address")
         .long("address")
         .short("a")
-        .help("Binded address for each component. Target address for cli. (specified by `host:port`).")
+        .help("Binded address for each component. Target address for cli. (specified by `host:port`, `[host]:port` for IPv6).")
         .takes_value(true)
         .required(true);
 
@@ -18,7 +18,7 @@ pub fn cloud<'a, 'b>() -> clap::Arg<'a, 'b> {
         .long("cloud")
         .short("c")
         .default_value("127.0.0.1:2777")
-        .help("Cloud address (specified by `host:port`).")
+        .help("Cloud address (specified by `host:port`, `[host]:port` for IPv6).")
         .takes_value(true)
 }
 
@@ -30,15 +30,177 @@ pub fn hops<'a, 'b>() -> clap::Arg<'a, 'b> {
         .takes_value(true)
 }
 
+pub fn circuits<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("circuits")
+        .long("circuits")
+        .default_value("3")
+        .help("Number of circuits built in advance and kept ready for new requests.")
+        .takes_value(true)
+}
+
+pub fn circuit_max_age<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("circuit-max-age")
+        .long("circuit-max-age")
+        .default_value("600")
+        .help("Secs after which a circuit stops taking new requests and is replaced.")
+        .takes_value(true)
+}
+
+pub fn circuit_attempts<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("circuit-attempts")
+        .long("circuit-attempts")
+        .default_value("3")
+        .help("Number of routes tried to build a circuit for a request. A failing node is skipped in the next route.")
+        .takes_value(true)
+}
+
+pub fn circuit_deadline<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("circuit-deadline")
+        .long("circuit-deadline")
+        .default_value("30")
+        .help("Secs to build a circuit for a request including the retries.")
+        .takes_value(true)
+}
+
+pub fn hop_timeout<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("hop-timeout")
+        .long("hop-timeout")
+        .default_value("5")
+        .help("Secs for each node to accept the connection and answer while a circuit is built. A slower node is taken as failed.")
+        .takes_value(true)
+}
+
+pub fn guards<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("guards")
+        .long("guards")
+        .default_value("3")
+        .help("Number of entry guards. Every circuit starts from one of them.")
+        .takes_value(true)
+}
+
+pub fn guard_lifetime<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("guard-lifetime")
+        .long("guard-lifetime")
+        .default_value("2592000")
+        .help("Secs after which an entry guard is replaced by another node.")
+        .takes_value(true)
+}
+
+pub fn guard_file<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("guard-file")
+        .long("guard-file")
+        .default_value("dytp-guards")
+        .help("Path to the file where the entry guards are kept across restarts.")
+        .takes_value(true)
+}
+
+pub fn cell_size<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("cell-size")
+        .long("cell-size")
+        .default_value("0")
+        .help("Pad every cell on the circuits to this many bytes (e.g. 512, at least 256) so that frame lengths don't reveal the size of what is sent. 0 sends cells as they are.")
+        .takes_value(true)
+}
+
+pub fn cover<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("cover")
+        .long("cover")
+        .default_value("none")
+        .help("Send dummy frames on every link of the circuits at each interval whether or not cells flow, from both ends of the link. none, constant:MILLIS or random:MILLIS (exponentially distributed intervals of MILLIS on average, at least 10)")
+        .takes_value(true)
+}
+
+pub fn allow_same_subnet<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("allow-same-subnet")
+        .long("allow-same-subnet")
+        .help("Allow hops of a circuit in the same IPv4 /16 or IPv6 /32 subnet. (e.g. all nodes on one host for testing)")
+}
+
+pub fn allow_same_family<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("allow-same-family")
+        .long("allow-same-family")
+        .help("Allow hops of a circuit in the same family declared by the node operators.")
+}
+
+pub fn proxy<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("proxy")
+        .long("proxy")
+        .default_value("auto")
+        .possible_values(&["auto", "http", "socks5"])
+        .help("Proxy protocol accepted by the gateway. \"auto\" detects HTTP or SOCKS5 from the first byte.")
+        .takes_value(true)
+}
+
+pub fn socks_auth<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("socks-auth")
+        .long("socks-auth")
+        .help("Require SOCKS5 username/password authentication (specified by `username:password`).")
+        .takes_value(true)
+}
+
+pub fn user_agent<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("user-agent")
+        .long("user-agent")
+        .default_value("keep")
+        .help("User-Agent sent to the destinations of plain HTTP requests. \"keep\" passes the client's one, \"remove\" never sends it, any other value replaces it.")
+        .takes_value(true)
+}
+
+pub fn accept_language<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("accept-language")
+        .long("accept-language")
+        .default_value("keep")
+        .help("Accept-Language sent to the destinations of plain HTTP requests. \"keep\" passes the client's one, \"remove\" never sends it, any other value replaces it.")
+        .takes_value(true)
+}
+
 pub fn global_address<'a, 'b>() -> clap::Arg<'a, 'b> {
     clap::Arg::with_name("global-address")
         .long("global-address")
         .short("g")
-        .help("Global ip with a port (specified by `host:port`).")
+        .help("Global ip with a port (specified by `host:port`, `[host]:port` for IPv6).")
         .takes_value(true)
         .required(true)
 }
 
+pub fn key_file<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("key-file")
+        .long("key-file")
+        .short("k")
+        .default_value("dytp-node.pem")
+        .help("Path to the identity key of the node. The key is created with 0600 permissions if it doesn't exist.")
+        .takes_value(true)
+}
+
+pub fn bandwidth<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("bandwidth")
+        .long("bandwidth")
+        .default_value("1024")
+        .help("Bandwidth the node can relay as KB/s. Gateways choose nodes in proportion to it.")
+        .takes_value(true)
+}
+
+pub fn family<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("family")
+        .long("family")
+        .help("Family name shared by the nodes you run. Gateways never put two nodes of a family in a circuit.")
+        .takes_value(true)
+}
+
+pub fn exit_policy<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("exit-policy")
+        .long("exit-policy")
+        .default_value("accept:*:*")
+        .help("Comma separated rules as `accept|reject:address[/prefix]:port[-port]` where `*` matches any. The first matching rule decides, destinations matching no rule are rejected.")
+        .takes_value(true)
+}
+
+pub fn allow_internal_destinations<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("allow-internal-destinations")
+        .long("allow-internal-destinations")
+        .help("Let the node connect to loopback, private and link-local destinations as the exit. Only for test networks.")
+}
+
 pub fn healthcheck_interval<'a, 'b>() -> clap::Arg<'a, 'b> {
     clap::Arg::with_name("healthcheck-interval")
         .long("healthcheck-interval")
@@ -59,7 +221,23 @@ pub fn read_timeout<'a, 'b>() -> clap::Arg<'a, 'b> {
     clap::Arg::with_name("read-timeout")
         .long("read-timeout")
         .default_value("1")
-        .help("Read timeout as secs.")
+        .help("Read timeout as secs. (0 to disable)")
+        .takes_value(true)
+}
+
+pub fn max_frame_size<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("max-frame-size")
+        .long("max-frame-size")
+        .default_value("1048576")
+        .help("Close connections sending a frame larger than this many bytes. (at least 65536)")
+        .takes_value(true)
+}
+
+pub fn linger_timeout<'a, 'b>() -> clap::Arg<'a, 'b> {
+    clap::Arg::with_name("linger-timeout")
+        .long("linger-timeout")
+        .default_value("10")
+        .help("Secs to keep a half-closed circuit for the other direction.")
         .takes_value(true)
 }
 